@@ -1,6 +1,6 @@
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use sqlx::prelude::FromRow;
+use sqlx::{prelude::FromRow, types::Json};
 use utoipa::ToSchema;
 
 pub mod middlewares;
@@ -12,9 +12,17 @@ pub struct User {
     pub ws_id: i64,
     pub fullname: String,
     pub email: String,
+    /// optional name to sign in with instead of the email
+    #[sqlx(default)]
+    #[serde(default)]
+    pub username: Option<String>,
     #[sqlx(default)]
     #[serde(skip)]
     pub password_hash: Option<String>,
+    /// tokens carrying an older version than the one stored are revoked
+    #[sqlx(default)]
+    #[serde(default)]
+    pub token_version: i32,
     pub created_at: DateTime<Utc>,
 }
 
@@ -36,6 +44,8 @@ pub struct Chat {
     pub r#type: ChatType,
     pub members: Vec<i64>,
     pub created_at: DateTime<Utc>,
+    #[serde(default)]
+    pub archived_at: Option<DateTime<Utc>>,
 }
 
 #[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
@@ -45,9 +55,28 @@ pub struct Message {
     pub sender_id: i64,
     pub content: String,
     pub files: Vec<String>,
+    /// metadata of the files, only for files sent as attachments
+    #[serde(default)]
+    pub attachments: Json<Vec<Attachment>>,
+    #[serde(default)]
+    pub pinned: bool,
+    /// id of the message this one was forwarded from
+    #[serde(default)]
+    pub forwarded_from: Option<i64>,
     pub created_at: DateTime<Utc>,
 }
 
+/// an uploaded file with what clients need to render it without fetching it
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Attachment {
+    pub url: String,
+    /// size in bytes
+    pub size: u64,
+    pub mime: String,
+    /// original name of the uploaded file
+    pub filename: String,
+}
+
 impl User {
     pub fn new(id: i64, fullname: &str, email: &str) -> Self {
         Self {
@@ -55,7 +84,9 @@ impl User {
             ws_id: 0,
             fullname: fullname.to_string(),
             email: email.to_string(),
+            username: None,
             password_hash: None,
+            token_version: 0,
             created_at: chrono::Utc::now(),
         }
     }
@@ -12,6 +12,13 @@ use serde::Deserialize;
 use tracing::warn;
 
 use super::TokenVerify;
+use crate::utils::TokenError;
+
+impl IntoResponse for TokenError {
+    fn into_response(self) -> Response {
+        (StatusCode::UNAUTHORIZED, self.to_string()).into_response()
+    }
+}
 
 #[allow(dead_code)]
 pub async fn verify_token<T>(State(state): State<T>, req: Request, next: Next) -> Response
@@ -68,11 +75,8 @@ where
             req.extensions_mut().insert(user);
         }
         Err(e) => {
-            return (
-                StatusCode::UNAUTHORIZED,
-                format!("parse Authorization header failed: {:?}", e),
-            )
-                .into_response()
+            warn!("verify token failed: {:?}", e);
+            return e.into_response();
         }
     }
     next.run(req).await
@@ -84,7 +88,7 @@ mod tests {
 
     use super::*;
     use crate::{
-        utils::{DecodingKey, EncodingKey},
+        utils::{DecodingKey, EncodingKey, TokenError},
         User,
     };
     use anyhow::Result;
@@ -99,8 +103,8 @@ mod tests {
     }
 
     impl TokenVerify for AppState {
-        type Error = anyhow::Error;
-        fn verify_token(&self, token: &str) -> Result<User> {
+        type Error = TokenError;
+        fn verify_token(&self, token: &str) -> Result<User, TokenError> {
             self.0.dk.verify(token)
         }
     }
@@ -158,4 +162,31 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn verify_token_with_wrong_audience_should_401() -> Result<()> {
+        let encoding_pem = include_str!("../../fixtures/encoding.pem");
+        let decoding_pem = include_str!("../../fixtures/decoding.pem");
+
+        let ek = EncodingKey::load(encoding_pem)?.with_scope("chat_server", "other_service");
+        let dk = DecodingKey::load(decoding_pem)?.with_scope("chat_server", "chat_web");
+        let state = AppState(Arc::new(AppStateInner { dk, ek }));
+        let token = state.0.ek.sign(User::new(1, "jack", "jack@admin"))?;
+
+        let app = Router::new()
+            .route("/", get(handler))
+            .layer(from_fn_with_state(
+                state.clone(),
+                verify_token_v2::<AppState>,
+            ))
+            .with_state(state);
+
+        let req = Request::builder()
+            .uri("/")
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::empty())?;
+        let res = app.oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        Ok(())
+    }
 }
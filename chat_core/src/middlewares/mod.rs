@@ -1,45 +1,167 @@
 use core::fmt;
 
-use axum::{middleware::from_fn, Router};
+use axum::{middleware::from_fn, response::IntoResponse, Router};
 use request_id::set_request_id;
 use server_time::ServerTimeLayer;
 use tower::ServiceBuilder;
 use tower_http::{
     compression::CompressionLayer,
-    trace::{DefaultMakeSpan, DefaultOnRequest, DefaultOnResponse, TraceLayer},
-    LatencyUnit,
+    limit::RequestBodyLimitLayer,
+    trace::{DefaultOnRequest, TraceLayer},
 };
+use trace::{RequestSpan, ResponseLog};
 use tracing::Level;
 
 mod auth;
 mod request_id;
 mod server_time;
+mod trace;
 pub use auth::verify_token_v2;
+pub use request_id::RequestId;
 
 use crate::User;
 
 pub trait TokenVerify {
-    type Error: fmt::Debug;
+    /// turned into the response when the token is rejected
+    type Error: fmt::Debug + IntoResponse;
     fn verify_token(&self, token: &str) -> Result<User, Self::Error>;
 }
 
 const REQUEST_ID_HEADER: &str = "X-Request-Id";
 const SERVER_TIME_HEADER: &str = "X-Server-Time";
-pub fn set_layer(app: Router) -> Router {
+/// set common layers, every request is traced with its request id, method, path, status and latency,
+/// request bodies larger than `body_limit` bytes are rejected with 413
+pub fn set_layer(app: Router, body_limit: usize) -> Router {
     app.layer(
         ServiceBuilder::new()
+            .layer(from_fn(set_request_id))
             .layer(
                 TraceLayer::new_for_http()
-                    .make_span_with(DefaultMakeSpan::new().include_headers(true))
+                    .make_span_with(RequestSpan)
                     .on_request(DefaultOnRequest::new().level(Level::INFO))
-                    .on_response(
-                        DefaultOnResponse::new()
-                            .level(Level::INFO)
-                            .latency_unit(LatencyUnit::Micros),
-                    ),
+                    .on_response(ResponseLog),
             )
             .layer(CompressionLayer::new().gzip(true).br(true).deflate(true))
-            .layer(from_fn(set_request_id))
-            .layer(ServerTimeLayer),
+            .layer(ServerTimeLayer)
+            .layer(RequestBodyLimitLayer::new(body_limit)),
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use axum::{
+        body::Body,
+        http::{Request, StatusCode},
+        routing::{get, post},
+        Extension,
+    };
+    use std::sync::{Arc, Mutex};
+    use tower::ServiceExt;
+    use tracing_subscriber::fmt::MakeWriter;
+
+    #[derive(Clone, Default)]
+    struct LogBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for LogBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl<'a> MakeWriter<'a> for LogBuf {
+        type Writer = LogBuf;
+
+        fn make_writer(&'a self) -> Self::Writer {
+            self.clone()
+        }
+    }
+
+    async fn handler(body: String) -> String {
+        body
+    }
+
+    #[tokio::test]
+    async fn body_limit_should_work() -> Result<()> {
+        let app = set_layer(Router::new().route("/", post(handler)), 16);
+
+        let req = Request::builder()
+            .method("POST")
+            .uri("/")
+            .header("Content-Type", "application/json")
+            .body(Body::from(r#"{"a":"hello"}"#))?;
+        let res = app.clone().oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let req = Request::builder()
+            .method("POST")
+            .uri("/")
+            .header("Content-Type", "application/json")
+            .body(Body::from(r#"{"content":"too large body"}"#))?;
+        let res = app.oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn trace_layer_should_log_request() -> Result<()> {
+        let buf = LogBuf::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(buf.clone())
+            .with_ansi(false)
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let app = set_layer(Router::new().route("/echo", post(handler)), 1024);
+        let req = Request::builder()
+            .method("POST")
+            .uri("/echo")
+            .header(REQUEST_ID_HEADER, "test-request-id")
+            .body(Body::from("hello"))?;
+        let res = app.oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let logs = String::from_utf8(buf.0.lock().unwrap().clone())?;
+        let line = logs
+            .lines()
+            .find(|l| l.contains("finished processing request"))
+            .expect("response should be logged");
+        assert!(line.contains("test-request-id"));
+        assert!(line.contains("method=POST"));
+        assert!(line.contains("path=/echo"));
+        assert!(line.contains("status=200"));
+        assert!(line.contains("latency_ms="));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn request_id_should_match_header_and_extension() -> Result<()> {
+        async fn id_handler(Extension(id): Extension<RequestId>) -> String {
+            id.0
+        }
+        let app = set_layer(Router::new().route("/", get(id_handler)), 1024);
+
+        let req = Request::builder().uri("/").body(Body::empty())?;
+        let res = app.clone().oneshot(req).await?;
+        let header = res.headers()[REQUEST_ID_HEADER].to_str()?.to_string();
+        let body = axum::body::to_bytes(res.into_body(), 1024).await?;
+        assert!(!header.is_empty());
+        assert_eq!(header.as_bytes(), &body[..]);
+
+        let req = Request::builder()
+            .uri("/")
+            .header(REQUEST_ID_HEADER, "client-id")
+            .body(Body::empty())?;
+        let res = app.oneshot(req).await?;
+        assert_eq!(res.headers()[REQUEST_ID_HEADER], "client-id");
+        let body = axum::body::to_bytes(res.into_body(), 1024).await?;
+        assert_eq!(&body[..], b"client-id");
+        Ok(())
+    }
+}
@@ -4,6 +4,10 @@ use uuid::Uuid;
 
 use super::REQUEST_ID_HEADER;
 
+/// id of the current request, stored in request extensions by `set_request_id`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestId(pub String);
+
 pub async fn set_request_id(mut req: Request, next: Next) -> Response {
     let id = match req.headers().get(REQUEST_ID_HEADER) {
         Some(v) => Some(v.to_owned()),
@@ -18,6 +22,9 @@ pub async fn set_request_id(mut req: Request, next: Next) -> Response {
             })
             .ok(),
     };
+    if let Some(v) = id.as_ref().and_then(|v| v.to_str().ok()) {
+        req.extensions_mut().insert(RequestId(v.to_string()));
+    }
     let mut resp = next.run(req).await;
 
     if let Some(id) = id {
@@ -0,0 +1,46 @@
+use std::time::Duration;
+
+use axum::http::{Request, Response};
+use tower_http::trace::{MakeSpan, OnResponse};
+use tracing::{field::Empty, info, info_span, Span};
+
+use super::{RequestId, REQUEST_ID_HEADER};
+
+/// create a span per request carrying the request id, method and path,
+/// status is recorded later when the response is ready
+#[derive(Debug, Clone, Copy)]
+pub struct RequestSpan;
+
+/// record status of the response into the request span and log the latency
+#[derive(Debug, Clone, Copy)]
+pub struct ResponseLog;
+
+impl<B> MakeSpan<B> for RequestSpan {
+    fn make_span(&mut self, req: &Request<B>) -> Span {
+        let request_id = match req.extensions().get::<RequestId>() {
+            Some(RequestId(id)) => id.as_str(),
+            None => req
+                .headers()
+                .get(REQUEST_ID_HEADER)
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or_default(),
+        };
+        info_span!(
+            "request",
+            request_id,
+            method = %req.method(),
+            path = %req.uri().path(),
+            status = Empty,
+        )
+    }
+}
+
+impl<B> OnResponse<B> for ResponseLog {
+    fn on_response(self, res: &Response<B>, latency: Duration, span: &Span) {
+        span.record("status", res.status().as_u16());
+        info!(
+            latency_ms = latency.as_millis() as u64,
+            "finished processing request"
+        );
+    }
+}
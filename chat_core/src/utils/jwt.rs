@@ -1,43 +1,182 @@
 use crate::User;
-use jwt_simple::prelude::*;
+use jwt_simple::{prelude::*, JWTError};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
 
 const JWT_DURATION: u64 = 60 * 60 * 24 * 7;
 const JWT_ISS: &str = "chat_server";
 const JWT_AUD: &str = "chat_web";
+const JWT_LEEWAY: u64 = 60;
+
+/// why a token was rejected
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum TokenError {
+    #[error("token expired")]
+    Expired,
+    #[error("malformed token: {0}")]
+    Malformed(String),
+    #[error("token signature mismatch")]
+    SignatureMismatch,
+    #[error("token issuer or audience mismatch")]
+    ScopeMismatch,
+    #[error("unknown token key id: {0}")]
+    UnknownKeyId(String),
+}
+
+impl From<jwt_simple::Error> for TokenError {
+    fn from(e: jwt_simple::Error) -> Self {
+        match e.downcast_ref::<JWTError>() {
+            Some(JWTError::TokenHasExpired) => Self::Expired,
+            Some(JWTError::InvalidSignature) => Self::SignatureMismatch,
+            Some(JWTError::RequiredIssuerMismatch | JWTError::RequiredAudienceMismatch) => {
+                Self::ScopeMismatch
+            }
+            _ => Self::Malformed(e.to_string()),
+        }
+    }
+}
+
+/// an extra public key accepted when verifying, e.g. the previous key during rotation
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct PublicKeyConfig {
+    pub pk: String,
+    /// `kid` header of tokens signed by the key, when the signer sets one
+    #[serde(default)]
+    pub kid: Option<String>,
+}
 
 // openssl pkey -in encoding.pem -pubout -out decoding.pem
 // openssl genpkey -algorithm ed25519 -out private.pem
-pub struct EncodingKey(Ed25519KeyPair);
+pub struct EncodingKey {
+    key: Ed25519KeyPair,
+    issuer: String,
+    audience: String,
+}
 #[allow(unused)]
-pub struct DecodingKey(Ed25519PublicKey);
+pub struct DecodingKey {
+    // the primary key first, then older keys still accepted during rotation
+    keys: Vec<Ed25519PublicKey>,
+    issuer: String,
+    audience: String,
+    // seconds `exp` and `nbf` may be off by, to tolerate clock skew
+    leeway: u64,
+}
 
 impl EncodingKey {
     pub fn load(pem: &str) -> Result<Self, jwt_simple::Error> {
-        // Ok(Self(Ed25519KeyPair::from_pem(pem)?))
-        Ed25519KeyPair::from_pem(pem).map(Self)
+        Ok(Self {
+            key: Ed25519KeyPair::from_pem(pem)?,
+            issuer: JWT_ISS.to_string(),
+            audience: JWT_AUD.to_string(),
+        })
     }
+
+    /// set the `iss` and `aud` claims embedded in signed tokens
+    pub fn with_scope(mut self, issuer: impl Into<String>, audience: impl Into<String>) -> Self {
+        self.issuer = issuer.into();
+        self.audience = audience.into();
+        self
+    }
+
+    /// set the `kid` header of signed tokens, so verifiers can pick the matching key
+    pub fn with_key_id(mut self, kid: &str) -> Self {
+        self.key = self.key.with_key_id(kid);
+        self
+    }
+
     pub fn sign(&self, user: impl Into<User>) -> Result<String, jwt_simple::Error> {
         let claims = Claims::with_custom_claims(user.into(), Duration::from(JWT_DURATION));
-        let claims = claims.with_issuer(JWT_ISS).with_audience(JWT_AUD);
+        let claims = claims
+            .with_issuer(&self.issuer)
+            .with_audience(&self.audience);
 
-        self.0.sign(claims)
+        self.key.sign(claims)
     }
 }
 
 impl DecodingKey {
     pub fn load(pem: &str) -> Result<Self, jwt_simple::Error> {
-        Ok(Self(Ed25519PublicKey::from_pem(pem)?))
+        Ok(Self {
+            keys: vec![Ed25519PublicKey::from_pem(pem)?],
+            issuer: JWT_ISS.to_string(),
+            audience: JWT_AUD.to_string(),
+            leeway: JWT_LEEWAY,
+        })
+    }
+
+    /// accept tokens signed by another key as well, used to rotate signing keys
+    pub fn add_key(mut self, pem: &str) -> Result<Self, jwt_simple::Error> {
+        self.keys.push(Ed25519PublicKey::from_pem(pem)?);
+        Ok(self)
+    }
+
+    /// like `add_key`, tokens with this `kid` header are only checked against this key
+    pub fn add_key_with_id(mut self, pem: &str, kid: &str) -> Result<Self, jwt_simple::Error> {
+        self.keys
+            .push(Ed25519PublicKey::from_pem(pem)?.with_key_id(kid));
+        Ok(self)
+    }
+
+    /// set the key id of the primary key
+    pub fn with_key_id(mut self, kid: &str) -> Self {
+        self.keys[0] = self.keys[0].clone().with_key_id(kid);
+        self
+    }
+
+    /// set the `iss` and `aud` claims a token must carry to be accepted
+    pub fn with_scope(mut self, issuer: impl Into<String>, audience: impl Into<String>) -> Self {
+        self.issuer = issuer.into();
+        self.audience = audience.into();
+        self
+    }
+
+    /// set the clock skew in seconds tolerated when checking `exp` and `nbf`
+    pub fn with_leeway(mut self, leeway: u64) -> Self {
+        self.leeway = leeway;
+        self
     }
 
     #[allow(unused)]
-    pub fn verify(&self, token: &str) -> Result<User, jwt_simple::Error> {
+    pub fn verify(&self, token: &str) -> Result<User, TokenError> {
+        self.verify_with_leeway(token, self.leeway)
+    }
+
+    /// like `verify`, tolerating `leeway` seconds of clock skew instead of the configured one
+    pub fn verify_with_leeway(&self, token: &str, leeway: u64) -> Result<User, TokenError> {
         let opts = VerificationOptions {
-            allowed_issuers: Some(HashSet::from_strings(&[JWT_ISS])),
-            allowed_audiences: Some(HashSet::from_strings(&[JWT_AUD])),
+            allowed_issuers: Some(HashSet::from_strings(&[&self.issuer])),
+            allowed_audiences: Some(HashSet::from_strings(&[&self.audience])),
+            time_tolerance: Some(Duration::from_secs(leeway)),
             ..Default::default()
         };
-        let claims = self.0.verify_token(token, Some(opts))?;
-        Ok(claims.custom)
+        for key in self.keys_for(token)? {
+            match key.verify_token::<User>(token, Some(opts.clone())) {
+                Ok(claims) => return Ok(claims.custom),
+                // signed by another key, try the next one
+                Err(e) => match TokenError::from(e) {
+                    TokenError::SignatureMismatch => continue,
+                    e => return Err(e),
+                },
+            }
+        }
+        Err(TokenError::SignatureMismatch)
+    }
+
+    /// keys to check the token against. A `kid` header picks the key with that id and fails
+    /// when no key has it, tokens without one are checked against all keys
+    fn keys_for(&self, token: &str) -> Result<Vec<&Ed25519PublicKey>, TokenError> {
+        let metadata = Token::decode_metadata(token)?;
+        let Some(kid) = metadata.key_id() else {
+            return Ok(self.keys.iter().collect());
+        };
+        match self
+            .keys
+            .iter()
+            .find(|v| v.key_id().as_deref() == Some(kid))
+        {
+            Some(key) => Ok(vec![key]),
+            None => Err(TokenError::UnknownKeyId(kid.to_string())),
+        }
     }
 }
 
@@ -60,4 +199,143 @@ mod tests {
         assert_eq!(user, user1);
         Ok(())
     }
+
+    #[test]
+    fn jwt_with_mismatched_scope_should_fail() -> Result<()> {
+        let encoding_pem = include_str!("../../fixtures/encoding.pem");
+        let decoding_pem = include_str!("../../fixtures/decoding.pem");
+
+        let dk = DecodingKey::load(decoding_pem)?.with_scope("chat_server", "chat_web");
+        let user = User::new(1, "jack", "admin@admin.com");
+
+        let ek = EncodingKey::load(encoding_pem)?.with_scope("chat_server", "other_service");
+        assert_eq!(
+            dk.verify(&ek.sign(user.clone())?),
+            Err(TokenError::ScopeMismatch)
+        );
+
+        let ek = EncodingKey::load(encoding_pem)?.with_scope("other_issuer", "chat_web");
+        assert_eq!(
+            dk.verify(&ek.sign(user.clone())?),
+            Err(TokenError::ScopeMismatch)
+        );
+
+        let ek = EncodingKey::load(encoding_pem)?.with_scope("chat_server", "chat_web");
+        assert_eq!(dk.verify(&ek.sign(user.clone())?)?, user);
+        Ok(())
+    }
+
+    #[test]
+    fn jwt_verify_with_rotated_keys_should_work() -> Result<()> {
+        let old_ek = EncodingKey::load(include_str!("../../fixtures/encoding.pem"))?;
+        let new_ek = EncodingKey::load(include_str!("../../fixtures/encoding_next.pem"))?;
+        let dk = DecodingKey::load(include_str!("../../fixtures/decoding_next.pem"))?
+            .add_key(include_str!("../../fixtures/decoding.pem"))?;
+
+        let user = User::new(1, "jack", "admin@admin.com");
+        assert_eq!(dk.verify(&old_ek.sign(user.clone())?)?, user);
+        assert_eq!(dk.verify(&new_ek.sign(user.clone())?)?, user);
+
+        let dk = DecodingKey::load(include_str!("../../fixtures/decoding_next.pem"))?;
+        assert_eq!(
+            dk.verify(&old_ek.sign(user)?),
+            Err(TokenError::SignatureMismatch)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn jwt_verify_should_select_key_by_kid() -> Result<()> {
+        let old_ek =
+            EncodingKey::load(include_str!("../../fixtures/encoding.pem"))?.with_key_id("old");
+        let new_ek =
+            EncodingKey::load(include_str!("../../fixtures/encoding_next.pem"))?.with_key_id("new");
+        let dk = DecodingKey::load(include_str!("../../fixtures/decoding_next.pem"))?
+            .with_key_id("new")
+            .add_key_with_id(include_str!("../../fixtures/decoding.pem"), "old")?;
+
+        let user = User::new(1, "jack", "admin@admin.com");
+        let token = new_ek.sign(user.clone())?;
+        assert_eq!(Token::decode_metadata(&token)?.key_id(), Some("new"));
+        assert_eq!(dk.verify(&token)?, user);
+        assert_eq!(dk.verify(&old_ek.sign(user.clone())?)?, user);
+
+        // the kid picks the key, the signature has to match that key
+        let ek = EncodingKey::load(include_str!("../../fixtures/encoding.pem"))?.with_key_id("new");
+        assert_eq!(
+            dk.verify(&ek.sign(user.clone())?),
+            Err(TokenError::SignatureMismatch)
+        );
+
+        let ek =
+            EncodingKey::load(include_str!("../../fixtures/encoding.pem"))?.with_key_id("gone");
+        assert_eq!(
+            dk.verify(&ek.sign(user.clone())?),
+            Err(TokenError::UnknownKeyId("gone".to_string()))
+        );
+
+        // keys without an id don't pick up unknown ids either
+        let dk = DecodingKey::load(include_str!("../../fixtures/decoding.pem"))?;
+        assert_eq!(
+            dk.verify(&ek.sign(user)?),
+            Err(TokenError::UnknownKeyId("gone".to_string()))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn jwt_verify_expired_token_should_fail() -> Result<()> {
+        let ek = EncodingKey::load(include_str!("../../fixtures/encoding.pem"))?;
+        let dk = DecodingKey::load(include_str!("../../fixtures/decoding.pem"))?;
+
+        // expired well beyond the default time tolerance
+        let mut claims = Claims::with_custom_claims(
+            User::new(1, "jack", "admin@admin.com"),
+            Duration::from_secs(1),
+        )
+        .with_issuer(JWT_ISS)
+        .with_audience(JWT_AUD);
+        claims.expires_at = Some(Clock::now_since_epoch() - Duration::from_hours(1));
+        let token = ek.key.sign(claims)?;
+        assert_eq!(dk.verify(&token), Err(TokenError::Expired));
+        Ok(())
+    }
+
+    #[test]
+    fn jwt_verify_should_tolerate_clock_skew_within_leeway() -> Result<()> {
+        let ek = EncodingKey::load(include_str!("../../fixtures/encoding.pem"))?;
+        let dk = DecodingKey::load(include_str!("../../fixtures/decoding.pem"))?;
+
+        let user = User::new(1, "jack", "admin@admin.com");
+        let mut claims = Claims::with_custom_claims(user.clone(), Duration::from_secs(1))
+            .with_issuer(JWT_ISS)
+            .with_audience(JWT_AUD);
+        claims.expires_at = Some(Clock::now_since_epoch() - Duration::from_secs(30));
+        let token = ek.key.sign(claims)?;
+
+        assert_eq!(dk.verify_with_leeway(&token, 60)?, user);
+        assert_eq!(dk.verify_with_leeway(&token, 0), Err(TokenError::Expired));
+        let dk = dk.with_leeway(0);
+        assert_eq!(dk.verify(&token), Err(TokenError::Expired));
+        Ok(())
+    }
+
+    #[test]
+    fn jwt_verify_malformed_token_should_fail() -> Result<()> {
+        let dk = DecodingKey::load(include_str!("../../fixtures/decoding.pem"))?;
+        assert!(matches!(
+            dk.verify("bad-token"),
+            Err(TokenError::Malformed(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn jwt_verify_with_wrong_key_should_fail() -> Result<()> {
+        let ek = EncodingKey::load(include_str!("../../fixtures/encoding_next.pem"))?;
+        let dk = DecodingKey::load(include_str!("../../fixtures/decoding.pem"))?;
+        let token = ek.sign(User::new(1, "jack", "admin@admin.com"))?;
+        assert_eq!(dk.verify(&token), Err(TokenError::SignatureMismatch));
+        Ok(())
+    }
 }
@@ -0,0 +1,133 @@
+use std::env;
+
+use serde::{Deserialize, Serialize};
+use tracing::{
+    level_filters::{LevelFilter, ParseLevelFilterError},
+    Subscriber,
+};
+use tracing_subscriber::{
+    fmt::{self, MakeWriter},
+    registry::LookupSpan,
+    Layer,
+};
+
+/// output format of the server logs
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// human readable lines
+    #[default]
+    Text,
+    /// one json object per line, for log aggregators
+    Json,
+}
+
+/// fmt layer writing events to `writer` in the given format
+pub fn log_layer<S, W>(format: LogFormat, writer: W) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    match format {
+        LogFormat::Text => fmt::layer().with_writer(writer).boxed(),
+        LogFormat::Json => fmt::layer().json().with_writer(writer).boxed(),
+    }
+}
+
+/// level from the `RUST_LOG` env, else the configured one, INFO when neither is set.
+/// Invalid values are skipped, see `invalid_env_log_level`
+pub fn log_level(configured: Option<&str>) -> LevelFilter {
+    env::var("RUST_LOG")
+        .ok()
+        .and_then(|level| parse_log_level(&level).ok())
+        .or_else(|| configured.and_then(|level| parse_log_level(level).ok()))
+        .unwrap_or(LevelFilter::INFO)
+}
+
+/// a single level like "debug" or "off"
+pub fn parse_log_level(level: &str) -> Result<LevelFilter, ParseLevelFilterError> {
+    level.trim().parse()
+}
+
+/// the `RUST_LOG` env when it's set but not a level, to be reported once logging is set up
+pub fn invalid_env_log_level() -> Option<String> {
+    env::var("RUST_LOG")
+        .ok()
+        .filter(|level| parse_log_level(level).is_err())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io,
+        sync::{Arc, Mutex},
+    };
+
+    use tracing::info;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct Buf(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Buf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl<'w> MakeWriter<'w> for Buf {
+        type Writer = Self;
+
+        fn make_writer(&'w self) -> Self::Writer {
+            self.clone()
+        }
+    }
+
+    fn log_with(format: LogFormat) -> String {
+        let buf = Buf::default();
+        let subscriber = tracing_subscriber::registry().with(log_layer(format, buf.clone()));
+        tracing::subscriber::with_default(subscriber, || info!(user_id = 1, "hello"));
+        let out = buf.0.lock().unwrap().clone();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn log_format_should_deserialize() {
+        let format: LogFormat = serde_json::from_str(r#""json""#).unwrap();
+        assert_eq!(format, LogFormat::Json);
+        let format: LogFormat = serde_json::from_str(r#""text""#).unwrap();
+        assert_eq!(format, LogFormat::Text);
+        assert_eq!(LogFormat::default(), LogFormat::Text);
+    }
+
+    #[test]
+    fn parse_log_level_should_work() {
+        assert_eq!(parse_log_level("debug").unwrap(), LevelFilter::DEBUG);
+        assert_eq!(parse_log_level(" WARN ").unwrap(), LevelFilter::WARN);
+        assert_eq!(parse_log_level("off").unwrap(), LevelFilter::OFF);
+        assert!(parse_log_level("garbage").is_err());
+    }
+
+    #[test]
+    fn text_layer_should_write_plain_lines() {
+        let out = log_with(LogFormat::Text);
+        assert!(out.contains("hello"));
+        assert!(out.contains("user_id"));
+        assert!(!out.trim_start().starts_with('{'));
+    }
+
+    #[test]
+    fn json_layer_should_write_json_lines() {
+        let out = log_with(LogFormat::Json);
+        let line: serde_json::Value = serde_json::from_str(out.trim()).unwrap();
+        assert_eq!(line["level"], "INFO");
+        assert_eq!(line["fields"]["message"], "hello");
+        assert_eq!(line["fields"]["user_id"], 1);
+    }
+}
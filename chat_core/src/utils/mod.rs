@@ -1,2 +1,8 @@
 mod jwt;
-pub use jwt::{DecodingKey, EncodingKey};
+mod log;
+mod shutdown;
+mod tls;
+pub use jwt::{DecodingKey, EncodingKey, PublicKeyConfig, TokenError};
+pub use log::{invalid_env_log_level, log_layer, log_level, parse_log_level, LogFormat};
+pub use shutdown::{graceful_shutdown, shutdown_signal};
+pub use tls::TlsConfig;
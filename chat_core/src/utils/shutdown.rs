@@ -0,0 +1,71 @@
+use std::{future::Future, time::Duration};
+
+use axum_server::Handle;
+use tokio::signal;
+use tracing::info;
+
+/// resolves when the process receives ctrl-c or SIGTERM
+pub async fn shutdown_signal() {
+    let ctrl_c = async {
+        signal::ctrl_c()
+            .await
+            .expect("failed to install ctrl-c handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+/// wait for the signal, then stop accepting connections and give in-flight requests
+/// at most `drain` to finish before they are dropped
+pub async fn graceful_shutdown(handle: Handle, signal: impl Future<Output = ()>, drain: Duration) {
+    signal.await;
+    info!("Shutting down, draining connections for {:?}", drain);
+    handle.graceful_shutdown(Some(drain));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use axum::{routing::get, Router};
+    use tokio::{sync::oneshot, time::timeout};
+
+    #[tokio::test]
+    async fn graceful_shutdown_should_stop_server() -> Result<()> {
+        let app = Router::new().route("/", get(|| async { "hello" }));
+        let handle = Handle::new();
+        let (tx, rx) = oneshot::channel::<()>();
+        tokio::spawn(graceful_shutdown(
+            handle.clone(),
+            async move {
+                let _ = rx.await;
+            },
+            Duration::from_millis(100),
+        ));
+
+        let server = axum_server::bind("127.0.0.1:0".parse()?)
+            .handle(handle.clone())
+            .serve(app.into_make_service());
+        let server = tokio::spawn(server);
+        assert!(handle.listening().await.is_some());
+
+        tx.send(()).expect("send shutdown signal");
+        let ret = timeout(Duration::from_secs(5), server).await;
+        assert!(matches!(ret, Ok(Ok(Ok(())))));
+        Ok(())
+    }
+}
@@ -0,0 +1,42 @@
+use std::{io, path::PathBuf};
+
+use axum_server::tls_rustls::RustlsConfig;
+use serde::{Deserialize, Serialize};
+
+/// pem encoded certificate chain and private key used to serve https
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+impl TlsConfig {
+    pub async fn load(&self) -> io::Result<RustlsConfig> {
+        RustlsConfig::from_pem_file(&self.cert, &self.key).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn tls_config_load_should_work() -> Result<()> {
+        let config = TlsConfig {
+            cert: "fixtures/cert.pem".into(),
+            key: "fixtures/key.pem".into(),
+        };
+        config.load().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tls_config_load_with_bad_key_should_fail() {
+        let config = TlsConfig {
+            cert: "fixtures/cert.pem".into(),
+            key: "fixtures/decoding.pem".into(),
+        };
+        assert!(config.load().await.is_err());
+    }
+}
@@ -0,0 +1,92 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+
+/// how long a positive membership check is trusted
+const MEMBER_CACHE_TTL: Duration = Duration::from_secs(10);
+
+/// positive chat membership checks keyed by (chat_id, user_id), only members are cached
+/// so a removal must invalidate the affected entries while an addition needs nothing
+pub(crate) struct MemberCache {
+    entries: DashMap<(u64, u64), Instant>,
+    ttl: Duration,
+    misses: AtomicU64,
+}
+
+impl Default for MemberCache {
+    fn default() -> Self {
+        Self::new(MEMBER_CACHE_TTL)
+    }
+}
+
+impl MemberCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            entries: DashMap::new(),
+            ttl,
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// whether the user is known to be a member, expired entries count as misses
+    pub fn contains(&self, chat_id: u64, user_id: u64) -> bool {
+        let fresh = self
+            .entries
+            .get(&(chat_id, user_id))
+            .is_some_and(|expires_at| *expires_at > Instant::now());
+        if !fresh {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+        }
+        fresh
+    }
+
+    pub fn insert(&self, chat_id: u64, user_id: u64) {
+        self.entries
+            .insert((chat_id, user_id), Instant::now() + self.ttl);
+    }
+
+    /// forget all members of the chat, e.g. when the chat is deleted or members are removed
+    pub fn invalidate_chat(&self, chat_id: u64) {
+        self.entries.retain(|(id, _), _| *id != chat_id);
+    }
+
+    /// forget all chats of the user, e.g. when the user is removed
+    pub fn invalidate_user(&self, user_id: u64) {
+        self.entries.retain(|(_, id), _| *id != user_id);
+    }
+
+    /// number of checks that had to go to the db
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn member_cache_should_expire_and_invalidate() {
+        let cache = MemberCache::new(Duration::from_secs(60));
+        assert!(!cache.contains(1, 1));
+        cache.insert(1, 1);
+        cache.insert(1, 2);
+        cache.insert(2, 1);
+        assert!(cache.contains(1, 1));
+        assert_eq!(cache.misses(), 1);
+
+        cache.invalidate_chat(1);
+        assert!(!cache.contains(1, 1));
+        assert!(!cache.contains(1, 2));
+        assert!(cache.contains(2, 1));
+        cache.invalidate_user(1);
+        assert!(!cache.contains(2, 1));
+
+        let cache = MemberCache::new(Duration::ZERO);
+        cache.insert(1, 1);
+        assert!(!cache.contains(1, 1));
+    }
+}
@@ -1,24 +1,44 @@
 use axum::{
-    http::StatusCode,
+    http::{header::RETRY_AFTER, HeaderValue, StatusCode},
     response::{IntoResponse, Response},
     Json,
 };
+use chat_core::{utils::TokenError, ChatType};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use thiserror::Error;
+use tracing::error;
 use utoipa::ToSchema;
 
+/// seconds clients are asked to wait before retrying a 503
+const RETRY_AFTER_SECS: u64 = 1;
+
 #[derive(Debug, ToSchema, Serialize, Deserialize)]
 pub struct ErrorOutput {
     pub error: String,
+    /// machine readable error code, only set for errors a client is expected to handle
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+    /// values the error was computed from, for clients to display
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Object>)]
+    pub details: Option<serde_json::Value>,
 }
 
 impl ErrorOutput {
     pub fn new(error: impl Into<String>) -> Self {
         Self {
             error: error.into(),
+            code: None,
+            details: None,
         }
     }
+
+    pub fn with_code(mut self, code: impl Into<String>, details: serde_json::Value) -> Self {
+        self.code = Some(code.into());
+        self.details = Some(details);
+        self
+    }
 }
 
 #[derive(Error, Debug)]
@@ -27,14 +47,29 @@ pub enum AppError {
     EmailAlreadyExists(String),
     #[error("create chat error: {0}")]
     CreateChatError(String),
+    #[error("create chat error: group chat with {members} members must have a name, unnamed chats have at most {max} members")]
+    ChatNameRequired {
+        members: usize,
+        max: usize,
+        /// the type the chat would have been created with
+        r#type: ChatType,
+    },
     #[error("not found: {0}")]
     NotFound(String),
     #[error("invalid input: {0}")]
     InvalidInput(String),
+    #[error("multipart error: {0}")]
+    Multipart(String),
     #[error("io error: {0}")]
     IoError(#[from] std::io::Error),
+    #[error("conflict: {0}")]
+    Conflict(String),
     #[error("permission deny")]
     PermissionDeny,
+    #[error("invalid token: {0}")]
+    TokenError(#[from] TokenError),
+    #[error("token revoked")]
+    TokenRevoked,
     #[error("sql error: {0}")]
     SqlxError(#[from] sqlx::Error),
     #[error("password hash error: {0}")]
@@ -48,14 +83,58 @@ impl IntoResponse for AppError {
         let status = match self {
             AppError::EmailAlreadyExists(_) => StatusCode::CONFLICT,
             AppError::CreateChatError(_) => StatusCode::BAD_REQUEST,
+            AppError::ChatNameRequired { .. } => StatusCode::BAD_REQUEST,
             AppError::NotFound(_) => StatusCode::NOT_FOUND,
             AppError::InvalidInput(_) => StatusCode::BAD_REQUEST,
+            AppError::Multipart(_) => StatusCode::BAD_REQUEST,
             AppError::IoError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::Conflict(_) => StatusCode::CONFLICT,
             AppError::PermissionDeny => StatusCode::FORBIDDEN,
+            AppError::TokenError(_) => StatusCode::UNAUTHORIZED,
+            AppError::TokenRevoked => StatusCode::UNAUTHORIZED,
+            // the db is busy rather than broken, clients may retry
+            AppError::SqlxError(sqlx::Error::PoolTimedOut) => StatusCode::SERVICE_UNAVAILABLE,
             AppError::SqlxError(_) => StatusCode::INTERNAL_SERVER_ERROR,
             AppError::PasswordHashError(_) => StatusCode::UNPROCESSABLE_ENTITY,
             AppError::AnyError(_) => StatusCode::INTERNAL_SERVER_ERROR,
         };
-        (status, Json(json!(ErrorOutput::new(self.to_string())))).into_response()
+        // logged inside the request span, so the request id is attached
+        if status.is_server_error() {
+            error!("request failed: {}", self);
+        }
+        let output = ErrorOutput::new(self.to_string());
+        let output = match self {
+            AppError::ChatNameRequired {
+                members,
+                max,
+                r#type,
+            } => output.with_code(
+                "chat_name_required",
+                json!({ "members": members, "max": max, "type": r#type }),
+            ),
+            _ => output,
+        };
+        let mut res = (status, Json(json!(output))).into_response();
+        if status == StatusCode::SERVICE_UNAVAILABLE {
+            res.headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from(RETRY_AFTER_SECS));
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pool_timeout_should_be_503() {
+        let res = AppError::SqlxError(sqlx::Error::PoolTimedOut).into_response();
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(res.headers()[RETRY_AFTER], "1");
+
+        let res = AppError::SqlxError(sqlx::Error::RowNotFound).into_response();
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(res.headers().get(RETRY_AFTER).is_none());
     }
 }
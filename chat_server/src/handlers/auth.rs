@@ -19,6 +19,7 @@ pub struct AuthOutput {
 /// - If the email already exists, it will return 409.
 /// - Otherwise, it will return 201 with a token.
 /// - If the workspace doesn't exist, it will create one.
+/// - If the workspace is omitted or empty, the configured default workspace is used.
 #[utoipa::path(
     post,
     path = "/api/signup",
@@ -29,8 +30,12 @@ pub struct AuthOutput {
 )]
 pub(crate) async fn signup_handler(
     State(state): State<AppState>,
-    Json(input): Json<CreateUser>,
+    Json(mut input): Json<CreateUser>,
 ) -> Result<impl IntoResponse, AppError> {
+    let has_workspace = matches!(input.workspace.as_deref(), Some(ws) if !ws.trim().is_empty());
+    if !has_workspace {
+        input.workspace = state.config.server.default_workspace.clone();
+    }
     let user = state.user_svc.create(&input).await?;
     let token = state.ek.sign(user)?;
     Ok((StatusCode::CREATED, Json(json!(AuthOutput { token }))))
@@ -66,7 +71,7 @@ pub(crate) async fn signin_handler(
 
 #[cfg(test)]
 mod tests {
-    use crate::{error::ErrorOutput, test_util::get_test_state_and_pg};
+    use crate::{config::AppConfig, error::ErrorOutput, test_util::get_test_state_and_pg};
 
     use super::*;
     use anyhow::Result;
@@ -86,6 +91,55 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn signup_without_workspace_should_use_default() -> Result<()> {
+        let mut config = AppConfig::try_load()?;
+        config.server.default_workspace = Some("lobby".to_string());
+        let (state, _tpg) = AppState::try_test_new(config).await?;
+
+        let signup = |input: CreateUser| {
+            let state = state.clone();
+            async move {
+                let ret = signup_handler(State(state.clone()), Json(input))
+                    .await?
+                    .into_response();
+                assert_eq!(ret.status(), StatusCode::CREATED);
+                let body = ret.into_body().collect().await?.to_bytes();
+                let auth: AuthOutput = serde_json::from_slice(&body)?;
+                Ok::<_, anyhow::Error>(state.dk.verify(&auth.token)?)
+            }
+        };
+
+        let input = CreateUser {
+            workspace: None,
+            ..CreateUser::new("", "tom", "tom@acme.org", "Hunter42")
+        };
+        let user = signup(input).await?;
+        let lobby = state.ws_svc.find_by_name("lobby").await?.unwrap();
+        assert_eq!(user.ws_id, lobby.id);
+
+        let user = signup(CreateUser::new("", "ann", "ann@acme.org", "Hunter42")).await?;
+        assert_eq!(user.ws_id, lobby.id);
+
+        let user = signup(CreateUser::new("ws2", "bob", "bob@acme.org", "Hunter42")).await?;
+        assert_eq!(user.ws_id, 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn signup_without_workspace_or_default_should_fail() -> Result<()> {
+        let (state, _tpg) = get_test_state_and_pg().await?;
+        let input = CreateUser {
+            workspace: None,
+            ..CreateUser::new("", "tom", "tom@acme.org", "Hunter42")
+        };
+        let ret = signup_handler(State(state), Json(input))
+            .await
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::BAD_REQUEST);
+        Ok(())
+    }
+
     #[tokio::test]
     async fn signup_duplicate_user_should_409() -> Result<()> {
         let (state, _tpg) = get_test_state_and_pg().await?;
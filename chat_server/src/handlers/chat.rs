@@ -1,5 +1,5 @@
 use axum::{
-    extract::{Path, State},
+    extract::{Path, Query, State},
     http::StatusCode,
     response::IntoResponse,
     Extension, Json,
@@ -8,21 +8,72 @@ use chat_core::User;
 
 use crate::{
     error::AppError,
-    services::{CreateChat, UpdateChat},
+    services::{
+        ChatMembership, ChatValidation, CreateChat, CreateChatOption, ListChatOption,
+        ListRecentChatOption, MarkRead, MuteChat, UpdateChat,
+    },
     AppState,
 };
 
 pub(crate) async fn list_chat_handler(
     State(state): State<AppState>,
     Extension(user): Extension<User>,
+    Query(input): Query<ListChatOption>,
 ) -> Result<impl IntoResponse, AppError> {
-    let chats = state.chat_svc.fetch_all(user.ws_id as _).await?;
-    Ok((StatusCode::OK, Json(chats)))
+    let ws_id = user.ws_id as u64;
+    if input.with_preview {
+        let mut chats = state
+            .chat_svc
+            .fetch_all_with_preview(ws_id, input.include_archived)
+            .await?;
+        if input.mine {
+            chats.retain(|v| v.chat.members.contains(&user.id));
+        }
+        return Ok((StatusCode::OK, Json(chats)).into_response());
+    }
+    let chats = if input.mine {
+        state
+            .chat_svc
+            .fetch_for_user(ws_id, user.id as _, input.include_archived)
+            .await?
+    } else {
+        state
+            .chat_svc
+            .fetch_all(ws_id, input.include_archived)
+            .await?
+    };
+    Ok((StatusCode::OK, Json(chats)).into_response())
+}
+
+/// chats of the workspace ordered by their latest message
+pub(crate) async fn list_recent_chat_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+    Query(input): Query<ListRecentChatOption>,
+) -> Result<impl IntoResponse, AppError> {
+    let chats = state
+        .chat_svc
+        .fetch_recent(user.ws_id as _, input.limit())
+        .await?;
+    Ok(Json(chats))
+}
+
+/// public channels the user can join
+pub(crate) async fn discover_chat_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+) -> Result<impl IntoResponse, AppError> {
+    let chats = state
+        .chat_svc
+        .fetch_discoverable(user.ws_id as _, user.id as _)
+        .await?;
+    Ok(Json(chats))
 }
 
 /// create new chat
 ///
 /// - If success, it'll return 201 with new chat
+/// - With `validate=true` nothing is created, it'll return 200 with the type the chat would get
 #[utoipa::path(
     post,
     path = "/api/chats",
@@ -31,22 +82,36 @@ pub(crate) async fn list_chat_handler(
     ),
     responses(
         (status = 201, description = "chat created", body = Chat),
+        (status = 200, description = "chat validated", body = ChatValidation),
     )
 )]
 pub(crate) async fn create_chat_handler(
     State(state): State<AppState>,
     Extension(user): Extension<User>,
+    Query(opts): Query<CreateChatOption>,
     Json(input): Json<CreateChat>,
 ) -> Result<impl IntoResponse, AppError> {
+    if opts.validate {
+        let r#type = state
+            .chat_svc
+            .validate_create(&input, user.ws_id as _)
+            .await?;
+        return Ok((StatusCode::OK, Json(ChatValidation { r#type })).into_response());
+    }
     let chat = state.chat_svc.create(input, user.ws_id as _).await?;
-    Ok((StatusCode::CREATED, Json(chat)))
+    Ok((StatusCode::CREATED, Json(chat)).into_response())
 }
 
+/// chats of other workspaces are reported as not found, so their existence isn't leaked
 pub(crate) async fn get_chat_handler(
     State(state): State<AppState>,
+    Extension(user): Extension<User>,
     Path(chat_id): Path<u64>,
 ) -> Result<impl IntoResponse, AppError> {
-    let chat = state.chat_svc.get_by_id(chat_id).await?;
+    let chat = state
+        .chat_svc
+        .get_by_id(chat_id, Some(user.ws_id as _))
+        .await?;
     let chat = match chat {
         Some(chat) => chat,
         None => return Err(AppError::NotFound("chat id not found".to_owned())),
@@ -60,18 +125,320 @@ pub(crate) async fn update_chat_handler(
     Path(chat_id): Path<u64>,
     Json(input): Json<UpdateChat>,
 ) -> Result<impl IntoResponse, AppError> {
+    let members_changed = input.members.is_some();
     let chat = state
         .chat_svc
         .update(input, user.ws_id as _, chat_id)
         .await?;
+    // removed members must lose access right away
+    if members_changed {
+        state.member_cache.invalidate_chat(chat_id);
+    }
     Ok((StatusCode::OK, Json(chat)))
 }
 
+/// only owners and admins of the workspace can delete chats
 pub(crate) async fn delete_chat_handler(
     State(state): State<AppState>,
     Extension(user): Extension<User>,
     Path(chat_id): Path<u64>,
 ) -> Result<impl IntoResponse, AppError> {
+    state
+        .ws_svc
+        .require_admin(user.ws_id as _, user.id as _)
+        .await?;
     let chat = state.chat_svc.delete(user.ws_id as _, chat_id).await?;
+    state.member_cache.invalidate_chat(chat_id);
+    Ok((StatusCode::OK, Json(chat)))
+}
+
+pub(crate) async fn archive_chat_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+    Path(chat_id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    let chat = state.chat_svc.archive(user.ws_id as _, chat_id).await?;
+    Ok((StatusCode::OK, Json(chat)))
+}
+
+pub(crate) async fn unarchive_chat_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+    Path(chat_id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    let chat = state.chat_svc.unarchive(user.ws_id as _, chat_id).await?;
+    Ok((StatusCode::OK, Json(chat)))
+}
+
+/// join a public channel of the workspace, open to non-members
+pub(crate) async fn join_chat_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+    Path(chat_id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    let chat = state
+        .chat_svc
+        .join(user.ws_id as _, chat_id, user.id as _)
+        .await?;
     Ok((StatusCode::OK, Json(chat)))
 }
+
+/// whether current user is a member of the chat, answers for non-members too
+pub(crate) async fn chat_membership_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+    Path(chat_id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    // chats of other workspaces look the same as missing ones
+    if state
+        .chat_svc
+        .get_by_id(chat_id, Some(user.ws_id as _))
+        .await?
+        .is_none()
+    {
+        return Err(AppError::NotFound("chat id not found".to_owned()));
+    }
+    let is_member = state.chat_svc.is_chat_member(chat_id, user.id as _).await?;
+    let role = state.ws_svc.role_of(user.ws_id as _, user.id as _).await?;
+    Ok((StatusCode::OK, Json(ChatMembership { is_member, role })))
+}
+
+/// mute or unmute new message notifications of the chat for current user
+pub(crate) async fn mute_chat_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+    Path(chat_id): Path<u64>,
+    Json(input): Json<MuteChat>,
+) -> Result<impl IntoResponse, AppError> {
+    let pref = state
+        .chat_svc
+        .set_muted(chat_id, user.id as _, input.muted)
+        .await?;
+    Ok((StatusCode::OK, Json(pref)))
+}
+
+/// mark the chat read by current user, other members get a `Seen` event
+pub(crate) async fn read_chat_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+    Path(chat_id): Path<u64>,
+    Json(input): Json<MarkRead>,
+) -> Result<impl IntoResponse, AppError> {
+    let read = state
+        .chat_svc
+        .mark_read(chat_id, user.id as _, input.message_id)
+        .await?;
+    Ok((StatusCode::OK, Json(read)))
+}
+
+/// mark every chat of current user read up to its latest message
+pub(crate) async fn read_all_chats_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+) -> Result<impl IntoResponse, AppError> {
+    let reads = state
+        .chat_svc
+        .mark_all_read(user.ws_id as _, user.id as _)
+        .await?;
+    Ok((StatusCode::OK, Json(reads)))
+}
+
+/// number of unread messages of each chat of current user
+pub(crate) async fn unread_chats_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+) -> Result<impl IntoResponse, AppError> {
+    let counts = state
+        .chat_svc
+        .unread_counts(user.ws_id as _, user.id as _)
+        .await?;
+    Ok((StatusCode::OK, Json(counts)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{get_router, test_util::get_test_state_and_pg};
+    use anyhow::Result;
+    use axum::{body::Body, extract::Request};
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn get_chat_of_other_workspace_should_404() -> Result<()> {
+        let (state, _tpg) = get_test_state_and_pg().await?;
+        let (chat_id,): (i64,) = sqlx::query_as(
+            "INSERT INTO chats(ws_id, type, members) VALUES (2, 'group', '{1,2,3}') RETURNING id",
+        )
+        .fetch_one(&state.pool)
+        .await?;
+        let user = User::new(1, "jack1", "jack1@gmail.com");
+        let user = User { ws_id: 1, ..user };
+
+        let ret = get_chat_handler(
+            State(state.clone()),
+            Extension(user.clone()),
+            Path(chat_id as u64),
+        )
+        .await
+        .into_response();
+        assert_eq!(ret.status(), StatusCode::NOT_FOUND);
+
+        let ret = get_chat_handler(State(state), Extension(user), Path(1))
+            .await
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::OK);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn join_public_channel_should_grant_access() -> Result<()> {
+        let (state, _tpg) = get_test_state_and_pg().await?;
+        let (chat_id,): (i64,) = sqlx::query_as(
+            "INSERT INTO chats(ws_id, name, type, members) VALUES (1, 'open', 'public_channel', '{1,2}') RETURNING id",
+        )
+        .fetch_one(&state.pool)
+        .await?;
+        let user = User::new(3, "jack3", "jack3@gmail.com");
+        let token = state.ek.sign(User { ws_id: 1, ..user })?;
+        let app = get_router(state).await?;
+        let req = |method: &str, uri: String| {
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::empty())
+        };
+
+        let ret = app
+            .clone()
+            .oneshot(req("GET", format!("/api/chats/{}/message", chat_id))?)
+            .await?;
+        assert_eq!(ret.status(), StatusCode::FORBIDDEN);
+
+        let ret = app
+            .clone()
+            .oneshot(req("POST", format!("/api/chats/{}/join", chat_id))?)
+            .await?;
+        assert_eq!(ret.status(), StatusCode::OK);
+
+        let ret = app
+            .clone()
+            .oneshot(req("GET", format!("/api/chats/{}/message", chat_id))?)
+            .await?;
+        assert_eq!(ret.status(), StatusCode::OK);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn removed_member_should_lose_access_right_away() -> Result<()> {
+        let (state, _tpg) = get_test_state_and_pg().await?;
+        let owner = state.ek.sign(User {
+            ws_id: 1,
+            ..User::new(1, "jack1", "jack1@gmail.com")
+        })?;
+        let removed = state.ek.sign(User {
+            ws_id: 1,
+            ..User::new(4, "jack4", "jack4@gmail.com")
+        })?;
+        let app = get_router(state).await?;
+        let req = |method: &str, token: &str, body: Body| {
+            Request::builder()
+                .method(method)
+                .uri("/api/chats/4")
+                .header("Authorization", format!("Bearer {}", token))
+                .header("Content-Type", "application/json")
+                .body(body)
+        };
+
+        // cache the membership of user 4
+        let ret = app
+            .clone()
+            .oneshot(req("GET", &removed, Body::empty())?)
+            .await?;
+        assert_eq!(ret.status(), StatusCode::OK);
+
+        let body = Body::from(r#"{"members":[1,3]}"#);
+        let ret = app.clone().oneshot(req("PATCH", &owner, body)?).await?;
+        assert_eq!(ret.status(), StatusCode::OK);
+
+        let ret = app
+            .clone()
+            .oneshot(req("GET", &removed, Body::empty())?)
+            .await?;
+        assert_eq!(ret.status(), StatusCode::FORBIDDEN);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn join_private_channel_should_403() -> Result<()> {
+        let (state, _tpg) = get_test_state_and_pg().await?;
+        let user = User::new(4, "jack4", "jack4@gmail.com");
+        let user = User { ws_id: 1, ..user };
+
+        let ret = join_chat_handler(State(state.clone()), Extension(user), Path(2))
+            .await
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::FORBIDDEN);
+        assert!(!state.chat_svc.is_chat_member(2, 4).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn chat_membership_should_answer_for_non_members() -> Result<()> {
+        let (state, _tpg) = get_test_state_and_pg().await?;
+        let member = User::new(2, "jack2", "jack2@gmail.com");
+        let member = state.ek.sign(User { ws_id: 1, ..member })?;
+        let other = User::new(5, "jack5", "jack5@gmail.com");
+        let other = state.ek.sign(User { ws_id: 1, ..other })?;
+        let app = get_router(state).await?;
+
+        for (token, is_member) in [(member, true), (other, false)] {
+            let req = Request::builder()
+                .uri("/api/chats/2/membership")
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::empty())?;
+            let res = app.clone().oneshot(req).await?;
+            assert_eq!(res.status(), StatusCode::OK);
+            let body = res.into_body().collect().await?.to_bytes();
+            let ret: ChatMembership = serde_json::from_slice(&body)?;
+            assert_eq!(ret.is_member, is_member);
+            assert!(ret.role.is_some());
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn delete_chat_should_require_admin() -> Result<()> {
+        let (state, _tpg) = get_test_state_and_pg().await?;
+        sqlx::query("UPDATE users SET role = 'admin' WHERE id = 2")
+            .execute(&state.pool)
+            .await?;
+        // user 3 is a member of chat 4, admin user 2 is not
+        let member = state.ek.sign(User {
+            ws_id: 1,
+            ..User::new(3, "jack3", "jack3@gmail.com")
+        })?;
+        let admin = state.ek.sign(User {
+            ws_id: 1,
+            ..User::new(2, "jack2", "jack2@gmail.com")
+        })?;
+        let app = get_router(state.clone()).await?;
+        let req = |token: &str| {
+            Request::builder()
+                .method("DELETE")
+                .uri("/api/chats/4")
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::empty())
+        };
+
+        let ret = app.clone().oneshot(req(&member)?).await?;
+        assert_eq!(ret.status(), StatusCode::FORBIDDEN);
+        assert!(state.chat_svc.get_by_id(4, None).await?.is_some());
+
+        let ret = app.clone().oneshot(req(&admin)?).await?;
+        assert_eq!(ret.status(), StatusCode::OK);
+        assert!(state.chat_svc.get_by_id(4, None).await?.is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,65 @@
+use axum::{extract::State, response::IntoResponse, Json};
+use serde::{Deserialize, Serialize};
+
+use crate::AppState;
+
+/// what is running, for ops to check a deployment
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ServerInfo {
+    pub version: String,
+    /// commit the binary was built from, set via the `GIT_SHA` env at build time
+    pub git_sha: Option<String>,
+    pub features: ServerFeatures,
+    pub uptime_secs: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ServerFeatures {
+    pub test_util: bool,
+    pub tls: bool,
+}
+
+pub(crate) async fn info_handler(State(state): State<AppState>) -> impl IntoResponse {
+    Json(ServerInfo {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        git_sha: option_env!("GIT_SHA").map(|sha| sha.to_string()),
+        features: ServerFeatures {
+            test_util: cfg!(feature = "test-util"),
+            tls: state.config.server.tls.is_some(),
+        },
+        uptime_secs: state.started_at.elapsed().as_secs(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{get_router, test_util::get_test_state_and_pg};
+    use anyhow::Result;
+    use axum::{body::Body, extract::Request, http::StatusCode};
+    use chat_core::User;
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn info_handler_should_work() -> Result<()> {
+        let (state, _tpg) = get_test_state_and_pg().await?;
+        let token = state.ek.sign(User::new(1, "jack1", "jack1@gmail.com"))?;
+        let app = get_router(state).await?;
+
+        let req = Request::builder()
+            .uri("/api/info")
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::empty())?;
+        let res = app.oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = res.into_body().collect().await?.to_bytes();
+        let value: serde_json::Value = serde_json::from_slice(&body)?;
+        assert!(value["uptime_secs"].is_u64());
+        let info: ServerInfo = serde_json::from_value(value)?;
+        assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+        assert!(info.features.test_util);
+        assert!(!info.features.tls);
+        Ok(())
+    }
+}
@@ -1,110 +1,961 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    path::{Component, Path as StdPath},
+    str::FromStr,
+};
+
 use axum::{
     body::Body,
     extract::{Multipart, Path, Query, State},
     http::{
-        header::{CONTENT_DISPOSITION, CONTENT_TYPE},
+        header::{CONTENT_DISPOSITION, CONTENT_LENGTH, CONTENT_TYPE, ETAG},
         HeaderMap, StatusCode,
     },
     response::IntoResponse,
     Extension, Json,
 };
-use chat_core::{Message, User};
-use tokio::fs;
-use tokio_util::io::ReaderStream;
-use tracing::{info, warn};
+use chat_core::{Attachment, Message, User};
+use chrono::{Duration, Utc};
+use futures::StreamExt;
+use tracing::warn;
 
 use crate::{
     error::AppError,
-    models::ChatFile,
-    services::{CreateMessage, ListMessageOption},
+    models::{ChatFile, CreateFileLink, FileLink},
+    services::{
+        CreateMessage, DeleteFiles, ExportFormat, ExportOption, FileDisposition, FileOption,
+        ForwardMessage, ListMessageOption, MessageCount, MessageSender, MessageWithReactions,
+        MessageWithSender, PinMessage, ReactMessage, UploadFormat, UploadOption, UploadOutput,
+        ZipFiles,
+    },
+    store::zip_files,
     AppState,
 };
 
+const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+const NDJSON_CONTENT_TYPE: &str = "application/x-ndjson";
+const MAX_ZIP_FILES: usize = 100;
+
+/// send a message, retries carrying the same `Idempotency-Key` header return the first message
 pub(crate) async fn send_message_handler(
     State(state): State<AppState>,
     Extension(user): Extension<User>,
     Path(chat_id): Path<u64>,
+    headers: HeaderMap,
     Json(input): Json<CreateMessage>,
 ) -> Result<impl IntoResponse, AppError> {
-    let message = state.msg_svc.create(input, chat_id, user.id as _).await?;
+    let key = headers
+        .get(IDEMPOTENCY_KEY_HEADER)
+        .map(|v| v.to_str())
+        .transpose()
+        .map_err(|_| AppError::InvalidInput("invalid idempotency key".to_string()))?;
+    let message = match key {
+        Some(key) => {
+            state
+                .msg_svc
+                .create_idempotent(input, chat_id, user.id as _, key)
+                .await?
+        }
+        None => state.msg_svc.create(input, chat_id, user.id as _).await?,
+    };
     Ok((StatusCode::CREATED, Json(message)))
 }
 
+/// create messages atomically, either all of them are created or none
+pub(crate) async fn send_message_batch_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+    Path(chat_id): Path<u64>,
+    Json(inputs): Json<Vec<CreateMessage>>,
+) -> Result<impl IntoResponse, AppError> {
+    let messages = state
+        .msg_svc
+        .create_batch(inputs, chat_id, user.id as _)
+        .await?;
+    Ok((StatusCode::CREATED, Json(messages)))
+}
+
+/// messages of the chat, with `with_sender` each one carries its sender's name and avatar,
+/// with `with_reactions` the count of each reaction emoji
 pub(crate) async fn list_message_handler(
     State(state): State<AppState>,
     Path(chat_id): Path<u64>,
     Query(input): Query<ListMessageOption>,
 ) -> Result<impl IntoResponse, AppError> {
-    let messages: Vec<Message> = state.msg_svc.list(input, chat_id as _).await?;
+    let with_sender = input.with_sender;
+    let with_reactions = input.with_reactions;
+    let messages: Vec<Message> = match (input.last_id, input.after_id) {
+        (Some(_), Some(_)) => {
+            return Err(AppError::InvalidInput(
+                "last_id and after_id are mutually exclusive".to_string(),
+            ))
+        }
+        (None, Some(after_id)) => {
+            state
+                .msg_svc
+                .list_after_in_range(chat_id, after_id, input.limit, input.before, input.after)
+                .await?
+        }
+        _ => state.msg_svc.list(input, chat_id as _).await?,
+    };
+    // one aggregate query for the page, rather than one per message
+    let reactions = if with_reactions {
+        let ids: Vec<_> = messages.iter().map(|msg| msg.id).collect();
+        Some(state.msg_svc.reaction_summary(&ids).await?)
+    } else {
+        None
+    };
+    if !with_sender {
+        return Ok(match reactions {
+            Some(summary) => {
+                Json(attach_reactions(messages, summary, |msg| msg.id)).into_response()
+            }
+            None => Json(messages).into_response(),
+        });
+    }
+
+    // one lookup for the page, rather than a join in each of the listing queries
+    let mut sender_ids: Vec<_> = messages.iter().map(|msg| msg.sender_id).collect();
+    sender_ids.sort_unstable();
+    sender_ids.dedup();
+    let senders: HashMap<_, _> = state
+        .user_svc
+        .fetch_by_ids(&sender_ids)
+        .await?
+        .into_iter()
+        .map(|user| {
+            let sender = MessageSender {
+                fullname: user.fullname,
+                avatar_url: user.avatar_url,
+            };
+            (user.id, sender)
+        })
+        .collect();
+    let messages: Vec<_> = messages
+        .into_iter()
+        .map(|message| MessageWithSender {
+            sender: senders.get(&message.sender_id).cloned(),
+            message,
+        })
+        .collect();
+    Ok(match reactions {
+        Some(summary) => {
+            Json(attach_reactions(messages, summary, |msg| msg.message.id)).into_response()
+        }
+        None => Json(messages).into_response(),
+    })
+}
+
+fn attach_reactions<T>(
+    messages: Vec<T>,
+    mut summary: HashMap<i64, BTreeMap<String, i64>>,
+    id: impl Fn(&T) -> i64,
+) -> Vec<MessageWithReactions<T>> {
+    messages
+        .into_iter()
+        .map(|message| MessageWithReactions {
+            reactions: summary.remove(&id(&message)).unwrap_or_default(),
+            message,
+        })
+        .collect()
+}
+
+/// all messages of the chat newest first, one json object per line, the body is streamed
+pub(crate) async fn stream_message_handler(
+    State(state): State<AppState>,
+    Path(chat_id): Path<u64>,
+) -> impl IntoResponse {
+    let lines = state.msg_svc.stream_all(chat_id).map(|msg| {
+        let mut line = serde_json::to_vec(&msg?).map_err(anyhow::Error::from)?;
+        line.push(b'\n');
+        Ok::<_, AppError>(line)
+    });
+    (
+        [(CONTENT_TYPE, NDJSON_CONTENT_TYPE)],
+        Body::from_stream(lines),
+    )
+}
+
+/// copy a message into another chat, the caller must be a member of both chats
+pub(crate) async fn forward_message_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+    Path((chat_id, message_id)): Path<(u64, u64)>,
+    Json(input): Json<ForwardMessage>,
+) -> Result<impl IntoResponse, AppError> {
+    let message = state
+        .msg_svc
+        .forward(chat_id, message_id, input.target_chat_id, user.id as _)
+        .await?;
+    Ok((StatusCode::CREATED, Json(message)))
+}
+
+/// number of messages in the chat
+pub(crate) async fn count_message_handler(
+    State(state): State<AppState>,
+    Path(chat_id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    let count = state.msg_svc.count(chat_id).await?;
+    Ok(Json(MessageCount { count }))
+}
+
+pub(crate) async fn list_pinned_message_handler(
+    State(state): State<AppState>,
+    Path(chat_id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    let messages = state.msg_svc.list_pinned(chat_id).await?;
     Ok(Json(messages))
 }
 
-pub(crate) async fn file_handler(
+/// pin or unpin a message, 409 if the chat has too many pinned messages
+pub(crate) async fn pin_message_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+    Path(chat_id): Path<u64>,
+    Json(input): Json<PinMessage>,
+) -> Result<impl IntoResponse, AppError> {
+    let message = state
+        .msg_svc
+        .set_pinned(chat_id, input.message_id, input.pinned, user.id as _)
+        .await?;
+    Ok(Json(message))
+}
+
+/// add or remove the caller's reaction to a message
+pub(crate) async fn react_message_handler(
+    State(state): State<AppState>,
     Extension(user): Extension<User>,
+    Path(chat_id): Path<u64>,
+    Json(input): Json<ReactMessage>,
+) -> Result<impl IntoResponse, AppError> {
+    state
+        .msg_svc
+        .set_reaction(chat_id, user.id as _, &input)
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// export all messages of the chat as json or csv, the body is streamed
+pub(crate) async fn export_message_handler(
+    State(state): State<AppState>,
+    Path(chat_id): Path<u64>,
+    Query(input): Query<ExportOption>,
+) -> Result<impl IntoResponse, AppError> {
+    let sender_ids = state.msg_svc.fetch_sender_ids(chat_id).await?;
+    let senders = state
+        .user_svc
+        .fetch_by_ids(&sender_ids)
+        .await?
+        .into_iter()
+        .map(|user| (user.id, user.fullname))
+        .collect();
+    let (mime, ext) = match input.format {
+        ExportFormat::Json => ("application/json", "json"),
+        ExportFormat::Csv => ("text/csv", "csv"),
+    };
+    let stream = state.msg_svc.export(chat_id, senders, input.format);
+    let headers = HeaderMap::from_iter([
+        (CONTENT_TYPE, mime.parse().unwrap()),
+        (
+            CONTENT_DISPOSITION,
+            format!("attachment; filename=\"chat-{}.{}\"", chat_id, ext)
+                .parse()
+                .unwrap(),
+        ),
+    ]);
+    Ok((headers, Body::from_stream(stream)))
+}
+
+/// download a file of the workspace, signed links verified by `verify_file_access` have no user
+pub(crate) async fn file_handler(
+    user: Option<Extension<User>>,
     State(state): State<AppState>,
     Path((ws_id, path)): Path<(u64, String)>,
+    Query(input): Query<FileOption>,
 ) -> Result<impl IntoResponse, AppError> {
-    if ws_id != user.ws_id as u64 {
-        return Err(AppError::PermissionDeny);
-    }
+    let file = resolve_file(user, ws_id, &path)?;
+    let size = state.store.size(&file).await?;
+    let stream = state.store.get(&file).await?;
+    let headers = file_headers(&file, &path, size, input.disposition);
+    Ok((headers, Body::from_stream(stream)))
+}
 
-    let base_dir = state.config.server.base_dir.join(ws_id.to_string());
-    let path = base_dir.join(path);
-    if !path.exists() {
-        return Err(AppError::NotFound("file doesn't exist".to_string()));
+/// the headers of `file_handler` without the body
+pub(crate) async fn file_head_handler(
+    user: Option<Extension<User>>,
+    State(state): State<AppState>,
+    Path((ws_id, path)): Path<(u64, String)>,
+    Query(input): Query<FileOption>,
+) -> Result<impl IntoResponse, AppError> {
+    let file = resolve_file(user, ws_id, &path)?;
+    let size = state.store.size(&file).await?;
+    Ok(file_headers(&file, &path, size, input.disposition))
+}
+
+/// the file `path` of the workspace refers to, users can't access files of other workspaces
+fn resolve_file(
+    user: Option<Extension<User>>,
+    ws_id: u64,
+    path: &str,
+) -> Result<ChatFile, AppError> {
+    if let Some(Extension(user)) = user {
+        if ws_id != user.ws_id as u64 {
+            return Err(AppError::PermissionDeny);
+        }
     }
-    // get path filename
-    let filename = path
-        .file_name()
-        .ok_or(AppError::AnyError(anyhow::anyhow!("invalid path")))?
-        .to_str()
-        .ok_or(AppError::AnyError(anyhow::anyhow!("invalid path")))?;
-    let mime = mime_guess::from_path(&path).first_or_octet_stream();
-
-    let file = fs::File::open(&path).await?;
-    let stream = ReaderStream::new(file);
-    // let body = fs::read(path).await?;
-    let headers = HeaderMap::from_iter([
+    parse_file_path(ws_id, path)
+}
+
+/// files are content addressed, so the hash is a strong etag
+fn file_headers(file: &ChatFile, path: &str, size: u64, disposition: FileDisposition) -> HeaderMap {
+    let filename = path.rsplit('/').next().unwrap_or_default();
+    let mime = mime_guess::from_path(path).first_or_octet_stream();
+    let disposition = match disposition {
+        FileDisposition::Attachment => "attachment",
+        FileDisposition::Inline => "inline",
+    };
+    HeaderMap::from_iter([
         (CONTENT_TYPE, mime.to_string().parse().unwrap()),
+        (CONTENT_LENGTH, size.into()),
+        (ETAG, format!("\"{}\"", file.hash).parse().unwrap()),
         (
             CONTENT_DISPOSITION,
-            format!("attachment; filename=\"{}\"", filename)
+            format!("{}; filename=\"{}\"", disposition, filename)
                 .parse()
                 .unwrap(),
         ),
+    ])
+}
+
+/// a temporary link to a file of the caller's workspace that can be fetched without a token
+pub(crate) async fn create_file_link_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<CreateFileLink>,
+) -> Result<impl IntoResponse, AppError> {
+    let file = ChatFile::from_str(&input.url)?;
+    if file.ws_id != user.ws_id as u64 {
+        return Err(AppError::PermissionDeny);
+    }
+    if !state.store.exists(&file).await? {
+        return Err(AppError::NotFound("file doesn't exist".to_string()));
+    }
+    let expires_at = Utc::now() + Duration::seconds(state.config.server.file_link_ttl_secs as _);
+    let expires = expires_at.timestamp();
+    let sig = state.file_link_key.sign(&file.url(), expires);
+    let base_url = state.config.server.public_base_url.as_deref();
+    let url = format!(
+        "{}?expires={}&sig={}",
+        file.public_url(base_url),
+        expires,
+        sig
+    );
+    Ok(Json(FileLink { url, expires_at }))
+}
+
+/// download files of the caller's workspace as a zip archive built while it's streamed,
+/// files that don't exist are listed in a manifest instead
+pub(crate) async fn zip_files_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<ZipFiles>,
+) -> Result<impl IntoResponse, AppError> {
+    if input.urls.is_empty() || input.urls.len() > MAX_ZIP_FILES {
+        return Err(AppError::InvalidInput(format!(
+            "urls must have 1 to {} items",
+            MAX_ZIP_FILES
+        )));
+    }
+    let mut seen = HashSet::new();
+    let mut files = Vec::with_capacity(input.urls.len());
+    for url in &input.urls {
+        let file = ChatFile::from_str(url)?;
+        if file.ws_id != user.ws_id as u64 {
+            return Err(AppError::PermissionDeny);
+        }
+        if seen.insert(file.url()) {
+            files.push(file);
+        }
+    }
+
+    let stream = zip_files(state.store.clone(), files);
+    let headers = HeaderMap::from_iter([
+        (CONTENT_TYPE, "application/zip".parse().unwrap()),
+        (
+            CONTENT_DISPOSITION,
+            "attachment; filename=\"files.zip\"".parse().unwrap(),
+        ),
     ]);
     Ok((headers, Body::from_stream(stream)))
 }
 
+/// parse a user supplied path under the workspace, anything trying to escape it
+/// (via `..` or absolute paths) is denied, other paths not naming a stored file are not found
+fn parse_file_path(ws_id: u64, path: &str) -> Result<ChatFile, AppError> {
+    if !StdPath::new(path)
+        .components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+    {
+        return Err(AppError::PermissionDeny);
+    }
+    ChatFile::from_str(&format!("/files/{}/{}", ws_id, path))
+        .map_err(|_| AppError::NotFound("file doesn't exist".to_string()))
+}
+
+/// store the uploaded files, returns the url and metadata of each one, as a bare array
+/// with `format=array`
 pub(crate) async fn upload_handler(
     Extension(user): Extension<User>,
     State(state): State<AppState>,
+    Query(input): Query<UploadOption>,
     mut multipart: Multipart,
 ) -> Result<impl IntoResponse, AppError> {
     let ws_id = user.ws_id as u64;
-    let base_dir = &state.config.server.base_dir;
     let mut files = vec![];
     while let Some(field) = multipart
         .next_field()
         .await
-        .map_err(|_| AppError::AnyError(anyhow::anyhow!("multipart error")))?
+        .map_err(|e| AppError::Multipart(e.body_text()))?
     {
-        let filename = field.file_name().map(|name| name.to_owned());
-        let (Some(filename), Ok(data)) = (filename, field.bytes().await) else {
-            warn!("failed to read multipart field");
+        if field.name() != Some(state.config.server.upload_field.as_str()) {
+            warn!("skip unexpected multipart field {:?}", field.name());
+            continue;
+        }
+        let Some(filename) = field.file_name().map(|name| name.to_owned()) else {
+            warn!("skip multipart field without filename");
             continue;
         };
+        let data = field
+            .bytes()
+            .await
+            .map_err(|e| AppError::Multipart(e.body_text()))?;
+        if data.is_empty() && !state.config.server.allow_empty_uploads {
+            return Err(AppError::InvalidInput("empty file".to_string()));
+        }
 
         let file = ChatFile::new(ws_id, &filename, &data);
-        files.push(file.url());
-        let path = file.path(base_dir);
-        if path.exists() {
-            info!("File {} already exists: {:?}", filename, path);
-            continue;
-        } else {
-            fs::create_dir_all(path.parent().expect("file path parent should exists")).await?;
-            fs::write(path, data).await?;
+        let size = data.len() as u64;
+        state.store.put(&file, data).await?;
+        files.push(Attachment {
+            url: file.public_url(state.config.server.public_base_url.as_deref()),
+            size,
+            mime: file.mime(),
+            filename,
+        });
+    }
+    Ok(match input.format {
+        UploadFormat::Object => Json(UploadOutput { files }).into_response(),
+        UploadFormat::Array => Json(files).into_response(),
+    })
+}
+
+/// delete uploaded files that no message references, each url gets its own status
+pub(crate) async fn delete_upload_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<DeleteFiles>,
+) -> Result<impl IntoResponse, AppError> {
+    let results = state
+        .msg_svc
+        .delete_unused_files(user.ws_id as _, input.urls)
+        .await?;
+    Ok(Json(results))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{error::ErrorOutput, test_util::get_test_state_and_pg};
+    use anyhow::Result;
+    use axum::body::Bytes;
+    use http_body_util::BodyExt;
+
+    #[tokio::test]
+    async fn file_handler_with_missing_file_should_404() -> Result<()> {
+        let (state, _tpg) = get_test_state_and_pg().await?;
+        let user = User::new(1, "jack1", "jack1@gmail.com");
+        let user = User { ws_id: 1, ..user };
+        let ret = file_handler(
+            Some(Extension(user)),
+            State(state),
+            Path((1, "no/such/file.txt".to_string())),
+            Query(FileOption::default()),
+        )
+        .await
+        .into_response();
+        assert_eq!(ret.status(), StatusCode::NOT_FOUND);
+        let body = ret.into_body().collect().await?.to_bytes();
+        let ret: ErrorOutput = serde_json::from_slice(&body)?;
+        assert_eq!(ret.error, "not found: file doesn't exist");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn file_handler_with_traversal_path_should_403() -> Result<()> {
+        let (state, _tpg) = get_test_state_and_pg().await?;
+        let user = User::new(1, "jack1", "jack1@gmail.com");
+        let user = User { ws_id: 1, ..user };
+        let ret = file_handler(
+            Some(Extension(user)),
+            State(state),
+            Path((1, "../../../etc/passwd".to_string())),
+            Query(FileOption::default()),
+        )
+        .await
+        .into_response();
+        assert_eq!(ret.status(), StatusCode::FORBIDDEN);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn file_handler_with_normal_path_should_work() -> Result<()> {
+        let (state, _tpg) = get_test_state_and_pg().await?;
+        let user = User::new(1, "jack1", "jack1@gmail.com");
+        let user = User { ws_id: 1, ..user };
+        let content = b"hello file handler";
+        let file = ChatFile::new(1, "hello.txt", content);
+        state.store.put(&file, Bytes::from_static(content)).await?;
+
+        let (_, rel) = file.hash_to_path().split_once('/').unwrap();
+        let ret = file_handler(
+            Some(Extension(user)),
+            State(state),
+            Path((1, rel.to_string())),
+            Query(FileOption::default()),
+        )
+        .await
+        .into_response();
+        assert_eq!(ret.status(), StatusCode::OK);
+        let body = ret.into_body().collect().await?.to_bytes();
+        assert_eq!(&body[..], content);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn head_file_should_return_headers_only() -> Result<()> {
+        let (state, _tpg) = get_test_state_and_pg().await?;
+        let user = User::new(1, "jack1", "jack1@gmail.com");
+        let token = state.ek.sign(User { ws_id: 1, ..user })?;
+        let content = b"hello head";
+        let file = ChatFile::new(1, "head.txt", content);
+        state.store.put(&file, Bytes::from_static(content)).await?;
+        let app = crate::get_router(state).await?;
+
+        let req = axum::extract::Request::builder()
+            .method("HEAD")
+            .uri(format!("/api{}", file.url()))
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::empty())?;
+        let ret = tower::ServiceExt::oneshot(app.clone(), req).await?;
+        assert_eq!(ret.status(), StatusCode::OK);
+        assert_eq!(ret.headers()[CONTENT_TYPE], "text/plain");
+        assert_eq!(
+            ret.headers()[CONTENT_LENGTH],
+            content.len().to_string().as_str()
+        );
+        assert_eq!(ret.headers()[ETAG], format!("\"{}\"", file.hash).as_str());
+        let body = ret.into_body().collect().await?.to_bytes();
+        assert!(body.is_empty());
+
+        // same checks as GET
+        let req = axum::extract::Request::builder()
+            .method("HEAD")
+            .uri("/api/files/2/no/such/file.txt")
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::empty())?;
+        let ret = tower::ServiceExt::oneshot(app, req).await?;
+        assert_eq!(ret.status(), StatusCode::FORBIDDEN);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn file_handler_with_inline_disposition_should_work() -> Result<()> {
+        let (state, _tpg) = get_test_state_and_pg().await?;
+        let user = User::new(1, "jack1", "jack1@gmail.com");
+        let user = User { ws_id: 1, ..user };
+        let content = b"not really a png";
+        let file = ChatFile::new(1, "inline.png", content);
+        state.store.put(&file, Bytes::from_static(content)).await?;
+
+        let (_, rel) = file.hash_to_path().split_once('/').unwrap();
+        let get = |disposition| {
+            file_handler(
+                Some(Extension(user.clone())),
+                State(state.clone()),
+                Path((1, rel.to_string())),
+                Query(FileOption { disposition }),
+            )
+        };
+        let ret = get(FileDisposition::Inline).await.into_response();
+        assert_eq!(ret.status(), StatusCode::OK);
+        let name = rel.rsplit('/').next().unwrap();
+        assert_eq!(
+            ret.headers()[CONTENT_DISPOSITION],
+            format!("inline; filename=\"{}\"", name).as_str()
+        );
+        let ret = get(FileDisposition::default()).await.into_response();
+        assert_eq!(
+            ret.headers()[CONTENT_DISPOSITION],
+            format!("attachment; filename=\"{}\"", name).as_str()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn upload_broken_multipart_should_400() -> Result<()> {
+        let (state, _tpg) = get_test_state_and_pg().await?;
+        let token = state.ek.sign(User::new(1, "jack1", "jack1@gmail.com"))?;
+        let app = crate::get_router(state).await?;
+
+        let req = axum::extract::Request::builder()
+            .method("POST")
+            .uri("/api/upload")
+            .header("Authorization", format!("Bearer {}", token))
+            .header(CONTENT_TYPE, "multipart/form-data; boundary=MyBoundary")
+            .body(Body::from(
+                "--MyBoundary\r\nContent-Disposition: form-data; name=\"files\"; filename=\"a.txt\"\r\n\r\nbroken",
+            ))?;
+        let ret = tower::ServiceExt::oneshot(app, req).await?;
+        assert_eq!(ret.status(), StatusCode::BAD_REQUEST);
+        let body = ret.into_body().collect().await?.to_bytes();
+        let ret: ErrorOutput = serde_json::from_slice(&body)?;
+        assert!(ret.error.starts_with("multipart error: "));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn file_handler_with_deleted_file_should_404() -> Result<()> {
+        let (state, _tpg) = get_test_state_and_pg().await?;
+        let user = User::new(1, "jack1", "jack1@gmail.com");
+        let user = User { ws_id: 1, ..user };
+        let content = b"deleted soon";
+        let file = ChatFile::new(1, "gone.txt", content);
+        state.store.put(&file, Bytes::from_static(content)).await?;
+        assert!(state.store.delete(&file).await?);
+
+        let (_, rel) = file.hash_to_path().split_once('/').unwrap();
+        let ret = file_handler(
+            Some(Extension(user)),
+            State(state),
+            Path((1, rel.to_string())),
+            Query(FileOption::default()),
+        )
+        .await
+        .into_response();
+        assert_eq!(ret.status(), StatusCode::NOT_FOUND);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn uploaded_file_should_be_downloadable() -> Result<()> {
+        let (state, _tpg) = get_test_state_and_pg().await?;
+        let token = state.ek.sign(User::new(1, "jack1", "jack1@gmail.com"))?;
+        let app = crate::get_router(state.clone()).await?;
+
+        let req = axum::extract::Request::builder()
+            .method("POST")
+            .uri("/api/upload?format=array")
+            .header("Authorization", format!("Bearer {}", token))
+            .header(CONTENT_TYPE, "multipart/form-data; boundary=MyBoundary")
+            .body(Body::from(
+                "--MyBoundary\r\nContent-Disposition: form-data; name=\"files\"; filename=\"a.txt\"\r\n\r\nhello store\r\n--MyBoundary--\r\n",
+            ))?;
+        let ret = tower::ServiceExt::oneshot(app.clone(), req).await?;
+        assert_eq!(ret.status(), StatusCode::OK);
+        let body = ret.into_body().collect().await?.to_bytes();
+        let attachments: Vec<Attachment> = serde_json::from_slice(&body)?;
+        assert_eq!(attachments.len(), 1);
+        assert_eq!(attachments[0].size, "hello store".len() as u64);
+        assert_eq!(attachments[0].mime, "text/plain");
+        assert_eq!(attachments[0].filename, "a.txt");
+        let file = ChatFile::from_str(&attachments[0].url)?;
+        assert!(state.store.exists(&file).await?);
+
+        let req = axum::extract::Request::builder()
+            .uri(format!("/api{}", file.url()))
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::empty())?;
+        let ret = tower::ServiceExt::oneshot(app, req).await?;
+        assert_eq!(ret.status(), StatusCode::OK);
+        let body = ret.into_body().collect().await?.to_bytes();
+        assert_eq!(&body[..], b"hello store");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn signed_file_link_should_work_without_token() -> Result<()> {
+        let (state, _tpg) = get_test_state_and_pg().await?;
+        let user = User::new(1, "jack1", "jack1@gmail.com");
+        let token = state.ek.sign(User { ws_id: 1, ..user })?;
+        let content = b"hello signed link";
+        let file = ChatFile::new(1, "link.txt", content);
+        state.store.put(&file, Bytes::from_static(content)).await?;
+        let app = crate::get_router(state.clone()).await?;
+
+        let req = axum::extract::Request::builder()
+            .method("POST")
+            .uri("/api/files/link")
+            .header("Authorization", format!("Bearer {}", token))
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(format!(r#"{{"url":"{}"}}"#, file.url())))?;
+        let ret = tower::ServiceExt::oneshot(app.clone(), req).await?;
+        assert_eq!(ret.status(), StatusCode::OK);
+        let body = ret.into_body().collect().await?.to_bytes();
+        let link: FileLink = serde_json::from_slice(&body)?;
+
+        let get = |uri: String| {
+            axum::extract::Request::builder()
+                .uri(uri)
+                .body(Body::empty())
+        };
+        let ret =
+            tower::ServiceExt::oneshot(app.clone(), get(format!("/api{}", link.url))?).await?;
+        assert_eq!(ret.status(), StatusCode::OK);
+        let body = ret.into_body().collect().await?.to_bytes();
+        assert_eq!(&body[..], content);
+
+        // tampered signature
+        let tampered = format!("/api{}00", link.url);
+        let ret = tower::ServiceExt::oneshot(app.clone(), get(tampered)?).await?;
+        assert_eq!(ret.status(), StatusCode::FORBIDDEN);
+
+        let expires = Utc::now().timestamp() - 1;
+        let sig = state.file_link_key.sign(&file.url(), expires);
+        let expired = format!("/api{}?expires={}&sig={}", file.url(), expires, sig);
+        let ret = tower::ServiceExt::oneshot(app.clone(), get(expired)?).await?;
+        assert_eq!(ret.status(), StatusCode::FORBIDDEN);
+
+        // neither a link nor a token
+        let ret = tower::ServiceExt::oneshot(app, get(format!("/api{}", file.url()))?).await?;
+        assert_eq!(ret.status(), StatusCode::BAD_REQUEST);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn upload_should_return_files_object() -> Result<()> {
+        let (state, _tpg) = get_test_state_and_pg().await?;
+        let token = state.ek.sign(User::new(1, "jack1", "jack1@gmail.com"))?;
+        let app = crate::get_router(state).await?;
+
+        let req = axum::extract::Request::builder()
+            .method("POST")
+            .uri("/api/upload")
+            .header("Authorization", format!("Bearer {}", token))
+            .header(CONTENT_TYPE, "multipart/form-data; boundary=MyBoundary")
+            .body(Body::from(
+                "--MyBoundary\r\nContent-Disposition: form-data; name=\"files\"; filename=\"b.txt\"\r\n\r\nhello object\r\n--MyBoundary--\r\n",
+            ))?;
+        let ret = tower::ServiceExt::oneshot(app, req).await?;
+        assert_eq!(ret.status(), StatusCode::OK);
+        let body = ret.into_body().collect().await?.to_bytes();
+        let ret: UploadOutput = serde_json::from_slice(&body)?;
+        assert_eq!(ret.files.len(), 1);
+        assert_eq!(ret.files[0].filename, "b.txt");
+        assert_eq!(ret.files[0].size, "hello object".len() as u64);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn upload_empty_file_should_400() -> Result<()> {
+        let (state, _tpg) = get_test_state_and_pg().await?;
+        let token = state.ek.sign(User::new(1, "jack1", "jack1@gmail.com"))?;
+        let app = crate::get_router(state).await?;
+
+        let upload = |content: &'static str| {
+            axum::extract::Request::builder()
+                .method("POST")
+                .uri("/api/upload")
+                .header("Authorization", format!("Bearer {}", token))
+                .header(CONTENT_TYPE, "multipart/form-data; boundary=MyBoundary")
+                .body(Body::from(format!(
+                    "--MyBoundary\r\nContent-Disposition: form-data; name=\"files\"; filename=\"a.txt\"\r\n\r\n{}\r\n--MyBoundary--\r\n",
+                    content
+                )))
+        };
+        let ret = tower::ServiceExt::oneshot(app.clone(), upload("")?).await?;
+        assert_eq!(ret.status(), StatusCode::BAD_REQUEST);
+        let body = ret.into_body().collect().await?.to_bytes();
+        let ret: ErrorOutput = serde_json::from_slice(&body)?;
+        assert_eq!(ret.error, "invalid input: empty file");
+
+        let ret = tower::ServiceExt::oneshot(app, upload("not empty")?).await?;
+        assert_eq!(ret.status(), StatusCode::OK);
+        let body = ret.into_body().collect().await?.to_bytes();
+        let ret: UploadOutput = serde_json::from_slice(&body)?;
+        assert_eq!(ret.files[0].size, "not empty".len() as u64);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn upload_under_unexpected_field_should_be_skipped() -> Result<()> {
+        let (state, _tpg) = get_test_state_and_pg().await?;
+        let token = state.ek.sign(User::new(1, "jack1", "jack1@gmail.com"))?;
+        let app = crate::get_router(state.clone()).await?;
+
+        let req = axum::extract::Request::builder()
+            .method("POST")
+            .uri("/api/upload?format=array")
+            .header("Authorization", format!("Bearer {}", token))
+            .header(CONTENT_TYPE, "multipart/form-data; boundary=MyBoundary")
+            .body(Body::from(
+                "--MyBoundary\r\nContent-Disposition: form-data; name=\"avatar\"; filename=\"a.txt\"\r\n\r\nstray\r\n--MyBoundary--\r\n",
+            ))?;
+        let ret = tower::ServiceExt::oneshot(app, req).await?;
+        assert_eq!(ret.status(), StatusCode::OK);
+        let body = ret.into_body().collect().await?.to_bytes();
+        let attachments: Vec<Attachment> = serde_json::from_slice(&body)?;
+        assert!(attachments.is_empty());
+        let file = ChatFile::new(0, "a.txt", b"stray");
+        assert!(!state.store.exists(&file).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn zip_files_should_contain_every_file() -> Result<()> {
+        let (state, _tpg) = get_test_state_and_pg().await?;
+        let user = User::new(1, "jack1", "jack1@gmail.com");
+        let token = state.ek.sign(User { ws_id: 1, ..user })?;
+        let file1 = ChatFile::new(1, "a.txt", b"hello zip");
+        let file2 = ChatFile::new(1, "b.png", b"not really a png");
+        state
+            .store
+            .put(&file1, Bytes::from_static(b"hello zip"))
+            .await?;
+        state
+            .store
+            .put(&file2, Bytes::from_static(b"not really a png"))
+            .await?;
+        let missing = ChatFile::new(1, "c.txt", b"never uploaded");
+        let app = crate::get_router(state).await?;
+
+        let urls = [file1.url(), file2.url(), file1.url(), missing.url()];
+        let req = axum::extract::Request::builder()
+            .method("POST")
+            .uri("/api/files/zip")
+            .header("Authorization", format!("Bearer {}", token))
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(serde_json::to_vec(&ZipFiles {
+                urls: urls.to_vec(),
+            })?))?;
+        let ret = tower::ServiceExt::oneshot(app.clone(), req).await?;
+        assert_eq!(ret.status(), StatusCode::OK);
+        assert_eq!(ret.headers()[CONTENT_TYPE], "application/zip");
+        let body = ret.into_body().collect().await?.to_bytes();
+
+        let mut archive = zip::ZipArchive::new(std::io::Cursor::new(body))?;
+        assert_eq!(archive.len(), 3);
+        let read = |archive: &mut zip::ZipArchive<_>, name: &str| -> Result<String> {
+            let mut content = String::new();
+            std::io::Read::read_to_string(&mut archive.by_name(name)?, &mut content)?;
+            Ok(content)
+        };
+        let name1 = crate::store::entry_name(&file1);
+        let name2 = crate::store::entry_name(&file2);
+        assert_eq!(read(&mut archive, &name1)?, "hello zip");
+        assert_eq!(read(&mut archive, &name2)?, "not really a png");
+        let manifest = read(&mut archive, crate::store::MANIFEST_NAME)?;
+        assert!(manifest.contains(&missing.url()));
+
+        // files of other workspaces are denied
+        let other = ChatFile::new(2, "a.txt", b"hello zip");
+        let req = axum::extract::Request::builder()
+            .method("POST")
+            .uri("/api/files/zip")
+            .header("Authorization", format!("Bearer {}", token))
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(serde_json::to_vec(&ZipFiles {
+                urls: vec![file1.url(), other.url()],
+            })?))?;
+        let ret = tower::ServiceExt::oneshot(app, req).await?;
+        assert_eq!(ret.status(), StatusCode::FORBIDDEN);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn list_messages_with_sender_should_carry_fullname() -> Result<()> {
+        let (state, _tpg) = get_test_state_and_pg().await?;
+        let token = state.ek.sign(User::new(1, "jack1", "jack1@gmail.com"))?;
+        let app = crate::get_router(state).await?;
+
+        let req = axum::extract::Request::builder()
+            .uri("/api/chats/1/message?limit=10&with_sender=true")
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::empty())?;
+        let ret = tower::ServiceExt::oneshot(app, req).await?;
+        assert_eq!(ret.status(), StatusCode::OK);
+        let body = ret.into_body().collect().await?.to_bytes();
+        let messages: Vec<MessageWithSender> = serde_json::from_slice(&body)?;
+        assert!(!messages.is_empty());
+        for msg in messages {
+            let sender = msg.sender.expect("sender should be resolved");
+            assert_eq!(sender.fullname, format!("jack{}", msg.message.sender_id));
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn list_messages_with_reactions_should_carry_summary() -> Result<()> {
+        let (state, _tpg) = get_test_state_and_pg().await?;
+        let token1 = state.ek.sign(User::new(1, "jack1", "jack1@gmail.com"))?;
+        let token2 = state.ek.sign(User::new(2, "jack2", "jack2@gmail.com"))?;
+        let app = crate::get_router(state).await?;
+
+        for (token, emoji) in [(&token1, "👍"), (&token2, "👍"), (&token1, "🎉")] {
+            let req = axum::extract::Request::builder()
+                .method("POST")
+                .uri("/api/chats/1/reactions")
+                .header("Authorization", format!("Bearer {}", token))
+                .header("Content-Type", "application/json")
+                .body(Body::from(serde_json::to_string(&ReactMessage {
+                    message_id: 1,
+                    emoji: emoji.to_string(),
+                    reacted: true,
+                })?))?;
+            let ret = tower::ServiceExt::oneshot(app.clone(), req).await?;
+            assert_eq!(ret.status(), StatusCode::NO_CONTENT);
         }
+
+        let req = axum::extract::Request::builder()
+            .uri("/api/chats/1/message?limit=20&with_reactions=true")
+            .header("Authorization", format!("Bearer {}", token1))
+            .body(Body::empty())?;
+        let ret = tower::ServiceExt::oneshot(app, req).await?;
+        assert_eq!(ret.status(), StatusCode::OK);
+        let body = ret.into_body().collect().await?.to_bytes();
+        let messages: Vec<MessageWithReactions<Message>> = serde_json::from_slice(&body)?;
+        assert!(!messages.is_empty());
+        for msg in messages {
+            if msg.message.id == 1 {
+                let expected = BTreeMap::from([("🎉".to_string(), 1), ("👍".to_string(), 2)]);
+                assert_eq!(msg.reactions, expected);
+            } else {
+                assert!(msg.reactions.is_empty());
+            }
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stream_messages_should_emit_one_line_per_message() -> Result<()> {
+        let (state, _tpg) = get_test_state_and_pg().await?;
+        let token = state.ek.sign(User::new(1, "jack1", "jack1@gmail.com"))?;
+        let count = state.msg_svc.count(1).await?;
+        let app = crate::get_router(state).await?;
+
+        let req = axum::extract::Request::builder()
+            .uri("/api/chats/1/message/stream")
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::empty())?;
+        let ret = tower::ServiceExt::oneshot(app, req).await?;
+        assert_eq!(ret.status(), StatusCode::OK);
+        assert_eq!(ret.headers()[CONTENT_TYPE], NDJSON_CONTENT_TYPE);
+        let body = ret.into_body().collect().await?.to_bytes();
+        let messages = std::str::from_utf8(&body)?
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<Vec<Message>, _>>()?;
+        assert_eq!(messages.len() as i64, count);
+        assert!(messages.windows(2).all(|w| w[0].id > w[1].id));
+        Ok(())
     }
-    Ok(Json(files))
 }
@@ -0,0 +1,57 @@
+use axum::{extract::State, http::header::CONTENT_TYPE, response::IntoResponse};
+
+use crate::{error::AppError, AppState};
+
+const PROMETHEUS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// counters of users, chats and messages in the prometheus text format
+pub(crate) async fn metrics_handler(
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let metrics = state.metrics_svc.collect().await?;
+    Ok((
+        [(CONTENT_TYPE, PROMETHEUS_CONTENT_TYPE)],
+        metrics.to_prometheus(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{get_router, services::SigninUser, test_util::get_test_state_and_pg};
+    use anyhow::Result;
+    use axum::{body::Body, extract::Request, http::StatusCode};
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn metrics_handler_should_work() -> Result<()> {
+        let (state, _tpg) = get_test_state_and_pg().await?;
+        let user = state
+            .user_svc
+            .verify(&SigninUser::new("jack1@gmail.com", "Hunter48"))
+            .await?
+            .expect("user should exist");
+        let token = state.ek.sign(user)?;
+        let app = get_router(state).await?;
+
+        let req = Request::builder().uri("/metrics").body(Body::empty())?;
+        let res = app.clone().oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        let req = Request::builder()
+            .uri("/metrics")
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::empty())?;
+        let res = app.oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = res.into_body().collect().await?.to_bytes();
+        let body = String::from_utf8(body.to_vec())?;
+        let total: u64 = body
+            .lines()
+            .find_map(|line| line.strip_prefix("chat_messages_total "))
+            .expect("chat_messages_total should exist")
+            .parse()?;
+        assert_eq!(total, 10);
+        Ok(())
+    }
+}
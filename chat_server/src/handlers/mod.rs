@@ -1,12 +1,18 @@
 mod auth;
 mod chat;
+mod info;
 mod messages;
+mod metrics;
+mod user;
 mod workspace;
 
 pub(crate) use auth::*;
 use axum::response::IntoResponse;
 pub(crate) use chat::*;
+pub(crate) use info::*;
 pub(crate) use messages::*;
+pub(crate) use metrics::*;
+pub(crate) use user::*;
 pub(crate) use workspace::*;
 
 pub(crate) async fn index_handler() -> impl IntoResponse {
@@ -0,0 +1,201 @@
+use axum::{
+    extract::{Multipart, Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+use chat_core::User;
+use mime_guess::mime;
+use tracing::warn;
+
+use crate::{error::AppError, models::ChatFile, services::UpdateProfile, AppState};
+
+pub(crate) async fn get_me_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let user = state
+        .user_svc
+        .find_chat_user_by_id(user.id as _)
+        .await?
+        .ok_or_else(|| AppError::NotFound("user id not found".to_owned()))?;
+    Ok(Json(user))
+}
+
+/// update the profile of current user, returns the updated profile
+pub(crate) async fn update_me_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<UpdateProfile>,
+) -> Result<impl IntoResponse, AppError> {
+    let user = state
+        .user_svc
+        .update_profile(user.id as _, input.fullname)
+        .await?;
+    Ok(Json(user))
+}
+
+/// upload an image as the avatar of current user, the first file field is used
+pub(crate) async fn upload_avatar_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    mut multipart: Multipart,
+) -> Result<impl IntoResponse, AppError> {
+    let ws_id = user.ws_id as u64;
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|e| AppError::Multipart(e.body_text()))?
+    {
+        let Some(filename) = field.file_name().map(|name| name.to_owned()) else {
+            warn!("skip multipart field without filename");
+            continue;
+        };
+        let data = field
+            .bytes()
+            .await
+            .map_err(|e| AppError::Multipart(e.body_text()))?;
+        let mime = mime_guess::from_path(&filename).first_or_octet_stream();
+        if mime.type_() != mime::IMAGE {
+            return Err(AppError::InvalidInput(
+                "avatar must be an image".to_string(),
+            ));
+        }
+
+        let file = ChatFile::new(ws_id, &filename, &data);
+        state.store.put(&file, data).await?;
+        let user = state
+            .user_svc
+            .update_avatar(user.id as _, &file.url())
+            .await?;
+        return Ok(Json(user));
+    }
+    Err(AppError::InvalidInput("avatar file is missing".to_string()))
+}
+
+/// revoke all tokens issued to current user, including the one used in this request
+pub(crate) async fn revoke_tokens_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    state.user_svc.revoke_tokens(user.id as _).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// delete a user of the workspace, only owners and admins are allowed
+pub(crate) async fn delete_user_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(user_id): Path<u64>,
+) -> Result<impl IntoResponse, AppError> {
+    state.user_svc.delete(user_id, user.id as _).await?;
+    state.member_cache.invalidate_user(user_id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        error::ErrorOutput, get_router, models::ChatUser, services::SigninUser,
+        test_util::get_test_state_and_pg,
+    };
+    use anyhow::Result;
+    use axum::{body::Body, extract::Request};
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn revoked_token_should_be_rejected() -> Result<()> {
+        let (state, _tpg) = get_test_state_and_pg().await?;
+        let user = state
+            .user_svc
+            .verify(&SigninUser::new("jack1@gmail.com", "Hunter48"))
+            .await?
+            .expect("user should exist");
+        let token = state.ek.sign(user)?;
+        let app = get_router(state).await?;
+
+        let me = |token: &str| {
+            Request::builder()
+                .uri("/api/users/me")
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::empty())
+        };
+        let res = app.clone().oneshot(me(&token)?).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let req = Request::builder()
+            .method("POST")
+            .uri("/api/users/revoke-tokens")
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::empty())?;
+        let res = app.clone().oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+
+        let res = app.oneshot(me(&token)?).await?;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let body = res.into_body().collect().await?.to_bytes();
+        let err: ErrorOutput = serde_json::from_slice(&body)?;
+        assert_eq!(err.error, "token revoked");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn update_me_should_change_fullname() -> Result<()> {
+        let (state, _tpg) = get_test_state_and_pg().await?;
+        let token = state.ek.sign(User::new(1, "jack1", "jack1@gmail.com"))?;
+        let app = get_router(state).await?;
+
+        let req = Request::builder()
+            .method("PATCH")
+            .uri("/api/users/me")
+            .header("Authorization", format!("Bearer {}", token))
+            .header("Content-Type", "application/json")
+            .body(Body::from(r#"{"fullname":" Jack Chen "}"#))?;
+        let res = app.clone().oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let req = Request::builder()
+            .uri("/api/users/me")
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::empty())?;
+        let res = app.oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = res.into_body().collect().await?.to_bytes();
+        let user: ChatUser = serde_json::from_slice(&body)?;
+        assert_eq!(user.fullname, "Jack Chen");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn bad_token_should_map_to_status() -> Result<()> {
+        let (state, _tpg) = get_test_state_and_pg().await?;
+        let app = get_router(state).await?;
+
+        let me = |token: &str| {
+            Request::builder()
+                .uri("/api/users/me")
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::empty())
+        };
+        let res = app.clone().oneshot(me("bad-token")?).await?;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let body = res.into_body().collect().await?.to_bytes();
+        let err: ErrorOutput = serde_json::from_slice(&body)?;
+        assert!(err.error.starts_with("invalid token: malformed token"));
+
+        // signed by a key the server doesn't know
+        let ek = chat_core::utils::EncodingKey::load(include_str!(
+            "../../../chat_core/fixtures/encoding_next.pem"
+        ))?;
+        let token = ek.sign(User::new(1, "jack1", "jack1@gmail.com"))?;
+        let res = app.oneshot(me(&token)?).await?;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let body = res.into_body().collect().await?.to_bytes();
+        let err: ErrorOutput = serde_json::from_slice(&body)?;
+        assert!(!err.error.contains("malformed"));
+        Ok(())
+    }
+}
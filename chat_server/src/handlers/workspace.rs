@@ -1,12 +1,165 @@
-use axum::{extract::State, response::IntoResponse, Extension, Json};
+use axum::{
+    extract::{Path, Query, State},
+    response::IntoResponse,
+    Extension, Json,
+};
 use chat_core::User;
 
-use crate::{error::AppError, AppState};
+use crate::{
+    error::AppError,
+    services::{
+        BatchUsers, ListUsersOption, SearchUsers, UpdateRole, WorkspaceInvite, MAX_SEARCH_USERS,
+    },
+    AppState,
+};
 
+/// list users of the caller's workspace, paged by `last_id` and `limit`, filtered by `role`,
+/// only the ones changed after `since` when given
 pub(crate) async fn list_chat_users_handler(
     Extension(user): Extension<User>,
     State(state): State<AppState>,
+    Query(input): Query<ListUsersOption>,
 ) -> Result<impl IntoResponse, AppError> {
-    let users = state.ws_svc.fetch_all_chat_users(user.ws_id as _).await?;
+    let ws_id = user.ws_id as _;
+    let users = match input.since {
+        Some(since) => {
+            state
+                .ws_svc
+                .fetch_chat_users_since(ws_id, since, input.last_id, input.limit(), input.role)
+                .await?
+        }
+        None => {
+            state
+                .ws_svc
+                .fetch_chat_users(ws_id, input.last_id, input.limit(), input.role)
+                .await?
+        }
+    };
     Ok(Json(users))
 }
+
+/// look up users by ids within the caller's workspace
+pub(crate) async fn batch_chat_users_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<BatchUsers>,
+) -> Result<impl IntoResponse, AppError> {
+    let users = state
+        .user_svc
+        .fetch_by_ids_in_ws(user.ws_id as _, &input.ids)
+        .await?;
+    Ok(Json(users))
+}
+
+/// search users of the caller's workspace by fullname or email prefix
+pub(crate) async fn search_chat_users_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Query(input): Query<SearchUsers>,
+) -> Result<impl IntoResponse, AppError> {
+    let users = state
+        .user_svc
+        .search(user.ws_id as _, &input.q, MAX_SEARCH_USERS)
+        .await?;
+    Ok(Json(users))
+}
+
+/// invite code of the caller's workspace, signing up with it joins the workspace
+pub(crate) async fn create_invite_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let code = state.ws_svc.invite_code(user.ws_id as _).await?;
+    Ok(Json(WorkspaceInvite { code }))
+}
+
+/// replace the invite code of the caller's workspace, only the owner is allowed
+pub(crate) async fn rotate_invite_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let code = state
+        .ws_svc
+        .rotate_invite(user.ws_id as _, user.id as _)
+        .await?;
+    Ok(Json(WorkspaceInvite { code }))
+}
+
+/// change the role of a member of the caller's workspace, only the owner is allowed
+pub(crate) async fn update_role_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(user_id): Path<u64>,
+    Json(input): Json<UpdateRole>,
+) -> Result<impl IntoResponse, AppError> {
+    let member = state
+        .ws_svc
+        .set_role(user.ws_id as _, user.id as _, user_id, input.role)
+        .await?;
+    Ok(Json(member))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{get_router, models::WorkspaceRole, test_util::get_test_state_and_pg};
+    use anyhow::Result;
+    use axum::{body::Body, extract::Request, http::StatusCode};
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn list_users_with_unknown_role_should_400() -> Result<()> {
+        let (state, _tpg) = get_test_state_and_pg().await?;
+        let user = User::new(1, "jack1", "jack1@gmail.com");
+        let token = state.ek.sign(User { ws_id: 1, ..user })?;
+        let app = get_router(state).await?;
+        let req = |uri: &str| {
+            Request::builder()
+                .uri(uri)
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::empty())
+        };
+
+        let res = app.clone().oneshot(req("/api/users?role=admin")?).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = app.oneshot(req("/api/users?role=root")?).await?;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn only_owner_should_update_role() -> Result<()> {
+        let (state, _tpg) = get_test_state_and_pg().await?;
+        sqlx::query("UPDATE workspaces SET owner_id = 1 WHERE id = 1")
+            .execute(&state.pool)
+            .await?;
+        let owner = User {
+            ws_id: 1,
+            ..User::new(1, "jack1", "jack1@gmail.com")
+        };
+        let member = User {
+            ws_id: 1,
+            ..User::new(2, "jack2", "jack2@gmail.com")
+        };
+        let input = || {
+            Json(UpdateRole {
+                role: WorkspaceRole::Admin,
+            })
+        };
+
+        let ret = update_role_handler(Extension(member), State(state.clone()), Path(3), input())
+            .await
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::FORBIDDEN);
+
+        let ret = update_role_handler(Extension(owner), State(state.clone()), Path(3), input())
+            .await
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::OK);
+        assert_eq!(
+            state.ws_svc.role_of(1, 3).await?,
+            Some(WorkspaceRole::Admin)
+        );
+        Ok(())
+    }
+}
@@ -1,11 +1,18 @@
-use std::{fmt, ops::Deref, sync::Arc, time::Duration};
+use std::{
+    fmt,
+    ops::Deref,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use anyhow::Context;
 use axum::{
+    extract::DefaultBodyLimit,
     middleware::from_fn_with_state,
-    routing::{get, post},
+    routing::{delete, get, post, put},
     Router,
 };
+use cache::MemberCache;
 use chat_core::{
     middlewares::{set_layer, verify_token_v2, TokenVerify},
     utils::{DecodingKey, EncodingKey},
@@ -14,11 +21,21 @@ use chat_core::{
 use config::{AppConfig, AuthConfig};
 use error::AppError;
 use handlers::{
-    create_chat_handler, delete_chat_handler, file_handler, get_chat_handler, index_handler,
-    list_chat_handler, list_chat_users_handler, list_message_handler, send_message_handler,
-    signin_handler, signup_handler, update_chat_handler, upload_handler,
+    archive_chat_handler, batch_chat_users_handler, chat_membership_handler, count_message_handler,
+    create_chat_handler, create_file_link_handler, create_invite_handler, delete_chat_handler,
+    delete_upload_handler, delete_user_handler, discover_chat_handler, export_message_handler,
+    file_handler, file_head_handler, forward_message_handler, get_chat_handler, get_me_handler,
+    index_handler, info_handler, join_chat_handler, list_chat_handler, list_chat_users_handler,
+    list_message_handler, list_pinned_message_handler, list_recent_chat_handler, metrics_handler,
+    mute_chat_handler, pin_message_handler, react_message_handler, read_all_chats_handler,
+    read_chat_handler, revoke_tokens_handler, rotate_invite_handler, search_chat_users_handler,
+    send_message_batch_handler, send_message_handler, signin_handler, signup_handler,
+    stream_message_handler, unarchive_chat_handler, unread_chats_handler, update_chat_handler,
+    update_me_handler, update_role_handler, upload_avatar_handler, upload_handler,
+    zip_files_handler,
 };
 
+mod cache;
 pub mod config;
 mod error;
 mod handlers;
@@ -26,12 +43,18 @@ mod middlewares;
 mod models;
 mod openapi;
 mod services;
+mod store;
 
-use middlewares::verify_chat_perm;
+use middlewares::{verify_chat_perm, verify_file_access, verify_token_version};
+use models::FileLinkKey;
 use openapi::OpenApiRouter;
-use services::{ChatService, MsgService, UserService, WsService};
-use sqlx::{postgres::PgPoolOptions, PgPool};
+use services::{
+    ChatOptions, ChatService, MetricsService, MsgOptions, MsgService, UserService, WsService,
+};
+use sqlx::PgPool;
+use store::{FileStore, LocalFileStore};
 use tokio::fs;
+use tracing::{info, warn};
 #[derive(Debug, Clone)]
 pub struct AppState {
     pub inner: Arc<AppStateInner>,
@@ -43,10 +66,15 @@ pub struct AppStateInner {
     pub(crate) ek: EncodingKey,
     pub(crate) dk: DecodingKey,
     pub(crate) pool: PgPool,
+    pub(crate) store: Arc<dyn FileStore>,
+    pub(crate) member_cache: MemberCache,
     pub(crate) chat_svc: ChatService,
     pub(crate) user_svc: UserService,
     pub(crate) ws_svc: WsService,
     pub(crate) msg_svc: MsgService,
+    pub(crate) metrics_svc: MetricsService,
+    pub(crate) started_at: Instant,
+    pub(crate) file_link_key: FileLinkKey,
 }
 
 impl TokenVerify for AppState {
@@ -67,26 +95,104 @@ pub async fn get_router(state: AppState) -> Result<Router, AppError> {
                 .post(send_message_handler),
         )
         .route("/:id/message", get(list_message_handler))
+        .route("/:id/message/count", get(count_message_handler))
+        .route("/:id/message/stream", get(stream_message_handler))
+        .route("/:id/messages/batch", post(send_message_batch_handler))
+        .route(
+            "/:id/pins",
+            get(list_pinned_message_handler).post(pin_message_handler),
+        )
+        .route("/:id/reactions", post(react_message_handler))
+        .route("/:id/export", get(export_message_handler))
+        .route("/:id/archive", post(archive_chat_handler))
+        .route("/:id/unarchive", post(unarchive_chat_handler))
+        .route("/:id/mute", put(mute_chat_handler))
+        .route("/:id/read", post(read_chat_handler))
         .layer(from_fn_with_state(state.clone(), verify_chat_perm))
-        .route("/", get(list_chat_handler).post(create_chat_handler));
+        .route("/", get(list_chat_handler).post(create_chat_handler))
+        .route("/recent", get(list_recent_chat_handler))
+        .route("/discover", get(discover_chat_handler))
+        .route("/read-all", post(read_all_chats_handler))
+        .route("/unread", get(unread_chats_handler))
+        .route("/:id/join", post(join_chat_handler))
+        .route("/:id/membership", get(chat_membership_handler))
+        // checks both chats itself, the perm middleware only takes a chat id
+        .route(
+            "/:id/message/:msg_id/forward",
+            post(forward_message_handler),
+        );
     let api = Router::new()
         .route("/users", get(list_chat_users_handler))
+        .route("/users/batch", post(batch_chat_users_handler))
+        .route("/users/search", get(search_chat_users_handler))
+        .route("/users/me", get(get_me_handler).patch(update_me_handler))
+        .route("/users/revoke-tokens", post(revoke_tokens_handler))
+        .route("/users/avatar", post(upload_avatar_handler))
+        .route("/users/:id", delete(delete_user_handler))
+        .route("/info", get(info_handler))
+        .route("/workspace/members/:id/role", put(update_role_handler))
+        .route("/workspace/invite", post(create_invite_handler))
+        .route("/workspace/invite/rotate", post(rotate_invite_handler))
         .nest("/chats", chat_route)
-        .route("/upload", post(upload_handler))
-        .route("/files/:ws_id/*path", get(file_handler))
+        .route(
+            "/upload",
+            post(upload_handler)
+                .layer(DefaultBodyLimit::max(state.config.server.upload_limit))
+                .delete(delete_upload_handler),
+        )
+        .route("/files/link", post(create_file_link_handler))
+        .route("/files/zip", post(zip_files_handler))
+        .layer(from_fn_with_state(state.clone(), verify_token_version))
         .layer(from_fn_with_state(
             state.clone(),
             verify_token_v2::<AppState>,
         ))
+        // takes a token or a signed link
+        .route(
+            "/files/:ws_id/*path",
+            get(file_handler)
+                .head(file_head_handler)
+                .layer(from_fn_with_state(state.clone(), verify_file_access)),
+        )
         .route("/signin", post(signin_handler))
-        .route("/signup", post(signup_handler));
+        .route("/signup", post(signup_handler))
+        .layer(DefaultBodyLimit::max(state.config.server.body_limit));
 
+    // the global limit must let uploads through, other routes are capped by DefaultBodyLimit
+    let body_limit = state
+        .config
+        .server
+        .body_limit
+        .max(state.config.server.upload_limit);
+    // metrics are global, so only signed in users can scrape them
+    let metrics = Router::new()
+        .route("/metrics", get(metrics_handler))
+        .layer(from_fn_with_state(state.clone(), verify_token_version))
+        .layer(from_fn_with_state(
+            state.clone(),
+            verify_token_v2::<AppState>,
+        ));
     let app = Router::new()
         .openapi()
         .route("/", get(index_handler))
+        .merge(metrics)
         .nest("/api", api)
         .with_state(state);
-    Ok(set_layer(app))
+    Ok(set_layer(app, body_limit))
+}
+
+/// periodically remove uploaded files no message references
+fn spawn_file_sweeper(msg_svc: MsgService, interval: Duration, grace: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            match msg_svc.sweep_orphan_files(grace).await {
+                Ok(n) => info!("swept {} orphan files", n),
+                Err(e) => warn!("sweep orphan files failed: {}", e),
+            }
+        }
+    });
 }
 
 impl Deref for AppState {
@@ -99,8 +205,24 @@ impl Deref for AppState {
 
 impl AppState {
     fn load_key(conf: &AuthConfig) -> Result<(EncodingKey, DecodingKey), AppError> {
-        let dk = DecodingKey::load(&conf.pk).context("load pk failed")?;
-        let ek = EncodingKey::load(&conf.sk).context("load sk failed")?;
+        let mut dk = DecodingKey::load(&conf.pk)
+            .context("load pk failed")?
+            .with_scope(&conf.issuer, &conf.audience)
+            .with_leeway(conf.leeway_secs);
+        let mut ek = EncodingKey::load(&conf.sk)
+            .context("load sk failed")?
+            .with_scope(&conf.issuer, &conf.audience);
+        if let Some(kid) = &conf.kid {
+            dk = dk.with_key_id(kid);
+            ek = ek.with_key_id(kid);
+        }
+        for pk in &conf.pks {
+            dk = match &pk.kid {
+                Some(kid) => dk.add_key_with_id(&pk.pk, kid),
+                None => dk.add_key(&pk.pk),
+            }
+            .context("load pks failed")?;
+        }
         Ok((ek, dk))
     }
     pub async fn try_new(config: AppConfig) -> Result<Self, AppError> {
@@ -108,25 +230,54 @@ impl AppState {
             .await
             .context("create base_dir failed")?;
         let (ek, dk) = Self::load_key(&config.auth)?;
-        let pool = PgPoolOptions::new()
-            .acquire_timeout(Duration::from_millis(1000))
-            .connect(&config.server.db_url)
-            .await
-            .context("connect db failed")?;
+        let hasher = config.auth.argon2.hasher()?;
+        let pool = config.server.connect_pool().await?;
         let ws_svc = WsService::new(pool.clone());
-        let user_svc = UserService::new(pool.clone(), ws_svc.clone());
-        let chat_svc = ChatService::new(pool.clone(), user_svc.clone());
-        let msg_svc = MsgService::new(pool.clone(), config.server.base_dir.clone());
+        let metrics_svc = MetricsService::new(pool.clone());
+        let user_svc = UserService::new(pool.clone(), ws_svc.clone(), hasher)
+            .with_allowed_domains(
+                config
+                    .server
+                    .signup_allowed_domains
+                    .as_deref()
+                    .unwrap_or_default(),
+            )
+            .with_default_channel(config.server.default_channel.as_deref())
+            .with_max_chat_members(config.server.max_chat_members);
+        let chat_svc = ChatService::new(
+            pool.clone(),
+            user_svc.clone(),
+            ChatOptions::from(&config.server),
+        );
+        let store: Arc<dyn FileStore> = Arc::new(LocalFileStore::new(&config.server.base_dir));
+        let file_link_key = FileLinkKey::new(config.server.file_link_secret.as_deref());
+        let msg_svc = MsgService::new(
+            pool.clone(),
+            store.clone(),
+            MsgOptions::from(&config.server),
+        );
+        if let Some(secs) = config.server.sweep_interval_secs.filter(|secs| *secs > 0) {
+            spawn_file_sweeper(
+                msg_svc.clone(),
+                Duration::from_secs(secs),
+                Duration::from_secs(config.server.sweep_grace_secs),
+            );
+        }
         Ok(Self {
             inner: Arc::new(AppStateInner {
                 config,
                 ek,
                 dk,
                 pool,
+                store,
+                member_cache: MemberCache::default(),
                 chat_svc,
                 user_svc,
                 ws_svc,
                 msg_svc,
+                metrics_svc,
+                started_at: Instant::now(),
+                file_link_key,
             }),
         })
     }
@@ -142,17 +293,23 @@ impl fmt::Debug for AppStateInner {
 
 #[cfg(feature = "test-util")]
 pub mod test_util {
-    use std::sync::Arc;
+    use std::{sync::Arc, time::Instant};
 
     use anyhow::Result;
     use sqlx::Executor;
     use sqlx::PgPool;
     use sqlx_db_tester::TestPg;
 
+    use crate::cache::MemberCache;
+    use crate::models::FileLinkKey;
+    use crate::services::ChatOptions;
     use crate::services::ChatService;
+    use crate::services::MetricsService;
+    use crate::services::MsgOptions;
     use crate::services::MsgService;
     use crate::services::UserService;
     use crate::services::WsService;
+    use crate::store::{FileStore, LocalFileStore};
     use crate::{config::AppConfig, error::AppError, AppState, AppStateInner};
 
     impl AppState {
@@ -160,13 +317,34 @@ pub mod test_util {
             config: AppConfig,
         ) -> Result<(Self, sqlx_db_tester::TestPg), AppError> {
             let (ek, dk) = Self::load_key(&config.auth)?;
+            let hasher = config.auth.argon2.hasher()?;
             // let server_db_url = config.server.db_url.rsplitn(2, '/').skip(1).next().unwrap();
             let (server_db_url, _) = config.server.db_url.rsplit_once('/').unwrap();
             let (tdb, pool) = get_test_pool(Some(server_db_url)).await;
             let ws_svc = WsService::new(pool.clone());
-            let user_svc = UserService::new(pool.clone(), ws_svc.clone());
-            let chat_svc = ChatService::new(pool.clone(), user_svc.clone());
-            let msg_svc = MsgService::new(pool.clone(), config.server.base_dir.clone());
+            let metrics_svc = MetricsService::new(pool.clone());
+            let user_svc = UserService::new(pool.clone(), ws_svc.clone(), hasher)
+                .with_allowed_domains(
+                    config
+                        .server
+                        .signup_allowed_domains
+                        .as_deref()
+                        .unwrap_or_default(),
+                )
+                .with_default_channel(config.server.default_channel.as_deref())
+                .with_max_chat_members(config.server.max_chat_members);
+            let chat_svc = ChatService::new(
+                pool.clone(),
+                user_svc.clone(),
+                ChatOptions::from(&config.server),
+            );
+            let store: Arc<dyn FileStore> = Arc::new(LocalFileStore::new(&config.server.base_dir));
+            let file_link_key = FileLinkKey::new(config.server.file_link_secret.as_deref());
+            let msg_svc = MsgService::new(
+                pool.clone(),
+                store.clone(),
+                MsgOptions::from(&config.server),
+            );
             Ok((
                 Self {
                     inner: Arc::new(AppStateInner {
@@ -174,10 +352,15 @@ pub mod test_util {
                         ek,
                         dk,
                         pool,
+                        store,
+                        member_cache: MemberCache::default(),
                         chat_svc,
                         user_svc,
                         ws_svc,
                         msg_svc,
+                        metrics_svc,
+                        started_at: Instant::now(),
+                        file_link_key,
                     }),
                 },
                 tdb,
@@ -1,22 +1,54 @@
+use std::time::Duration;
+
 use anyhow::Result;
+use axum_server::Handle;
+use chat_core::utils::{
+    graceful_shutdown, invalid_env_log_level, log_layer, log_level, shutdown_signal,
+};
 use chat_server::{config::AppConfig, get_router, AppState};
-use tokio::net::TcpListener;
-use tracing::{info, level_filters::LevelFilter};
-use tracing_subscriber::{fmt::Layer, layer::SubscriberExt, util::SubscriberInitExt, Layer as _};
+use tracing::{info, warn};
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, Layer as _};
+
+const SHUTDOWN_DRAIN: Duration = Duration::from_secs(10);
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let layer = Layer::new().with_filter(LevelFilter::INFO);
+    let config = AppConfig::try_load()?;
+    let layer = log_layer(config.server.log_format, std::io::stdout)
+        .with_filter(log_level(config.server.log_level.as_deref()));
     tracing_subscriber::registry().with(layer).init();
+    if let Some(level) = invalid_env_log_level() {
+        warn!("Ignore invalid RUST_LOG: {:?}", level);
+    }
 
-    let config = AppConfig::try_load()?;
     let addr = format!("0.0.0.0:{}", config.server.port);
+    let tls = config.server.tls.clone();
 
     let state = AppState::try_new(config).await?;
     let app = get_router(state).await?;
-    let listener = TcpListener::bind(&addr).await?;
-    info!("Listening on: {}", addr);
 
-    axum::serve(listener, app.into_make_service()).await?;
+    let handle = Handle::new();
+    tokio::spawn(graceful_shutdown(
+        handle.clone(),
+        shutdown_signal(),
+        SHUTDOWN_DRAIN,
+    ));
+    match tls {
+        Some(tls) => {
+            let tls = tls.load().await?;
+            info!("Listening on: https://{}", addr);
+            axum_server::bind_rustls(addr.parse()?, tls)
+                .handle(handle)
+                .serve(app.into_make_service())
+                .await?;
+        }
+        None => {
+            info!("Listening on: {}", addr);
+            axum_server::bind(addr.parse()?)
+                .handle(handle)
+                .serve(app.into_make_service())
+                .await?;
+        }
+    }
     Ok(())
 }
@@ -0,0 +1,59 @@
+use axum::{
+    extract::{Path, Query, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use axum_extra::{
+    headers::{authorization::Bearer, Authorization},
+    TypedHeader,
+};
+use chat_core::middlewares::TokenVerify;
+use serde::Deserialize;
+
+use crate::{error::AppError, AppState};
+
+#[derive(Debug, Default, Deserialize)]
+pub struct FileAccessQuery {
+    /// unix timestamp in seconds a signed link expires at
+    pub expires: Option<i64>,
+    /// hex encoded signature of a signed link
+    pub sig: Option<String>,
+    pub token: Option<String>,
+}
+
+/// files can be fetched with a signed link, or like other apis with a token of a user whose
+/// tokens are not revoked. Signed links carry no user
+pub async fn verify_file_access(
+    State(state): State<AppState>,
+    Path((ws_id, path)): Path<(u64, String)>,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+    Query(query): Query<FileAccessQuery>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    if let (Some(expires), Some(sig)) = (query.expires, &query.sig) {
+        let path = format!("/files/{}/{}", ws_id, path);
+        if !state.file_link_key.verify(&path, expires, sig) {
+            return AppError::PermissionDeny.into_response();
+        }
+        return next.run(req).await;
+    }
+
+    let token = match (&bearer, &query.token) {
+        (Some(TypedHeader(bearer)), _) => bearer.token(),
+        (_, Some(token)) => token,
+        _ => return (StatusCode::BAD_REQUEST, "need token").into_response(),
+    };
+    let user = match state.verify_token(token) {
+        Ok(user) => user,
+        Err(e) => return e.into_response(),
+    };
+    match state.user_svc.token_version(user.id as _).await {
+        Err(e) => return e.into_response(),
+        Ok(Some(version)) if user.token_version >= version => {}
+        Ok(_) => return AppError::TokenRevoked.into_response(),
+    }
+    req.extensions_mut().insert(user);
+    next.run(req).await
+}
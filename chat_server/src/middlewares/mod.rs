@@ -1,2 +1,6 @@
+mod file_access;
 mod perm;
+mod token_version;
+pub use file_access::verify_file_access;
 pub use perm::verify_chat_perm;
+pub use token_version::verify_token_version;
@@ -1,5 +1,6 @@
 use axum::{
     extract::{Path, Request, State},
+    http::Method,
     middleware::Next,
     response::{IntoResponse, Response},
     Extension,
@@ -8,6 +9,9 @@ use chat_core::User;
 
 use crate::{error::AppError, AppState};
 
+/// non members get 403 for chats of their workspace, and the same 404 as for a missing chat
+/// for chats of other workspaces, so chat ids can't be probed across workspaces. Workspace
+/// admins may delete chats they are not a member of
 pub async fn verify_chat_perm(
     State(state): State<AppState>,
     Path(chat_id): Path<u64>,
@@ -15,23 +19,46 @@ pub async fn verify_chat_perm(
     req: Request,
     next: Next,
 ) -> Response {
-    match state.chat_svc.is_chat_member(chat_id, user.id as _).await {
-        Err(e) => return e.into_response(),
-        Ok(is_member) if !is_member => return AppError::PermissionDeny.into_response(),
-        _ => {}
+    let user_id = user.id as u64;
+    if !state.member_cache.contains(chat_id, user_id) {
+        match state.chat_svc.is_chat_member(chat_id, user_id).await {
+            Err(e) => return e.into_response(),
+            Ok(false) => {
+                return match state
+                    .chat_svc
+                    .get_by_id(chat_id, Some(user.ws_id as _))
+                    .await
+                {
+                    Ok(Some(_)) if req.method() == Method::DELETE => {
+                        match state.ws_svc.require_admin(user.ws_id as _, user_id).await {
+                            Ok(()) => next.run(req).await,
+                            Err(e) => e.into_response(),
+                        }
+                    }
+                    Ok(Some(_)) => AppError::PermissionDeny.into_response(),
+                    Ok(None) => AppError::NotFound("chat id not found".to_owned()).into_response(),
+                    Err(e) => e.into_response(),
+                }
+            }
+            Ok(true) => state.member_cache.insert(chat_id, user_id),
+        }
     }
     next.run(req).await
 }
 
 #[cfg(test)]
 mod tests {
+    use anyhow::Result;
     use axum::{
-        body::Body, http::StatusCode, middleware::from_fn_with_state, routing::get, Router,
+        body::Body, http::StatusCode, middleware::from_fn_with_state, routing::get, Json, Router,
     };
     use chat_core::middlewares::verify_token_v2;
+    use http_body_util::BodyExt;
     use tower::ServiceExt;
 
-    use crate::test_util::get_test_state_and_pg;
+    use crate::{
+        handlers::update_chat_handler, services::UpdateChat, test_util::get_test_state_and_pg,
+    };
 
     use super::*;
 
@@ -68,6 +95,95 @@ mod tests {
             .body(Body::empty())
             .expect("request builder");
         let res = app.clone().oneshot(req).await.expect("oneshot should work");
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn verify_chat_perm_should_hide_chats_of_other_workspaces() -> Result<()> {
+        let (state, _pg) = get_test_state_and_pg().await?;
+        let (other_ws_chat,): (i64,) = sqlx::query_as(
+            "INSERT INTO chats(ws_id, name, type, members) VALUES (2, 'other', 'private_channel', '{}') RETURNING id",
+        )
+        .fetch_one(&state.pool)
+        .await?;
+        let user = User {
+            ws_id: 1,
+            ..User::new(4, "jack4", "jack4@gmail.com")
+        };
+        let token = state.ek.sign(user)?;
+
+        let app = Router::new()
+            .route("/:id", get(handler))
+            .layer(from_fn_with_state(state.clone(), verify_chat_perm))
+            .layer(from_fn_with_state(
+                state.clone(),
+                verify_token_v2::<AppState>,
+            ))
+            .with_state(state);
+        let get_chat = |id: i64| {
+            Request::builder()
+                .uri(format!("/{}", id))
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::empty())
+        };
+
+        let missing = app.clone().oneshot(get_chat(99999)?).await?;
+        let other = app.clone().oneshot(get_chat(other_ws_chat)?).await?;
+        assert_eq!(missing.status(), StatusCode::NOT_FOUND);
+        assert_eq!(other.status(), StatusCode::NOT_FOUND);
+        let missing = missing.into_body().collect().await?.to_bytes();
+        let other = other.into_body().collect().await?.to_bytes();
+        assert_eq!(missing, other);
+
+        // not a member of the private channel of its own workspace
+        let res = app.oneshot(get_chat(2)?).await?;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn verify_chat_perm_should_cache_membership() {
+        let (state, _pg) = get_test_state_and_pg().await.unwrap();
+        let user = User::new(1, "jack", "jack@gmail.com");
+        let user = User { ws_id: 1, ..user };
+        let token = state.ek.sign(user.clone()).expect("sign should work");
+
+        let app = Router::new()
+            .route("/:id", get(handler))
+            .layer(from_fn_with_state(state.clone(), verify_chat_perm))
+            .layer(from_fn_with_state(
+                state.clone(),
+                verify_token_v2::<AppState>,
+            ))
+            .with_state(state.clone());
+        let req = || {
+            Request::builder()
+                .uri("/4")
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::empty())
+                .expect("request builder")
+        };
+
+        for _ in 0..2 {
+            let res = app
+                .clone()
+                .oneshot(req())
+                .await
+                .expect("oneshot should work");
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+        assert_eq!(state.member_cache.misses(), 1);
+
+        // removing the user from the chat drops the cached membership
+        let input = UpdateChat {
+            members: Some(vec![3, 4]),
+            ..Default::default()
+        };
+        update_chat_handler(State(state.clone()), Extension(user), Path(4), Json(input))
+            .await
+            .expect("update chat should work");
+        let res = app.oneshot(req()).await.expect("oneshot should work");
         assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        assert_eq!(state.member_cache.misses(), 2);
     }
 }
@@ -0,0 +1,23 @@
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Extension,
+};
+use chat_core::User;
+
+use crate::{error::AppError, AppState};
+
+/// reject tokens revoked by bumping the user's token version, must run after token verification
+pub async fn verify_token_version(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+    req: Request,
+    next: Next,
+) -> Response {
+    match state.user_svc.token_version(user.id as _).await {
+        Err(e) => e.into_response(),
+        Ok(Some(version)) if user.token_version >= version => next.run(req).await,
+        Ok(_) => AppError::TokenRevoked.into_response(),
+    }
+}
@@ -0,0 +1,232 @@
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+
+use crate::error::AppError;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatFile {
+    pub ws_id: u64,
+    pub ext: String,
+    pub hash: String,
+}
+
+impl ChatFile {
+    pub fn new(ws_id: u64, filename: &str, data: &[u8]) -> Self {
+        let hash = Sha1::digest(data);
+        let ext = extension_of(filename);
+        Self {
+            ws_id,
+            ext: ext.to_string(),
+            hash: hex::encode(hash),
+        }
+    }
+
+    pub fn url(&self) -> String {
+        format!("/files/{}", self.hash_to_path())
+    }
+
+    /// url prefixed with the public base url when configured, e.g. https://chat.example.com
+    pub fn public_url(&self, base_url: Option<&str>) -> String {
+        match base_url {
+            Some(base_url) => format!("{}{}", base_url.trim_end_matches('/'), self.url()),
+            None => self.url(),
+        }
+    }
+
+    /// mime type guessed from the extension
+    pub fn mime(&self) -> String {
+        mime_guess::from_ext(&self.ext)
+            .first_or_octet_stream()
+            .to_string()
+    }
+
+    pub fn path(&self, base_dir: impl AsRef<Path>) -> PathBuf {
+        base_dir.as_ref().join(self.hash_to_path())
+    }
+
+    pub fn hash_to_path(&self) -> String {
+        let (first, remain) = self.hash.split_at(3);
+        let (second, third) = remain.split_at(3);
+        let ext = &self.ext;
+        let ws_id = self.ws_id;
+        if ext.is_empty() {
+            format!("{ws_id}/{first}/{second}/{third}")
+        } else {
+            format!("{ws_id}/{first}/{second}/{third}.{ext}")
+        }
+    }
+}
+
+/// Extension of the filename, empty if it has none.
+///
+/// Like `Path::extension`, dotfiles (".env") and names without a dot ("README") have no
+/// extension, and only the last part of a multi-dot name ("a.tar.gz" -> "gz") is kept.
+/// Anything that is not plain ascii alphanumeric is dropped so it can't break the path.
+fn extension_of(filename: &str) -> &str {
+    match filename.rsplit_once('.') {
+        Some((name, ext))
+            if !name.is_empty()
+                && !ext.is_empty()
+                && ext.chars().all(|c| c.is_ascii_alphanumeric()) =>
+        {
+            ext
+        }
+        _ => "",
+    }
+}
+
+impl FromStr for ChatFile {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // absolute urls are accepted as well, only the path from /files/ on matters
+        let s = match s.split_once("://") {
+            Some((_, rest)) => rest
+                .find("/files/")
+                .map(|i| &rest[i..])
+                .ok_or(AppError::InvalidInput("file path".to_string()))?,
+            None => s,
+        };
+        let remain = s
+            .strip_prefix("/files/")
+            .ok_or(AppError::InvalidInput("file path".to_string()))?;
+        let [ws_id, part1, part2, filename] = remain
+            .split('/')
+            .collect::<Vec<_>>()
+            .try_into()
+            .map_err(|_| AppError::InvalidInput("file path".to_string()))?;
+        let ws_id: u64 = ws_id
+            .parse()
+            .map_err(|_| AppError::InvalidInput("file path".to_string()))?;
+        let (part3, ext) = match filename.split_once('.') {
+            Some((part3, ext)) if !ext.is_empty() && extension_of(filename) == ext => (part3, ext),
+            Some(_) => return Err(AppError::InvalidInput("file path".to_string())),
+            None => (filename, ""),
+        };
+
+        let hash = format!("{part1}{part2}{part3}");
+        // the hash is used to build a path on disk, so it must not contain anything else
+        if part1.len() != 3 || part2.len() != 3 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(AppError::InvalidInput("file path".to_string()));
+        }
+        Ok(Self {
+            ws_id,
+            ext: ext.to_owned(),
+            hash,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::panic;
+
+    use super::*;
+
+    #[test]
+    fn chat_file_new_should_work() {
+        let file = ChatFile::new(1, "test.txt", b"hello world");
+        assert_eq!(file.ws_id, 1);
+        assert_eq!(file.ext, "txt");
+        assert_eq!(file.hash, "2aae6c35c94fcfb415dbe95f408b9ce91ee846ed");
+        assert_eq!(
+            file.hash_to_path(),
+            "1/2aa/e6c/35c94fcfb415dbe95f408b9ce91ee846ed.txt"
+        );
+        assert_eq!(
+            file.url(),
+            "/files/1/2aa/e6c/35c94fcfb415dbe95f408b9ce91ee846ed.txt"
+        );
+        assert_eq!(
+            file.path("/files"),
+            Path::new("/files/1/2aa/e6c/35c94fcfb415dbe95f408b9ce91ee846ed.txt")
+        );
+        assert_eq!(file.mime(), "text/plain");
+        assert_eq!(
+            ChatFile::new(1, "README", b"").mime(),
+            "application/octet-stream"
+        );
+    }
+
+    #[test]
+    fn parse_valid_url_should_work() {
+        let file =
+            ChatFile::from_str("/files/1/2aa/e6c/35c94fcfb415dbe95f408b9ce91ee846ed.txt").unwrap();
+        assert_eq!(file.ws_id, 1);
+        assert_eq!(file.ext, "txt");
+        assert_eq!(file.hash, "2aae6c35c94fcfb415dbe95f408b9ce91ee846ed");
+    }
+
+    #[test]
+    fn url_from_str_round_trip_should_work() {
+        let file = ChatFile::new(42, "hello.png", b"hello world");
+        let url = file.url();
+        let parsed = ChatFile::from_str(&url).unwrap();
+        assert_eq!(parsed.ws_id, file.ws_id);
+        assert_eq!(parsed.ext, file.ext);
+        assert_eq!(parsed.hash, file.hash);
+        assert_eq!(parsed.url(), url);
+        assert_eq!(parsed.path("/tmp"), file.path("/tmp"));
+    }
+
+    #[test]
+    fn chat_file_without_extension_should_round_trip() {
+        let file = ChatFile::new(1, "README", b"hello world");
+        assert_eq!(file.ext, "");
+        assert_eq!(
+            file.url(),
+            "/files/1/2aa/e6c/35c94fcfb415dbe95f408b9ce91ee846ed"
+        );
+        let parsed = ChatFile::from_str(&file.url()).unwrap();
+        assert_eq!(parsed.ext, "");
+        assert_eq!(parsed.url(), file.url());
+    }
+
+    #[test]
+    fn chat_file_dotfile_should_have_no_extension() {
+        let file = ChatFile::new(1, ".env", b"hello world");
+        assert_eq!(file.ext, "");
+        let parsed = ChatFile::from_str(&file.url()).unwrap();
+        assert_eq!(parsed.url(), file.url());
+    }
+
+    #[test]
+    fn chat_file_multi_dot_should_keep_last_extension() {
+        let file = ChatFile::new(1, "a.tar.gz", b"hello world");
+        assert_eq!(file.ext, "gz");
+        let parsed = ChatFile::from_str(&file.url()).unwrap();
+        assert_eq!(parsed.ext, "gz");
+        assert_eq!(parsed.url(), file.url());
+    }
+
+    #[test]
+    fn public_url_should_be_absolute_and_round_trip() {
+        let file = ChatFile::new(1, "test.txt", b"hello world");
+        assert_eq!(file.public_url(None), file.url());
+
+        let url = file.public_url(Some("https://chat.example.com/"));
+        assert_eq!(
+            url,
+            "https://chat.example.com/files/1/2aa/e6c/35c94fcfb415dbe95f408b9ce91ee846ed.txt"
+        );
+        let parsed = ChatFile::from_str(&url).unwrap();
+        assert_eq!(parsed.url(), file.url());
+
+        let url = file.public_url(Some("https://chat.example.com/api"));
+        let parsed = ChatFile::from_str(&url).unwrap();
+        assert_eq!(parsed.url(), file.url());
+    }
+
+    #[test]
+    fn parse_invalid_url_should_work() {
+        match ChatFile::from_str("/files/1/2aa/e6c/aa/35c94fcfb415dbe95f408b9ce91ee846ed.txt") {
+            Err(AppError::InvalidInput(msg)) => assert_eq!(msg, "file path"),
+            _ => panic!("invalid url should return error"),
+        };
+    }
+}
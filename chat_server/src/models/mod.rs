@@ -1,7 +1,13 @@
-mod chat;
+mod file;
+mod file_link;
+mod notification;
+mod read;
 mod user;
 mod workspace;
 
-pub use chat::*;
+pub use file::*;
+pub use file_link::*;
+pub use notification::*;
+pub use read::*;
 pub use user::*;
 pub use workspace::*;
@@ -0,0 +1,9 @@
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct NotificationPref {
+    pub user_id: i64,
+    pub chat_id: i64,
+    pub muted: bool,
+}
@@ -0,0 +1,17 @@
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+
+/// messages of the chat up to `last_read_id` are read by the user
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct ChatRead {
+    pub user_id: i64,
+    pub chat_id: i64,
+    pub last_read_id: i64,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct UnreadCount {
+    pub chat_id: i64,
+    /// messages of others newer than the last read one
+    pub unread: i64,
+}
@@ -6,4 +6,5 @@ pub struct ChatUser {
     pub id: i64,
     pub fullname: String,
     pub email: String,
+    pub avatar_url: Option<String>,
 }
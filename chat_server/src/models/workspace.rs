@@ -12,6 +12,23 @@ pub struct Workspace {
     pub created_at: DateTime<Utc>,
 }
 
+/// role of a user in its workspace
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, sqlx::Type)]
+#[sqlx(type_name = "workspace_role", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum WorkspaceRole {
+    Owner,
+    Admin,
+    Member,
+}
+
+impl WorkspaceRole {
+    /// owners and admins may run destructive operations like deleting chats or users
+    pub fn is_admin(&self) -> bool {
+        matches!(self, Self::Owner | Self::Admin)
+    }
+}
+
 impl Workspace {
     pub async fn update_owner(&self, owner_id: u64, pool: &PgPool) -> Result<Workspace, AppError> {
         // update owner_id in two cases 1) owner_id = 0 2) owner's ws_id = id
@@ -27,6 +44,10 @@ impl Workspace {
         .bind(self.id)
         .fetch_one(pool)
         .await?;
+        sqlx::query("UPDATE users SET role = 'owner' WHERE id = $1")
+            .bind(owner_id as i64)
+            .execute(pool)
+            .await?;
 
         Ok(ws)
     }
@@ -23,7 +23,7 @@ pub(crate) trait OpenApiRouter {
 #[derive(OpenApi)]
 #[openapi(
     paths(signup_handler, signin_handler, create_chat_handler),
-    components(schemas(CreateUser, AuthOutput, ErrorOutput, SigninUser, Chat, CreateChat, ChatType)),
+    components(schemas(CreateUser, AuthOutput, ErrorOutput, SigninUser, Chat, CreateChat, ChatType, ChatValidation)),
     modifiers(&SecurityAddon),
     tags(
         (name = "chat", description = "Chat related operations")
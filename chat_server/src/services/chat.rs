@@ -1,14 +1,22 @@
-use std::sync::Arc;
+use std::{collections::HashSet, sync::Arc};
 
-use crate::AppError;
+use crate::{
+    config::ServerConfig,
+    models::{ChatRead, NotificationPref, UnreadCount, WorkspaceRole},
+    AppError,
+};
 
 use chat_core::{Chat, ChatType};
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use sqlx::PgPool;
+use sqlx::{FromRow, PgPool};
 use utoipa::ToSchema;
 
 use super::UserService;
 
+/// chats with more members must be named
+const MAX_UNNAMED_MEMBERS: usize = 8;
+
 #[derive(Debug, Clone, ToSchema, Default, Serialize, Deserialize)]
 pub struct CreateChat {
     /// chat name
@@ -19,14 +27,167 @@ pub struct CreateChat {
     pub public: bool,
 }
 
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CreateChatOption {
+    /// only validate the input, nothing is created
+    #[serde(default)]
+    pub validate: bool,
+}
+
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize)]
+pub struct ChatValidation {
+    /// type the chat would be created with
+    pub r#type: ChatType,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ListChatOption {
+    /// include archived chats
+    #[serde(default)]
+    pub include_archived: bool,
+    /// attach a preview of the latest message to each chat
+    #[serde(default)]
+    pub with_preview: bool,
+    /// only chats the user is a member of
+    #[serde(default)]
+    pub mine: bool,
+}
+
+const PREVIEW_LEN: i32 = 100;
+
+/// a chat in the listing with its latest message, none if it has no messages yet
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ChatWithPreview {
+    #[serde(flatten)]
+    pub chat: Chat,
+    pub preview: Option<MessagePreview>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct MessagePreview {
+    pub sender_id: i64,
+    /// first 100 chars of the content
+    pub content: String,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(FromRow)]
+struct ChatPreviewRow {
+    #[sqlx(flatten)]
+    chat: Chat,
+    preview_sender_id: Option<i64>,
+    preview_content: Option<String>,
+    preview_created_at: Option<DateTime<Utc>>,
+}
+
+impl From<ChatPreviewRow> for ChatWithPreview {
+    fn from(row: ChatPreviewRow) -> Self {
+        let preview = match (
+            row.preview_sender_id,
+            row.preview_content,
+            row.preview_created_at,
+        ) {
+            (Some(sender_id), Some(content), Some(created_at)) => Some(MessagePreview {
+                sender_id,
+                content,
+                created_at,
+            }),
+            _ => None,
+        };
+        Self {
+            chat: row.chat,
+            preview,
+        }
+    }
+}
+
+const DEFAULT_RECENT_CHATS_LIMIT: u64 = 20;
+const MAX_RECENT_CHATS_LIMIT: u64 = 100;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ListRecentChatOption {
+    /// 20 by default and at most 100
+    pub limit: Option<u64>,
+}
+
+impl ListRecentChatOption {
+    pub fn limit(&self) -> u64 {
+        self.limit
+            .unwrap_or(DEFAULT_RECENT_CHATS_LIMIT)
+            .clamp(1, MAX_RECENT_CHATS_LIMIT)
+    }
+}
+
+/// a chat with the time of its latest message, none if it has no messages yet
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct RecentChat {
+    #[sqlx(flatten)]
+    #[serde(flatten)]
+    pub chat: Chat,
+    pub last_message_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MarkRead {
+    /// read up to this message, the latest message of the chat when absent
+    #[serde(default)]
+    pub message_id: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChatMembership {
+    pub is_member: bool,
+    /// workspace role of the caller, it decides what the caller may do with the chat
+    pub role: Option<WorkspaceRole>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MuteChat {
+    pub muted: bool,
+}
+
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct UpdateChat {
+    /// kept as is when absent
     pub name: Option<String>,
+    /// switch a channel between public and private, only channels can be switched
+    #[serde(default)]
+    pub public: Option<bool>,
+    /// the full member set wanted, members missing from it are removed and new ones added
+    #[serde(default)]
+    pub members: Option<Vec<i64>>,
+}
+
+/// limits applied when creating chats
+#[derive(Debug, Clone)]
+pub struct ChatOptions {
+    pub max_chats_per_workspace: usize,
+    /// applies to members added later as well
+    pub max_chat_members: usize,
 }
 
 pub struct ChatService {
     pool: PgPool,
     user_svc: Arc<UserService>,
+    opts: ChatOptions,
+}
+
+impl Default for ChatOptions {
+    fn default() -> Self {
+        Self {
+            max_chats_per_workspace: 1000,
+            max_chat_members: 500,
+        }
+    }
+}
+
+impl From<&ServerConfig> for ChatOptions {
+    fn from(config: &ServerConfig) -> Self {
+        Self {
+            max_chats_per_workspace: config.max_chats_per_workspace,
+            max_chat_members: config.max_chat_members,
+        }
+    }
 }
 
 impl Clone for ChatService {
@@ -34,33 +195,93 @@ impl Clone for ChatService {
         Self {
             pool: self.pool.clone(),
             user_svc: self.user_svc.clone(),
+            opts: self.opts.clone(),
         }
     }
 }
 
 impl ChatService {
-    pub fn new(pool: PgPool, user_svc: UserService) -> Self {
+    pub fn new(pool: PgPool, user_svc: UserService, opts: ChatOptions) -> Self {
         Self {
             pool,
             user_svc: Arc::new(user_svc),
+            opts,
         }
     }
 
     pub async fn create(&self, input: CreateChat, ws_id: u64) -> Result<Chat, AppError> {
+        let chat_type = match self.check_create(&input, ws_id).await? {
+            (_, Some(chat)) => return Ok(chat),
+            (chat_type, None) => chat_type,
+        };
+
+        let chat = sqlx::query_as(
+            r#"
+            INSERT INTO chats (ws_id, name, type, members)
+            VALUES ($1, $2, $3, $4)
+            RETURNING id, ws_id, name, type, members, created_at, archived_at
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(input.name)
+        .bind(chat_type)
+        .bind(input.members)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(chat)
+    }
+
+    /// run the validation of `create` without creating anything, returns the type the chat
+    /// would be created with
+    pub async fn validate_create(
+        &self,
+        input: &CreateChat,
+        ws_id: u64,
+    ) -> Result<ChatType, AppError> {
+        let (chat_type, _) = self.check_create(input, ws_id).await?;
+        Ok(chat_type)
+    }
+
+    /// the type of the chat to create, and the existing single chat between the members
+    /// which is reused instead
+    async fn check_create(
+        &self,
+        input: &CreateChat,
+        ws_id: u64,
+    ) -> Result<(ChatType, Option<Chat>), AppError> {
         let len = match input.members.len() {
             len if len < 2 => {
                 return Err(AppError::CreateChatError(
                     "Chat must have at least 2 members".to_string(),
                 ))
             }
-            len if len > 8 && input.name.is_none() => {
-                return Err(AppError::CreateChatError(
-                    "Group chat with more than 8 members must have a name".to_string(),
-                ))
+            len if len > self.opts.max_chat_members => {
+                return Err(AppError::CreateChatError("too many members".to_string()))
+            }
+            len if len > MAX_UNNAMED_MEMBERS && input.name.is_none() => {
+                return Err(AppError::ChatNameRequired {
+                    members: len,
+                    max: MAX_UNNAMED_MEMBERS,
+                    r#type: ChatType::Group,
+                })
             }
             len => len,
         };
 
+        // checked before existence, as duplicates would look like missing members
+        let unique: HashSet<_> = input.members.iter().collect();
+        if unique.len() == 1 {
+            return Err(AppError::CreateChatError(
+                "Can't create a chat with yourself".to_string(),
+            ));
+        }
+        if unique.len() != len {
+            return Err(AppError::CreateChatError(
+                "Chat members must be distinct".to_string(),
+            ));
+        }
+
         let users = self.user_svc.fetch_by_ids(&input.members).await?;
         if users.len() != len {
             return Err(AppError::CreateChatError(
@@ -80,18 +301,40 @@ impl ChatService {
             }
         };
 
+        // a single chat between the same two users is unique in the workspace
+        if chat_type == ChatType::Single {
+            if let Some(chat) = self.find_single(ws_id, &input.members).await? {
+                return Ok((chat_type, Some(chat)));
+            }
+        }
+
+        let (count,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM chats WHERE ws_id = $1")
+            .bind(ws_id as i64)
+            .fetch_one(&self.pool)
+            .await?;
+        if count as usize >= self.opts.max_chats_per_workspace {
+            return Err(AppError::CreateChatError(
+                "workspace chat limit reached".to_string(),
+            ));
+        }
+
+        Ok((chat_type, None))
+    }
+
+    async fn find_single(&self, ws_id: u64, members: &[i64]) -> Result<Option<Chat>, AppError> {
         let chat = sqlx::query_as(
             r#"
-            INSERT INTO chats (ws_id, name, type, members)
-            VALUES ($1, $2, $3, $4)
-            RETURNING id, ws_id, name, type, members, created_at
+            SELECT id, ws_id, name, type, members, created_at, archived_at
+            FROM chats
+            WHERE ws_id = $1 AND type = 'single'
+            AND members @> $2 AND members <@ $2
+            ORDER BY id
+            LIMIT 1
             "#,
         )
         .bind(ws_id as i64)
-        .bind(input.name)
-        .bind(chat_type)
-        .bind(input.members)
-        .fetch_one(&self.pool)
+        .bind(members)
+        .fetch_optional(&self.pool)
         .await?;
 
         Ok(chat)
@@ -103,19 +346,43 @@ impl ChatService {
         ws_id: u64,
         chat_id: u64,
     ) -> Result<Chat, AppError> {
-        if let Some(chat) = self.get_by_id(chat_id).await? {
+        if let Some(chat) = self.get_by_id(chat_id, None).await? {
             if chat.ws_id as u64 != ws_id {
                 return Err(AppError::PermissionDeny);
             }
+            let chat_type = match (input.public, chat.r#type) {
+                (None, chat_type) => chat_type,
+                (Some(public), ChatType::PublicChannel | ChatType::PrivateChannel) => {
+                    if public {
+                        ChatType::PublicChannel
+                    } else {
+                        ChatType::PrivateChannel
+                    }
+                }
+                (Some(_), _) => {
+                    return Err(AppError::InvalidInput(
+                        "only channels can be public or private".to_string(),
+                    ))
+                }
+            };
+            let members = match &input.members {
+                Some(members) => Some(
+                    self.diff_members(&chat, input.name.is_some(), members)
+                        .await?,
+                ),
+                None => None,
+            };
             let chat = sqlx::query_as(
                 r#"
                 update chats
-                SET name = $1
-                WHERE id = $2
-                RETURNING id, ws_id, name, type, members, created_at
+                SET name = COALESCE($1, name), type = $2, members = COALESCE($3, members)
+                WHERE id = $4
+                RETURNING id, ws_id, name, type, members, created_at, archived_at
                 "#,
             )
             .bind(input.name)
+            .bind(chat_type)
+            .bind(members)
             .bind(chat_id as i64)
             .fetch_one(&self.pool)
             .await?;
@@ -124,8 +391,72 @@ impl ChatService {
             Err(AppError::NotFound("chat id not found".to_owned()))
         }
     }
+
+    /// the members of the chat after applying the wanted set, remaining members keep their
+    /// order and added ones are appended. Added members must be in the chat's workspace
+    async fn diff_members(
+        &self,
+        chat: &Chat,
+        named: bool,
+        members: &[i64],
+    ) -> Result<Vec<i64>, AppError> {
+        if chat.r#type == ChatType::Single {
+            return Err(AppError::InvalidInput(
+                "members of a single chat can't be changed".to_string(),
+            ));
+        }
+        let wanted: HashSet<_> = members.iter().copied().collect();
+        if wanted.len() != members.len() {
+            return Err(AppError::InvalidInput(
+                "Chat members must be distinct".to_string(),
+            ));
+        }
+        match wanted.len() {
+            len if len < 2 => {
+                return Err(AppError::InvalidInput(
+                    "Chat must have at least 2 members".to_string(),
+                ))
+            }
+            len if len > self.opts.max_chat_members => {
+                return Err(AppError::CreateChatError("too many members".to_string()))
+            }
+            len if len > MAX_UNNAMED_MEMBERS && chat.name.is_none() && !named => {
+                return Err(AppError::ChatNameRequired {
+                    members: len,
+                    max: MAX_UNNAMED_MEMBERS,
+                    r#type: chat.r#type,
+                })
+            }
+            _ => {}
+        }
+
+        let current: HashSet<_> = chat.members.iter().copied().collect();
+        let added: Vec<_> = members
+            .iter()
+            .copied()
+            .filter(|id| !current.contains(id))
+            .collect();
+        if !added.is_empty() {
+            let found = self.user_svc.count_in_ws(chat.ws_id as _, &added).await?;
+            if found != added.len() {
+                return Err(AppError::InvalidInput(
+                    "Some members do not exist".to_string(),
+                ));
+            }
+        }
+
+        let mut members: Vec<_> = chat
+            .members
+            .iter()
+            .copied()
+            .filter(|id| wanted.contains(id))
+            .collect();
+        members.extend(added);
+        Ok(members)
+    }
+
     pub async fn delete(&self, ws_id: u64, chat_id: u64) -> Result<Chat, AppError> {
-        if let Some(chat) = self.get_by_id(chat_id).await? {
+        if let Some(chat) = self.get_by_id(chat_id, None).await? {
             if chat.ws_id as u64 != ws_id {
                 return Err(AppError::PermissionDeny);
             }
@@ -133,7 +464,7 @@ impl ChatService {
                 r#"
                 DELETE FROM chats
                 WHERE id = $1
-                RETURNING id, ws_id, name, type, members, created_at
+                RETURNING id, ws_id, name, type, members, created_at, archived_at
                 "#,
             )
             .bind(chat_id as i64)
@@ -144,36 +475,330 @@ impl ChatService {
             Err(AppError::NotFound("chat id not found".to_owned()))
         }
     }
-    pub async fn get_by_id(&self, id: u64) -> Result<Option<Chat>, AppError> {
+    /// chats outside `ws_id` are treated as missing when it's given
+    pub async fn get_by_id(&self, id: u64, ws_id: Option<u64>) -> Result<Option<Chat>, AppError> {
         let chat = sqlx::query_as(
             r#"
-            SELECT id, ws_id, name, type, members, created_at
+            SELECT id, ws_id, name, type, members, created_at, archived_at
             FROM chats
-            WHERE id = $1
+            WHERE id = $1 AND ($2::bigint IS NULL OR ws_id = $2)
             "#,
         )
         .bind(id as i64)
+        .bind(ws_id.map(|id| id as i64))
         .fetch_optional(&self.pool)
         .await?;
 
         Ok(chat)
     }
 
-    pub async fn fetch_all(&self, ws_id: u64) -> Result<Vec<Chat>, AppError> {
+    /// archive the chat, it's hidden from the default list and can't receive new messages
+    pub async fn archive(&self, ws_id: u64, chat_id: u64) -> Result<Chat, AppError> {
+        self.set_archived(ws_id, chat_id, true).await
+    }
+
+    pub async fn unarchive(&self, ws_id: u64, chat_id: u64) -> Result<Chat, AppError> {
+        self.set_archived(ws_id, chat_id, false).await
+    }
+
+    async fn set_archived(
+        &self,
+        ws_id: u64,
+        chat_id: u64,
+        archived: bool,
+    ) -> Result<Chat, AppError> {
+        if let Some(chat) = self.get_by_id(chat_id, None).await? {
+            if chat.ws_id as u64 != ws_id {
+                return Err(AppError::PermissionDeny);
+            }
+            let chat = sqlx::query_as(
+                r#"
+                UPDATE chats
+                SET archived_at = CASE WHEN $1 THEN COALESCE(archived_at, now()) ELSE NULL END
+                WHERE id = $2
+                RETURNING id, ws_id, name, type, members, created_at, archived_at
+                "#,
+            )
+            .bind(archived)
+            .bind(chat_id as i64)
+            .fetch_one(&self.pool)
+            .await?;
+            Ok(chat)
+        } else {
+            Err(AppError::NotFound("chat id not found".to_owned()))
+        }
+    }
+
+    /// chats of the workspace ordered by id
+    pub async fn fetch_all(
+        &self,
+        ws_id: u64,
+        include_archived: bool,
+    ) -> Result<Vec<Chat>, AppError> {
         let chats = sqlx::query_as(
             r#"
-            SELECT id, ws_id, name, type, members, created_at
+            SELECT id, ws_id, name, type, members, created_at, archived_at
             FROM chats
             WHERE ws_id = $1
+            AND ($2 OR archived_at IS NULL)
+            ORDER BY id
             "#,
         )
         .bind(ws_id as i64)
+        .bind(include_archived)
         .fetch_all(&self.pool)
         .await?;
 
         Ok(chats)
     }
 
+    /// chats of the workspace the user is a member of, ordered by id
+    pub async fn fetch_for_user(
+        &self,
+        ws_id: u64,
+        user_id: u64,
+        include_archived: bool,
+    ) -> Result<Vec<Chat>, AppError> {
+        let chats = sqlx::query_as(
+            r#"
+            SELECT id, ws_id, name, type, members, created_at, archived_at
+            FROM chats
+            WHERE ws_id = $1 AND $2 = ANY(members)
+            AND ($3 OR archived_at IS NULL)
+            ORDER BY id
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(user_id as i64)
+        .bind(include_archived)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(chats)
+    }
+
+    /// public channels of the workspace the user hasn't joined yet, ordered by id
+    pub async fn fetch_discoverable(
+        &self,
+        ws_id: u64,
+        user_id: u64,
+    ) -> Result<Vec<Chat>, AppError> {
+        let chats = sqlx::query_as(
+            r#"
+            SELECT id, ws_id, name, type, members, created_at, archived_at
+            FROM chats
+            WHERE ws_id = $1
+            AND type = 'public_channel'
+            AND NOT ($2 = ANY(members))
+            AND archived_at IS NULL
+            ORDER BY id
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(user_id as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(chats)
+    }
+
+    /// like `fetch_all`, with the latest message of each chat
+    pub async fn fetch_all_with_preview(
+        &self,
+        ws_id: u64,
+        include_archived: bool,
+    ) -> Result<Vec<ChatWithPreview>, AppError> {
+        let rows: Vec<ChatPreviewRow> = sqlx::query_as(
+            r#"
+            SELECT c.id, c.ws_id, c.name, c.type, c.members, c.created_at, c.archived_at,
+                m.sender_id AS preview_sender_id,
+                left(m.content, $3) AS preview_content,
+                m.created_at AS preview_created_at
+            FROM chats c
+            LEFT JOIN LATERAL (
+                SELECT sender_id, content, created_at
+                FROM messages
+                WHERE chat_id = c.id
+                ORDER BY id DESC
+                LIMIT 1
+            ) m ON true
+            WHERE c.ws_id = $1
+            AND ($2 OR c.archived_at IS NULL)
+            ORDER BY c.id
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(include_archived)
+        .bind(PREVIEW_LEN)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(Into::into).collect())
+    }
+
+    /// unarchived chats of the workspace, most recently active first, chats without
+    /// messages are ordered by their own creation time
+    pub async fn fetch_recent(&self, ws_id: u64, limit: u64) -> Result<Vec<RecentChat>, AppError> {
+        let chats = sqlx::query_as(
+            r#"
+            SELECT c.id, c.ws_id, c.name, c.type, c.members, c.created_at, c.archived_at,
+                m.last_message_at
+            FROM chats c
+            LEFT JOIN (
+                SELECT chat_id, max(created_at) AS last_message_at
+                FROM messages
+                GROUP BY chat_id
+            ) m ON m.chat_id = c.id
+            WHERE c.ws_id = $1 AND c.archived_at IS NULL
+            ORDER BY COALESCE(m.last_message_at, c.created_at) DESC, c.id DESC
+            LIMIT $2
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(limit as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(chats)
+    }
+
+    /// mute or unmute new message notifications of the chat for the user
+    pub async fn set_muted(
+        &self,
+        chat_id: u64,
+        user_id: u64,
+        muted: bool,
+    ) -> Result<NotificationPref, AppError> {
+        let pref = sqlx::query_as(
+            r#"
+            INSERT INTO notification_prefs (user_id, chat_id, muted)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (user_id, chat_id) DO UPDATE SET muted = EXCLUDED.muted
+            RETURNING user_id, chat_id, muted
+            "#,
+        )
+        .bind(user_id as i64)
+        .bind(chat_id as i64)
+        .bind(muted)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(pref)
+    }
+
+    /// mark the chat read by the user up to the latest message at or before `message_id`,
+    /// reading never moves backward. Other members are notified by the `chat_read` trigger
+    pub async fn mark_read(
+        &self,
+        chat_id: u64,
+        user_id: u64,
+        message_id: Option<u64>,
+    ) -> Result<ChatRead, AppError> {
+        let read = sqlx::query_as(
+            r#"
+            INSERT INTO chat_reads (user_id, chat_id, last_read_id)
+            SELECT $1, $2, m.last_id
+            FROM (
+                SELECT max(id) AS last_id FROM messages
+                WHERE chat_id = $2 AND ($3::bigint IS NULL OR id <= $3)
+            ) m
+            WHERE m.last_id IS NOT NULL
+            ON CONFLICT (user_id, chat_id) DO UPDATE
+            SET last_read_id = GREATEST(chat_reads.last_read_id, EXCLUDED.last_read_id),
+                updated_at = now()
+            RETURNING user_id, chat_id, last_read_id
+            "#,
+        )
+        .bind(user_id as i64)
+        .bind(chat_id as i64)
+        .bind(message_id.map(|v| v as i64))
+        .fetch_optional(&self.pool)
+        .await?;
+
+        read.ok_or_else(|| AppError::NotFound("no message to mark read".to_owned()))
+    }
+
+    /// mark every chat of the user in the workspace read up to its latest message, in one
+    /// statement so either all of them are marked or none. Chats without messages are skipped
+    pub async fn mark_all_read(&self, ws_id: u64, user_id: u64) -> Result<Vec<ChatRead>, AppError> {
+        let reads = sqlx::query_as(
+            r#"
+            INSERT INTO chat_reads (user_id, chat_id, last_read_id)
+            SELECT $2, c.id, m.last_id
+            FROM chats c
+            JOIN LATERAL (
+                SELECT max(id) AS last_id FROM messages WHERE chat_id = c.id
+            ) m ON m.last_id IS NOT NULL
+            WHERE c.ws_id = $1 AND $2 = ANY(c.members)
+            ON CONFLICT (user_id, chat_id) DO UPDATE
+            SET last_read_id = GREATEST(chat_reads.last_read_id, EXCLUDED.last_read_id),
+                updated_at = now()
+            RETURNING user_id, chat_id, last_read_id
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(user_id as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(reads)
+    }
+
+    /// number of unread messages of each chat of the user in the workspace, ordered by chat id.
+    /// the user's own messages are never unread
+    pub async fn unread_counts(
+        &self,
+        ws_id: u64,
+        user_id: u64,
+    ) -> Result<Vec<UnreadCount>, AppError> {
+        let counts = sqlx::query_as(
+            r#"
+            SELECT c.id AS chat_id, count(m.id) AS unread
+            FROM chats c
+            LEFT JOIN chat_reads r ON r.chat_id = c.id AND r.user_id = $2
+            LEFT JOIN messages m ON m.chat_id = c.id
+                AND m.id > COALESCE(r.last_read_id, 0)
+                AND m.sender_id != $2
+            WHERE c.ws_id = $1 AND $2 = ANY(c.members)
+            GROUP BY c.id
+            ORDER BY c.id
+            "#,
+        )
+        .bind(ws_id as i64)
+        .bind(user_id as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(counts)
+    }
+
+    /// add the user to a public channel of the workspace, joining twice is a no-op
+    pub async fn join(&self, ws_id: u64, chat_id: u64, user_id: u64) -> Result<Chat, AppError> {
+        // chats of other workspaces look the same as missing ones
+        let chat = match self.get_by_id(chat_id, Some(ws_id)).await? {
+            Some(chat) => chat,
+            None => return Err(AppError::NotFound("chat id not found".to_owned())),
+        };
+        if chat.r#type != ChatType::PublicChannel {
+            return Err(AppError::PermissionDeny);
+        }
+        // the size is checked in the update, so concurrent joins can't exceed it
+        let chat = sqlx::query_as(
+            r#"
+            UPDATE chats
+            SET members = CASE WHEN $2 = ANY(members) THEN members ELSE array_append(members, $2) END
+            WHERE id = $1 AND ($2 = ANY(members) OR cardinality(members) < $3)
+            RETURNING id, ws_id, name, type, members, created_at, archived_at
+            "#,
+        )
+        .bind(chat_id as i64)
+        .bind(user_id as i64)
+        .bind(self.opts.max_chat_members as i32)
+        .fetch_optional(&self.pool)
+        .await?;
+        chat.ok_or_else(|| AppError::CreateChatError("too many members".to_string()))
+    }
+
     pub async fn is_chat_member(&self, chat_id: u64, user_id: u64) -> Result<bool, AppError> {
         let is_member = sqlx::query(
             r#"
@@ -203,14 +828,19 @@ impl CreateChat {
 
 #[cfg(test)]
 impl UpdateChat {
-    pub fn new(name: Option<String>) -> Self {
-        Self { name }
+    pub fn new(name: Option<String>, public: Option<bool>) -> Self {
+        Self {
+            name,
+            public,
+            members: None,
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use crate::{services::WsService, test_util::get_test_pool};
+    use argon2::Argon2;
 
     use super::*;
 
@@ -218,8 +848,8 @@ mod tests {
     async fn create_single_chat_should_work() {
         let (_tdb, pool) = get_test_pool(None).await;
         let ws_svc = WsService::new(pool.clone());
-        let user_svc = UserService::new(pool.clone(), ws_svc);
-        let svc = ChatService::new(pool.clone(), user_svc);
+        let user_svc = UserService::new(pool.clone(), ws_svc, Argon2::default());
+        let svc = ChatService::new(pool.clone(), user_svc, ChatOptions::default());
         let input = CreateChat::new(None, &[1, 2], false);
         let chat = svc.create(input, 1).await.expect("create chat failed");
         assert_eq!(chat.ws_id, 1);
@@ -227,12 +857,146 @@ mod tests {
         assert_eq!(chat.r#type, ChatType::Single);
     }
 
+    #[tokio::test]
+    async fn create_duplicate_single_chat_should_return_existing() {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let ws_svc = WsService::new(pool.clone());
+        let user_svc = UserService::new(pool.clone(), ws_svc, Argon2::default());
+        let svc = ChatService::new(pool.clone(), user_svc, ChatOptions::default());
+        let input = CreateChat::new(None, &[2, 4], false);
+        let chat1 = svc.create(input, 1).await.expect("create chat failed");
+        let input = CreateChat::new(None, &[4, 2], false);
+        let chat2 = svc.create(input, 1).await.expect("create chat failed");
+        assert_eq!(chat1.id, chat2.id);
+
+        // the seeded single chat between 1 and 2 is reused as well
+        let input = CreateChat::new(None, &[1, 2], false);
+        let chat = svc.create(input, 1).await.expect("create chat failed");
+        assert_eq!(chat.id, 3);
+    }
+
+    #[tokio::test]
+    async fn validate_create_should_not_create_chat() {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let ws_svc = WsService::new(pool.clone());
+        let user_svc = UserService::new(pool.clone(), ws_svc, Argon2::default());
+        let svc = ChatService::new(pool.clone(), user_svc, ChatOptions::default());
+        let pool = &pool;
+        let count = || async move {
+            let (count,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM chats")
+                .fetch_one(pool)
+                .await
+                .unwrap();
+            count
+        };
+        let before = count().await;
+
+        let input = CreateChat::new(None, &[2, 4, 5], false);
+        let chat_type = svc.validate_create(&input, 1).await.unwrap();
+        assert_eq!(chat_type, ChatType::Group);
+        let input = CreateChat::new(Some("news".to_string()), &[2, 4], true);
+        let chat_type = svc.validate_create(&input, 1).await.unwrap();
+        assert_eq!(chat_type, ChatType::PublicChannel);
+        assert_eq!(count().await, before);
+
+        let input = CreateChat::new(None, &[1, 999], false);
+        match svc.validate_create(&input, 1).await {
+            Err(AppError::CreateChatError(msg)) => assert_eq!(msg, "Some members do not exist"),
+            _ => panic!("members that don't exist should fail validation"),
+        }
+    }
+
+    #[tokio::test]
+    async fn chat_members_should_be_capped() {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let ws_svc = WsService::new(pool.clone());
+        let user_svc = UserService::new(pool.clone(), ws_svc, Argon2::default());
+        let opts = ChatOptions {
+            max_chat_members: 3,
+            ..Default::default()
+        };
+        let svc = ChatService::new(pool.clone(), user_svc, opts);
+        let input = CreateChat::new(Some("full".to_string()), &[1, 2, 3], true);
+        let chat = svc
+            .create(input, 1)
+            .await
+            .expect("create chat at the cap failed");
+
+        let input = CreateChat::new(Some("over".to_string()), &[1, 2, 3, 4], true);
+        match svc.create(input, 1).await {
+            Err(AppError::CreateChatError(msg)) => assert_eq!(msg, "too many members"),
+            _ => panic!("create chat over the cap should fail"),
+        }
+        match svc.join(1, chat.id as _, 4).await {
+            Err(AppError::CreateChatError(msg)) => assert_eq!(msg, "too many members"),
+            _ => panic!("join a full chat should fail"),
+        }
+        // joining again as a member is still a no-op
+        let chat = svc
+            .join(1, chat.id as _, 1)
+            .await
+            .expect("join chat failed");
+        assert_eq!(chat.members, vec![1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn create_chat_with_self_should_fail() {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let ws_svc = WsService::new(pool.clone());
+        let user_svc = UserService::new(pool.clone(), ws_svc, Argon2::default());
+        let svc = ChatService::new(pool.clone(), user_svc, ChatOptions::default());
+        let input = CreateChat::new(None, &[5, 5], false);
+        match svc.create(input, 1).await {
+            Err(AppError::CreateChatError(msg)) => {
+                assert_eq!(msg, "Can't create a chat with yourself")
+            }
+            _ => panic!("self chat should fail"),
+        }
+        let input = CreateChat::new(None, &[1, 2, 2], false);
+        match svc.create(input, 1).await {
+            Err(AppError::CreateChatError(msg)) => assert_eq!(msg, "Chat members must be distinct"),
+            _ => panic!("duplicate members should fail"),
+        }
+    }
+
+    #[tokio::test]
+    async fn create_large_unnamed_chat_should_require_name() -> anyhow::Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let ws_svc = WsService::new(pool.clone());
+        let user_svc = UserService::new(pool.clone(), ws_svc, Argon2::default());
+        let svc = ChatService::new(pool.clone(), user_svc, ChatOptions::default());
+        let input = CreateChat::new(None, &[1, 2, 3, 4, 5, 6, 7, 8, 9], false);
+        let err = svc.create(input, 1).await.unwrap_err();
+        assert!(matches!(
+            err,
+            AppError::ChatNameRequired {
+                members: 9,
+                max: 8,
+                r#type: ChatType::Group,
+            }
+        ));
+
+        let ret = axum::response::IntoResponse::into_response(err);
+        assert_eq!(ret.status(), axum::http::StatusCode::BAD_REQUEST);
+        let body = http_body_util::BodyExt::collect(ret.into_body())
+            .await?
+            .to_bytes();
+        let ret: crate::error::ErrorOutput = serde_json::from_slice(&body)?;
+        assert_eq!(ret.code.as_deref(), Some("chat_name_required"));
+        assert!(ret.error.contains("9 members must have a name"));
+        assert_eq!(
+            ret.details,
+            Some(serde_json::json!({ "members": 9, "max": 8, "type": "group" }))
+        );
+        Ok(())
+    }
+
     #[tokio::test]
     async fn create_public_name_chat_should_work() {
         let (_tdb, pool) = get_test_pool(None).await;
         let ws_svc = WsService::new(pool.clone());
-        let user_svc = UserService::new(pool.clone(), ws_svc);
-        let svc = ChatService::new(pool.clone(), user_svc);
+        let user_svc = UserService::new(pool.clone(), ws_svc, Argon2::default());
+        let svc = ChatService::new(pool.clone(), user_svc, ChatOptions::default());
         let input = CreateChat::new(Some("test".to_string()), &[1, 2, 3], true);
         let chat = svc.create(input, 1).await.expect("create chat failed");
         assert_eq!(chat.ws_id, 1);
@@ -245,43 +1009,258 @@ mod tests {
     pub async fn chat_get_by_id_should_work() {
         let (_tdb, pool) = get_test_pool(None).await;
         let ws_svc = WsService::new(pool.clone());
-        let user_svc = UserService::new(pool.clone(), ws_svc);
-        let svc = ChatService::new(pool.clone(), user_svc);
+        let user_svc = UserService::new(pool.clone(), ws_svc, Argon2::default());
+        let svc = ChatService::new(pool.clone(), user_svc, ChatOptions::default());
         let chat = svc
-            .get_by_id(1)
+            .get_by_id(1, None)
             .await
             .expect("get chat by id failed")
             .unwrap();
+        assert_eq!(chat.ws_id, 1);
         assert_eq!(chat.members.len(), 5);
         assert_eq!(chat.name.unwrap(), "general");
-        assert_eq!(chat.ws_id, 1);
+
+        let chat = svc
+            .get_by_id(1, Some(2))
+            .await
+            .expect("get chat by id failed");
+        assert!(chat.is_none());
     }
     #[tokio::test]
     pub async fn chat_get_all_should_work() {
         let (_tdb, pool) = get_test_pool(None).await;
         let ws_svc = WsService::new(pool.clone());
-        let user_svc = UserService::new(pool.clone(), ws_svc);
-        let svc = ChatService::new(pool.clone(), user_svc);
-        let chats = svc.fetch_all(1).await.expect("get all chat fail");
+        let user_svc = UserService::new(pool.clone(), ws_svc, Argon2::default());
+        let svc = ChatService::new(pool.clone(), user_svc, ChatOptions::default());
+        let chats = svc.fetch_all(1, false).await.expect("get all chat fail");
+        assert_eq!(chats.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn fetch_for_user_should_skip_chats_without_the_user() {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let ws_svc = WsService::new(pool.clone());
+        let user_svc = UserService::new(pool.clone(), ws_svc, Argon2::default());
+        let svc = ChatService::new(pool.clone(), user_svc, ChatOptions::default());
+        let input = CreateChat::new(Some("others".to_string()), &[3, 4, 5], false);
+        let other = svc.create(input, 1).await.expect("create chat failed");
+
+        let chats = svc
+            .fetch_for_user(1, 1, false)
+            .await
+            .expect("get chats fail");
+        let ids: Vec<_> = chats.iter().map(|chat| chat.id).collect();
+        assert_eq!(ids, vec![1, 2, 3, 4]);
+        let chats = svc
+            .fetch_for_user(1, 5, false)
+            .await
+            .expect("get chats fail");
+        let ids: Vec<_> = chats.iter().map(|chat| chat.id).collect();
+        assert_eq!(ids, vec![1, other.id]);
+    }
+
+    #[tokio::test]
+    async fn mark_read_should_never_move_backward() {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let ws_svc = WsService::new(pool.clone());
+        let user_svc = UserService::new(pool.clone(), ws_svc, Argon2::default());
+        let svc = ChatService::new(pool.clone(), user_svc, ChatOptions::default());
+        let (last_id,): (i64,) = sqlx::query_as("SELECT max(id) FROM messages WHERE chat_id = 1")
+            .fetch_one(&pool)
+            .await
+            .expect("get last message fail");
+
+        let read = svc.mark_read(1, 1, None).await.expect("mark read fail");
+        assert_eq!(read.last_read_id, last_id);
+        let read = svc.mark_read(1, 1, Some(1)).await.expect("mark read fail");
+        assert_eq!(read.last_read_id, last_id);
+
+        // the chat 4 has no messages
+        let err = svc.mark_read(4, 1, None).await.unwrap_err();
+        assert!(matches!(err, AppError::NotFound(_)));
+    }
+
+    #[tokio::test]
+    async fn mark_all_read_should_clear_unread_counts() {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let ws_svc = WsService::new(pool.clone());
+        let user_svc = UserService::new(pool.clone(), ws_svc, Argon2::default());
+        let svc = ChatService::new(pool.clone(), user_svc, ChatOptions::default());
+        for chat_id in [1i64, 2] {
+            sqlx::query("INSERT INTO messages (chat_id, sender_id, content) VALUES ($1, 2, 'hi')")
+                .bind(chat_id)
+                .execute(&pool)
+                .await
+                .expect("insert message fail");
+        }
+        let unread = |counts: Vec<UnreadCount>, chat_id| {
+            counts
+                .into_iter()
+                .find(|v| v.chat_id == chat_id)
+                .map(|v| v.unread)
+        };
+
+        let counts = svc.unread_counts(1, 1).await.expect("unread counts fail");
+        assert!(unread(counts.clone(), 1).unwrap() > 0);
+        assert!(unread(counts, 2).unwrap() > 0);
+
+        let reads = svc.mark_all_read(1, 1).await.expect("mark all read fail");
+        assert!(reads.iter().any(|v| v.chat_id == 1));
+        assert!(reads.iter().any(|v| v.chat_id == 2));
+        let counts = svc.unread_counts(1, 1).await.expect("unread counts fail");
+        assert_eq!(unread(counts.clone(), 1), Some(0));
+        assert_eq!(unread(counts, 2), Some(0));
+    }
+
+    #[tokio::test]
+    pub async fn chat_fetch_all_should_order_by_id() {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let ws_svc = WsService::new(pool.clone());
+        let user_svc = UserService::new(pool.clone(), ws_svc, Argon2::default());
+        let svc = ChatService::new(pool.clone(), user_svc, ChatOptions::default());
+        // rewriting a row moves it to the end of the heap, so the physical order changes
+        sqlx::query("UPDATE chats SET name = 'renamed' WHERE id = 1")
+            .execute(&pool)
+            .await
+            .expect("update chat fail");
+
+        let chats = svc.fetch_all(1, false).await.expect("get all chat fail");
+        let ids: Vec<_> = chats.iter().map(|chat| chat.id).collect();
+        assert_eq!(ids, vec![1, 2, 3, 4]);
+        let chats = svc
+            .fetch_all_with_preview(1, false)
+            .await
+            .expect("get all chat fail");
+        let ids: Vec<_> = chats.iter().map(|chat| chat.chat.id).collect();
+        assert_eq!(ids, vec![1, 2, 3, 4]);
+    }
+
+    #[tokio::test]
+    pub async fn chat_fetch_all_with_preview_should_work() {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let ws_svc = WsService::new(pool.clone());
+        let user_svc = UserService::new(pool.clone(), ws_svc, Argon2::default());
+        let svc = ChatService::new(pool.clone(), user_svc, ChatOptions::default());
+        sqlx::query("INSERT INTO messages (chat_id, sender_id, content) VALUES (1, 3, 'latest')")
+            .execute(&pool)
+            .await
+            .expect("insert message fail");
+
+        let chats = svc
+            .fetch_all_with_preview(1, false)
+            .await
+            .expect("fetch all with preview fail");
+        assert_eq!(chats.len(), 4);
+        for chat in chats {
+            match chat.chat.id {
+                1 => {
+                    let preview = chat.preview.expect("general chat should have a preview");
+                    assert_eq!(preview.sender_id, 3);
+                    assert_eq!(preview.content, "latest");
+                }
+                _ => assert!(chat.preview.is_none()),
+            }
+        }
+    }
+
+    #[tokio::test]
+    pub async fn chat_fetch_recent_should_work() {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let ws_svc = WsService::new(pool.clone());
+        let user_svc = UserService::new(pool.clone(), ws_svc, Argon2::default());
+        let svc = ChatService::new(pool.clone(), user_svc, ChatOptions::default());
+        let chats = svc.fetch_recent(1, 10).await.expect("fetch recent fail");
+        assert_eq!(chats.len(), 4);
+        // only the general chat has messages in the fixtures
+        for chat in &chats {
+            assert_eq!(chat.last_message_at.is_some(), chat.chat.id == 1);
+        }
+
+        sqlx::query("INSERT INTO messages (chat_id, sender_id, content) VALUES (4, 1, 'hi')")
+            .execute(&pool)
+            .await
+            .expect("insert message fail");
+        let chats = svc.fetch_recent(1, 2).await.expect("fetch recent fail");
+        assert_eq!(chats.len(), 2);
+        assert_eq!(chats[0].chat.id, 4);
+    }
+
+    #[tokio::test]
+    pub async fn chat_archive_should_work() {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let ws_svc = WsService::new(pool.clone());
+        let user_svc = UserService::new(pool.clone(), ws_svc, Argon2::default());
+        let svc = ChatService::new(pool.clone(), user_svc, ChatOptions::default());
+        let chat = svc.archive(1, 2).await.expect("archive chat fail");
+        assert!(chat.archived_at.is_some());
+
+        let chats = svc.fetch_all(1, false).await.expect("get all chat fail");
+        assert_eq!(chats.len(), 3);
+        assert!(chats.iter().all(|c| c.id != 2));
+        let chats = svc.fetch_all(1, true).await.expect("get all chat fail");
+        assert_eq!(chats.len(), 4);
+
+        let chat = svc.unarchive(1, 2).await.expect("unarchive chat fail");
+        assert!(chat.archived_at.is_none());
+        let chats = svc.fetch_all(1, false).await.expect("get all chat fail");
         assert_eq!(chats.len(), 4);
     }
+
+    #[tokio::test]
+    pub async fn chat_archive_other_ws_chat_should_fail() {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let ws_svc = WsService::new(pool.clone());
+        let user_svc = UserService::new(pool.clone(), ws_svc, Argon2::default());
+        let svc = ChatService::new(pool.clone(), user_svc, ChatOptions::default());
+        match svc.archive(2, 1).await {
+            Err(AppError::PermissionDeny) => {}
+            _ => panic!("should fail"),
+        };
+    }
     #[tokio::test]
     pub async fn chat_delete_should_work() {
         let (_tdb, pool) = get_test_pool(None).await;
         let ws_svc = WsService::new(pool.clone());
-        let user_svc = UserService::new(pool.clone(), ws_svc);
-        let svc = ChatService::new(pool.clone(), user_svc);
+        let user_svc = UserService::new(pool.clone(), ws_svc, Argon2::default());
+        let svc = ChatService::new(pool.clone(), user_svc, ChatOptions::default());
         let chat = svc.delete(1, 1).await.expect("delete chat fail");
         assert_eq!(chat.name.unwrap(), "general");
-        let chat = svc.get_by_id(1).await.expect("get chat by id failed");
+        let chat = svc.get_by_id(1, None).await.expect("get chat by id failed");
         assert!(chat.is_none())
     }
+    #[tokio::test]
+    pub async fn create_chat_over_workspace_limit_should_fail() {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let ws_svc = WsService::new(pool.clone());
+        let user_svc = UserService::new(pool.clone(), ws_svc, Argon2::default());
+        // ws1 already has 4 chats
+        let opts = ChatOptions {
+            max_chats_per_workspace: 6,
+            ..Default::default()
+        };
+        let svc = ChatService::new(pool.clone(), user_svc, opts);
+        for name in ["c5", "c6"] {
+            let input = CreateChat::new(Some(name.to_string()), &[1, 2, 3], false);
+            svc.create(input, 1).await.expect("create chat failed");
+        }
+
+        let input = CreateChat::new(Some("c7".to_string()), &[1, 2, 3], false);
+        match svc.create(input, 1).await {
+            Err(AppError::CreateChatError(msg)) => assert_eq!(msg, "workspace chat limit reached"),
+            _ => panic!("create chat over the limit should fail"),
+        }
+        // the existing single chat is still returned
+        let input = CreateChat::new(None, &[1, 2], false);
+        let chat = svc.create(input, 1).await.expect("create chat failed");
+        assert_eq!(chat.id, 3);
+    }
+
     #[tokio::test]
     pub async fn chat_delete_other_ws_chat_should_fail() {
         let (_tdb, pool) = get_test_pool(None).await;
         let ws_svc = WsService::new(pool.clone());
-        let user_svc = UserService::new(pool.clone(), ws_svc);
-        let svc = ChatService::new(pool.clone(), user_svc);
+        let user_svc = UserService::new(pool.clone(), ws_svc, Argon2::default());
+        let svc = ChatService::new(pool.clone(), user_svc, ChatOptions::default());
         match svc.delete(2, 1).await {
             Err(AppError::PermissionDeny) => return,
             _ => panic!("should fail"),
@@ -292,24 +1271,179 @@ mod tests {
     pub async fn chat_update_should_work() {
         let (_tdb, pool) = get_test_pool(None).await;
         let ws_svc = WsService::new(pool.clone());
-        let user_svc = UserService::new(pool.clone(), ws_svc);
-        let svc = ChatService::new(pool.clone(), user_svc);
-        let input = UpdateChat::new(Some("test".to_string()));
+        let user_svc = UserService::new(pool.clone(), ws_svc, Argon2::default());
+        let svc = ChatService::new(pool.clone(), user_svc, ChatOptions::default());
+        let input = UpdateChat::new(Some("test".to_string()), None);
         svc.update(input, 1, 1).await.expect("update chat fail");
         let chat = svc
-            .get_by_id(1)
+            .get_by_id(1, None)
             .await
             .expect("get chat by id failed")
             .unwrap();
         assert_eq!(chat.name.unwrap(), "test");
     }
 
+    #[tokio::test]
+    pub async fn chat_update_visibility_should_work() {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let ws_svc = WsService::new(pool.clone());
+        let user_svc = UserService::new(pool.clone(), ws_svc, Argon2::default());
+        let svc = ChatService::new(pool.clone(), user_svc, ChatOptions::default());
+        let input = UpdateChat::new(None, Some(true));
+        let chat = svc.update(input, 1, 2).await.expect("update chat fail");
+        assert_eq!(chat.r#type, ChatType::PublicChannel);
+        assert_eq!(chat.name.as_deref(), Some("private"));
+
+        let input = UpdateChat::new(None, Some(false));
+        let chat = svc.update(input, 1, 2).await.expect("update chat fail");
+        assert_eq!(chat.r#type, ChatType::PrivateChannel);
+    }
+
+    #[tokio::test]
+    pub async fn chat_update_members_should_add_and_remove() {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let ws_svc = WsService::new(pool.clone());
+        let user_svc = UserService::new(pool.clone(), ws_svc, Argon2::default());
+        let svc = ChatService::new(pool.clone(), user_svc, ChatOptions::default());
+        let input = CreateChat::new(Some("team".to_string()), &[1, 2], false);
+        let chat = svc.create(input, 1).await.expect("create chat failed");
+
+        let input = UpdateChat {
+            members: Some(vec![1, 3]),
+            ..Default::default()
+        };
+        let chat = svc
+            .update(input, 1, chat.id as _)
+            .await
+            .expect("update chat fail");
+        assert_eq!(chat.members, vec![1, 3]);
+        assert!(!svc.is_chat_member(chat.id as _, 2).await.unwrap());
+        assert!(svc.is_chat_member(chat.id as _, 3).await.unwrap());
+
+        // single chats keep their members
+        let input = UpdateChat {
+            members: Some(vec![1, 3]),
+            ..Default::default()
+        };
+        match svc.update(input, 1, 3).await {
+            Err(AppError::InvalidInput(_)) => {}
+            _ => panic!("members of a single chat can't be changed"),
+        }
+    }
+
+    #[tokio::test]
+    pub async fn chat_update_members_should_add_more_than_a_batch() {
+        let (_tdb, pool) = get_test_pool(None).await;
+        sqlx::query(
+            r#"
+            INSERT INTO users(ws_id, email, fullname, password_hash)
+            SELECT 1, 'user' || i || '@gmail.com', 'user' || i, '' FROM generate_series(1, 150) i
+            "#,
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let ws_svc = WsService::new(pool.clone());
+        let user_svc = UserService::new(pool.clone(), ws_svc, Argon2::default());
+        let svc = ChatService::new(pool.clone(), user_svc, ChatOptions::default());
+        let members: Vec<i64> = (1..=155).collect();
+
+        let input = UpdateChat {
+            members: Some(members.clone()),
+            ..Default::default()
+        };
+        let chat = svc.update(input, 1, 1).await.expect("update chat fail");
+        assert_eq!(chat.members, members);
+    }
+
+    #[tokio::test]
+    pub async fn chat_update_visibility_of_single_chat_should_fail() {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let ws_svc = WsService::new(pool.clone());
+        let user_svc = UserService::new(pool.clone(), ws_svc, Argon2::default());
+        let svc = ChatService::new(pool.clone(), user_svc, ChatOptions::default());
+        let input = UpdateChat::new(None, Some(true));
+        match svc.update(input, 1, 3).await {
+            Err(AppError::InvalidInput(_)) => {}
+            _ => panic!("single chat can't be public"),
+        }
+        let chat = svc.get_by_id(3, None).await.unwrap().unwrap();
+        assert_eq!(chat.r#type, ChatType::Single);
+    }
+
+    #[tokio::test]
+    pub async fn chat_set_muted_should_work() {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let ws_svc = WsService::new(pool.clone());
+        let user_svc = UserService::new(pool.clone(), ws_svc, Argon2::default());
+        let svc = ChatService::new(pool.clone(), user_svc, ChatOptions::default());
+        let pref = svc.set_muted(1, 2, true).await.expect("mute chat fail");
+        assert!(pref.muted);
+        let pref = svc.set_muted(1, 2, false).await.expect("unmute chat fail");
+        assert!(!pref.muted);
+        assert_eq!((pref.user_id, pref.chat_id), (2, 1));
+    }
+
+    #[tokio::test]
+    pub async fn chat_join_should_work() {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let ws_svc = WsService::new(pool.clone());
+        let user_svc = UserService::new(pool.clone(), ws_svc, Argon2::default());
+        let svc = ChatService::new(pool.clone(), user_svc, ChatOptions::default());
+        let input = CreateChat::new(Some("open".to_string()), &[1, 2], true);
+        let chat = svc.create(input, 1).await.expect("create chat failed");
+
+        let joined = svc
+            .join(1, chat.id as _, 3)
+            .await
+            .expect("join chat failed");
+        assert_eq!(joined.members, vec![1, 2, 3]);
+        let joined = svc
+            .join(1, chat.id as _, 3)
+            .await
+            .expect("join chat failed");
+        assert_eq!(joined.members, vec![1, 2, 3]);
+
+        assert!(matches!(
+            svc.join(2, chat.id as _, 3).await,
+            Err(AppError::NotFound(_))
+        ));
+        assert!(matches!(
+            svc.join(1, 2, 4).await,
+            Err(AppError::PermissionDeny)
+        ));
+    }
+
+    #[tokio::test]
+    pub async fn chat_fetch_discoverable_should_exclude_joined() {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let ws_svc = WsService::new(pool.clone());
+        let user_svc = UserService::new(pool.clone(), ws_svc, Argon2::default());
+        let svc = ChatService::new(pool.clone(), user_svc, ChatOptions::default());
+        let input = CreateChat::new(Some("open".to_string()), &[2, 3], true);
+        let open = svc.create(input, 1).await.expect("create chat failed");
+
+        let chats = svc
+            .fetch_discoverable(1, 1)
+            .await
+            .expect("fetch discoverable chats failed");
+        let ids: Vec<_> = chats.iter().map(|chat| chat.id).collect();
+        // user 1 is already in "general", "private" is not public
+        assert_eq!(ids, vec![open.id]);
+
+        let chats = svc
+            .fetch_discoverable(1, 2)
+            .await
+            .expect("fetch discoverable chats failed");
+        assert!(chats.is_empty());
+    }
+
     #[tokio::test]
     pub async fn chat_is_member_should_work() {
         let (_tdb, pool) = get_test_pool(None).await;
         let ws_svc = WsService::new(pool.clone());
-        let user_svc = UserService::new(pool.clone(), ws_svc);
-        let svc = ChatService::new(pool.clone(), user_svc);
+        let user_svc = UserService::new(pool.clone(), ws_svc, Argon2::default());
+        let svc = ChatService::new(pool.clone(), user_svc, ChatOptions::default());
         let is_member = svc
             .is_chat_member(1, 1)
             .await
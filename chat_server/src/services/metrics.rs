@@ -0,0 +1,92 @@
+use std::fmt::Write;
+
+use sqlx::{FromRow, PgPool};
+
+use crate::error::AppError;
+
+/// totals across all workspaces, exposed for scraping, the placeholder super user is not counted
+#[derive(Debug, Clone, Default, PartialEq, Eq, FromRow)]
+pub struct Metrics {
+    pub users: i64,
+    pub chats: i64,
+    pub messages: i64,
+    pub messages_24h: i64,
+}
+
+impl Metrics {
+    /// render in the prometheus text exposition format
+    pub fn to_prometheus(&self) -> String {
+        let gauges = [
+            ("chat_users_total", "Number of users.", self.users),
+            ("chat_chats_total", "Number of chats.", self.chats),
+            ("chat_messages_total", "Number of messages.", self.messages),
+            (
+                "chat_messages_24h",
+                "Number of messages created in the last 24 hours.",
+                self.messages_24h,
+            ),
+        ];
+        let mut out = String::new();
+        for (name, help, value) in gauges {
+            // writing to a String never fails
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} gauge", name);
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+        out
+    }
+}
+
+pub(crate) struct MetricsService {
+    pool: PgPool,
+}
+
+impl Clone for MetricsService {
+    fn clone(&self) -> Self {
+        Self {
+            pool: self.pool.clone(),
+        }
+    }
+}
+
+impl MetricsService {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    pub async fn collect(&self) -> Result<Metrics, AppError> {
+        let metrics = sqlx::query_as(
+            r#"
+        SELECT
+            (SELECT count(*) FROM users WHERE id > 0) AS users,
+            (SELECT count(*) FROM chats) AS chats,
+            (SELECT count(*) FROM messages) AS messages,
+            (SELECT count(*) FROM messages WHERE created_at > now() - interval '24 hours') AS messages_24h
+        "#,
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(metrics)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::get_test_pool;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn collect_metrics_should_work() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let svc = MetricsService::new(pool);
+        let metrics = svc.collect().await?;
+        assert_eq!(metrics.users, 5);
+        assert_eq!(metrics.chats, 4);
+        assert_eq!(metrics.messages, 10);
+        assert!(metrics
+            .to_prometheus()
+            .contains("\nchat_messages_total 10\n"));
+        Ok(())
+    }
+}
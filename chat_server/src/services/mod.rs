@@ -1,9 +1,11 @@
 mod chat;
+mod metrics;
 mod msg;
 mod user;
 mod ws;
 
 pub(crate) use chat::*;
+pub(crate) use metrics::*;
 pub(crate) use msg::*;
 pub(crate) use user::*;
 pub(crate) use ws::*;
@@ -1,38 +1,248 @@
 use std::{
-    path::{Path, PathBuf},
+    collections::{BTreeMap, HashMap},
+    future,
     str::FromStr,
+    sync::Arc,
+    time::{Duration, SystemTime},
 };
 
-use chat_core::Message;
+use chat_core::{Attachment, Message};
+use chrono::{DateTime, Utc};
+use futures::{stream, Stream, StreamExt, TryStreamExt};
 use serde::{Deserialize, Serialize};
-use sqlx::PgPool;
+use sqlx::{types::Json, PgExecutor, PgPool};
 
-use crate::{error::AppError, models::ChatFile};
+use crate::{config::ServerConfig, error::AppError, models::ChatFile, store::FileStore};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CreateMessage {
     pub content: String,
     pub files: Vec<String>,
+    /// files with metadata as returned by upload, their urls are added to files as well
+    #[serde(default)]
+    pub attachments: Vec<Attachment>,
+}
+
+/// a validated message ready to insert
+struct NewMessage {
+    content: String,
+    files: Vec<String>,
+    attachments: Vec<Attachment>,
+    forwarded_from: Option<i64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ForwardMessage {
+    pub target_chat_id: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct MessageCount {
+    pub count: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PinMessage {
+    pub message_id: u64,
+    pub pinned: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ListMessageOption {
+    /// walk backward from this id (exclusive)
     pub last_id: Option<u64>,
+    /// walk forward from this id (exclusive), can't be used with last_id
+    pub after_id: Option<u64>,
     pub limit: u64,
+    /// only messages created before this time
+    pub before: Option<DateTime<Utc>>,
+    /// only messages created after this time
+    pub after: Option<DateTime<Utc>>,
+    /// attach the sender's name and avatar to each message
+    #[serde(default)]
+    pub with_sender: bool,
+    /// attach the count of each reaction emoji to each message
+    #[serde(default)]
+    pub with_reactions: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct MessageSender {
+    pub fullname: String,
+    pub avatar_url: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct MessageWithSender {
+    #[serde(flatten)]
+    pub message: Message,
+    /// none when the sender no longer exists
+    pub sender: Option<MessageSender>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct MessageWithReactions<T> {
+    #[serde(flatten)]
+    pub message: T,
+    /// count of each emoji, empty when nobody reacted
+    pub reactions: BTreeMap<String, i64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReactMessage {
+    pub message_id: u64,
+    pub emoji: String,
+    /// false removes the caller's reaction
+    pub reacted: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    #[default]
+    Json,
+    Csv,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ExportOption {
+    #[serde(default)]
+    pub format: ExportFormat,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ExportMessage {
+    pub id: i64,
+    pub sender_id: i64,
+    pub sender: String,
+    pub content: String,
+    pub files: Vec<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum UploadFormat {
+    /// `{ "files": [...] }`
+    #[default]
+    Object,
+    /// bare array of the files, the original response
+    Array,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UploadOption {
+    #[serde(default)]
+    pub format: UploadFormat,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct UploadOutput {
+    /// url, size, mime and original filename of each stored file
+    pub files: Vec<Attachment>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum FileDisposition {
+    /// the browser downloads the file
+    #[default]
+    Attachment,
+    /// the browser displays the file, e.g. to preview images and pdfs
+    Inline,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FileOption {
+    #[serde(default)]
+    pub disposition: FileDisposition,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeleteFiles {
+    pub urls: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ZipFiles {
+    /// urls of uploaded files of the caller's workspace
+    pub urls: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeleteFileStatus {
+    Deleted,
+    /// still used by a message or as an avatar, kept
+    Referenced,
+    NotFound,
+    PermissionDeny,
+    Invalid,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct DeleteFileResult {
+    pub url: String,
+    pub status: DeleteFileStatus,
+}
+
+const EXPORT_BATCH_SIZE: u64 = 500;
+/// a repeated idempotency key within this window returns the original message
+const IDEMPOTENCY_WINDOW_HOURS: i64 = 24;
+const MAX_IDEMPOTENCY_KEY_LEN: usize = 64;
+const MAX_EMOJI_LEN: usize = 32;
+
+/// limits applied when creating messages
+#[derive(Debug, Clone)]
+pub struct MsgOptions {
+    pub max_message_len: usize,
+    pub max_files_per_message: usize,
+    pub max_pins_per_chat: usize,
+    pub max_batch_messages: usize,
 }
 
 pub struct MsgService {
     pool: PgPool,
-    base_dir: PathBuf,
+    store: Arc<dyn FileStore>,
+    opts: MsgOptions,
 }
 
-impl MsgService {
-    pub fn new(pool: PgPool, base_dir: impl AsRef<Path>) -> Self {
+impl Default for MsgOptions {
+    fn default() -> Self {
         Self {
-            pool,
-            base_dir: base_dir.as_ref().to_path_buf(),
+            max_message_len: 10000,
+            max_files_per_message: 10,
+            max_pins_per_chat: 50,
+            max_batch_messages: 100,
+        }
+    }
+}
+
+impl From<&ServerConfig> for MsgOptions {
+    fn from(config: &ServerConfig) -> Self {
+        Self {
+            max_message_len: config.max_message_len,
+            max_files_per_message: config.max_files_per_message,
+            max_pins_per_chat: config.max_pins_per_chat,
+            max_batch_messages: config.max_batch_messages,
+        }
+    }
+}
+
+impl Clone for MsgService {
+    fn clone(&self) -> Self {
+        Self {
+            pool: self.pool.clone(),
+            store: self.store.clone(),
+            opts: self.opts.clone(),
         }
     }
+}
+
+impl MsgService {
+    pub fn new(pool: PgPool, store: Arc<dyn FileStore>, opts: MsgOptions) -> Self {
+        Self { pool, store, opts }
+    }
 
     pub async fn create(
         &self,
@@ -40,31 +250,249 @@ impl MsgService {
         chat_id: u64,
         user_id: u64,
     ) -> Result<Message, AppError> {
-        if input.content.is_empty() {
-            return Err(AppError::InvalidInput("content is empty".to_string()));
+        let msg = self.validate(input, chat_id, user_id).await?;
+        insert_message(&self.pool, chat_id, user_id, msg).await
+    }
+
+    /// create a message at most once per idempotency key of the sender, a retry with the
+    /// same key returns the message created by the first request. Reusing the key in another
+    /// chat is a conflict
+    pub async fn create_idempotent(
+        &self,
+        input: CreateMessage,
+        chat_id: u64,
+        user_id: u64,
+        key: &str,
+    ) -> Result<Message, AppError> {
+        if key.is_empty() || key.len() > MAX_IDEMPOTENCY_KEY_LEN {
+            return Err(AppError::InvalidInput(format!(
+                "idempotency key must be 1 to {} bytes",
+                MAX_IDEMPOTENCY_KEY_LEN
+            )));
         }
+        if let Some(message) = self.find_by_idempotency_key(user_id, key).await? {
+            return same_chat(message, chat_id);
+        }
+        // the key was used long ago, release it so it can be reused
+        sqlx::query(
+            "UPDATE messages SET idempotency_key = NULL WHERE sender_id = $1 AND idempotency_key = $2",
+        )
+        .bind(user_id as i64)
+        .bind(key)
+        .execute(&self.pool)
+        .await?;
 
-        for url in &input.files {
-            let file = ChatFile::from_str(url)?;
-            if !file.path(&self.base_dir).exists() {
-                return Err(AppError::InvalidInput("file not found".to_string()));
-            }
+        let msg = self.validate(input, chat_id, user_id).await?;
+        let message: Option<Message> = sqlx::query_as(
+            r#"
+        INSERT INTO messages (chat_id, sender_id, content, files, attachments, idempotency_key)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        ON CONFLICT (sender_id, idempotency_key) WHERE idempotency_key IS NOT NULL DO NOTHING
+        RETURNING id, chat_id, sender_id, content, files, attachments, pinned, forwarded_from, created_at
+        "#,
+        )
+        .bind(chat_id as i64)
+        .bind(user_id as i64)
+        .bind(msg.content)
+        .bind(msg.files)
+        .bind(Json(msg.attachments))
+        .bind(key)
+        .fetch_optional(&self.pool)
+        .await?;
+        match message {
+            Some(message) => Ok(message),
+            // a concurrent request with the same key won
+            None => match self.find_by_idempotency_key(user_id, key).await? {
+                Some(message) => same_chat(message, chat_id),
+                None => Err(AppError::Conflict("idempotency key in use".to_string())),
+            },
+        }
+    }
+
+    async fn find_by_idempotency_key(
+        &self,
+        user_id: u64,
+        key: &str,
+    ) -> Result<Option<Message>, AppError> {
+        let message = sqlx::query_as(
+            r#"
+        SELECT id, chat_id, sender_id, content, files, attachments, pinned, forwarded_from, created_at
+        FROM messages
+        WHERE sender_id = $1 AND idempotency_key = $2
+        AND created_at > now() - make_interval(hours => $3)
+        "#,
+        )
+        .bind(user_id as i64)
+        .bind(key)
+        .bind(IDEMPOTENCY_WINDOW_HOURS as i32)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(message)
+    }
+
+    /// create messages in one transaction, nothing is inserted if any of them is invalid
+    pub async fn create_batch(
+        &self,
+        inputs: Vec<CreateMessage>,
+        chat_id: u64,
+        user_id: u64,
+    ) -> Result<Vec<Message>, AppError> {
+        if inputs.len() > self.opts.max_batch_messages {
+            return Err(AppError::InvalidInput(format!(
+                "too many messages, at most {} allowed",
+                self.opts.max_batch_messages
+            )));
+        }
+        let mut validated = Vec::with_capacity(inputs.len());
+        for input in inputs {
+            validated.push(self.validate(input, chat_id, user_id).await?);
         }
 
-        Ok(sqlx::query_as(
+        let mut tx = self.pool.begin().await?;
+        let mut messages = Vec::with_capacity(validated.len());
+        for msg in validated {
+            messages.push(insert_message(&mut *tx, chat_id, user_id, msg).await?);
+        }
+        tx.commit().await?;
+        Ok(messages)
+    }
+
+    /// copy a message of the chat into the target chat as a new message of the caller, who
+    /// must be a member of both chats
+    pub async fn forward(
+        &self,
+        chat_id: u64,
+        message_id: u64,
+        target_chat_id: u64,
+        user_id: u64,
+    ) -> Result<Message, AppError> {
+        let is_member = sqlx::query("SELECT 1 FROM chats WHERE id = $1 AND $2 = ANY(members)")
+            .bind(chat_id as i64)
+            .bind(user_id as i64)
+            .fetch_optional(&self.pool)
+            .await?;
+        if is_member.is_none() {
+            return Err(AppError::PermissionDeny);
+        }
+        let source: Message = sqlx::query_as(
             r#"
-            INSERT INTO messages (chat_id, sender_id, content, files)
-            VALUES ($1, $2, $3, $4)
-            RETURNING id, chat_id, sender_id, content, files, created_at
-            "#,
+        SELECT id, chat_id, sender_id, content, files, attachments, pinned, forwarded_from, created_at
+        FROM messages
+        WHERE chat_id = $1 AND id = $2
+        "#,
+        )
+        .bind(chat_id as i64)
+        .bind(message_id as i64)
+        .fetch_optional(&self.pool)
+        .await?
+        .ok_or_else(|| AppError::NotFound("message id not found".to_owned()))?;
+
+        // urls of attachments are added to files again by validate
+        let attachments = source.attachments.0;
+        let files = source
+            .files
+            .into_iter()
+            .filter(|url| !attachments.iter().any(|v| &v.url == url))
+            .collect();
+        let input = CreateMessage {
+            content: source.content,
+            files,
+            attachments,
+        };
+        let msg = NewMessage {
+            forwarded_from: Some(source.id),
+            ..self.validate(input, target_chat_id, user_id).await?
+        };
+        insert_message(&self.pool, target_chat_id, user_id, msg).await
+    }
+
+    /// check the message can be sent, file urls are normalized and attachment mime types
+    /// are derived from the file rather than trusted
+    async fn validate(
+        &self,
+        input: CreateMessage,
+        chat_id: u64,
+        user_id: u64,
+    ) -> Result<NewMessage, AppError> {
+        // a message may be just files, but not nothing at all
+        if input.content.is_empty() && input.files.is_empty() && input.attachments.is_empty() {
+            return Err(AppError::InvalidInput(
+                "content and files are both empty".to_string(),
+            ));
+        }
+        if input.content.chars().count() > self.opts.max_message_len {
+            return Err(AppError::InvalidInput("message too long".to_string()));
+        }
+
+        // callers may skip the chat perm middleware, so membership is checked here as well
+        let chat: Option<(bool, bool)> = sqlx::query_as(
+            "SELECT $2 = ANY(members), archived_at IS NOT NULL FROM chats WHERE id = $1",
         )
         .bind(chat_id as i64)
         .bind(user_id as i64)
-        .bind(input.content)
-        .bind(input.files)
-        .fetch_one(&self.pool)
-        .await?)
+        .fetch_optional(&self.pool)
+        .await?;
+        match chat {
+            None => return Err(AppError::NotFound("chat not found".to_string())),
+            Some((false, _)) => return Err(AppError::PermissionDeny),
+            Some((_, true)) => return Err(AppError::InvalidInput("chat is archived".to_string())),
+            Some((true, false)) => {}
+        }
+
+        let count = input.files.len() + input.attachments.len();
+        if count > self.opts.max_files_per_message {
+            return Err(AppError::InvalidInput(format!(
+                "too many files, at most {} allowed",
+                self.opts.max_files_per_message
+            )));
+        }
+
+        let mut files = Vec::with_capacity(count);
+        let mut attachments = Vec::with_capacity(input.attachments.len());
+        if count > 0 {
+            let (ws_id,): (i64,) = sqlx::query_as("SELECT ws_id FROM users WHERE id = $1")
+                .bind(user_id as i64)
+                .fetch_one(&self.pool)
+                .await?;
+            for url in &input.files {
+                // absolute urls are stored in the relative form
+                files.push(self.check_file(url, ws_id as _).await?.url());
+            }
+            for attachment in input.attachments {
+                let file = self.check_file(&attachment.url, ws_id as _).await?;
+                files.push(file.url());
+                attachments.push(Attachment {
+                    url: file.url(),
+                    mime: file.mime(),
+                    ..attachment
+                });
+            }
+        }
+
+        Ok(NewMessage {
+            content: input.content,
+            files,
+            attachments,
+            forwarded_from: None,
+        })
+    }
+
+    /// the file must be uploaded to the workspace
+    async fn check_file(&self, url: &str, ws_id: u64) -> Result<ChatFile, AppError> {
+        let file = ChatFile::from_str(url)?;
+        if file.ws_id != ws_id {
+            return Err(AppError::InvalidInput(format!(
+                "file not in workspace: {}",
+                url
+            )));
+        }
+        if !self.store.exists(&file).await? {
+            return Err(AppError::InvalidInput(format!("file not found: {}", url)));
+        }
+        Ok(file)
     }
+
+    /// messages of the chat, newest (highest id) first, paged by `last_id`
     pub async fn list(
         &self,
         input: ListMessageOption,
@@ -73,10 +501,12 @@ impl MsgService {
         let last_id = input.last_id.unwrap_or(i64::MAX as _);
         let messages = sqlx::query_as(
             r#"
-        SELECT id, chat_id, sender_id, content, files, created_at
+        SELECT id, chat_id, sender_id, content, files, attachments, pinned, forwarded_from, created_at
         FROM messages
         WHERE chat_id = $1
         AND id < $2
+        AND ($4::timestamptz IS NULL OR created_at < $4)
+        AND ($5::timestamptz IS NULL OR created_at > $5)
         ORDER BY id DESC
         LIMIT $3
         "#,
@@ -84,23 +514,434 @@ impl MsgService {
         .bind(chat_id as i64)
         .bind(last_id as i64)
         .bind(input.limit as i64)
+        .bind(input.before)
+        .bind(input.after)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(messages)
+    }
+
+    /// list messages newer than after_id in ascending order, used to catch up after reconnecting
+    pub async fn list_after(
+        &self,
+        chat_id: u64,
+        after_id: u64,
+        limit: u64,
+    ) -> Result<Vec<Message>, AppError> {
+        self.list_after_in_range(chat_id, after_id, limit, None, None)
+            .await
+    }
+
+    /// like `list_after`, only messages created between `after` and `before` when given
+    pub async fn list_after_in_range(
+        &self,
+        chat_id: u64,
+        after_id: u64,
+        limit: u64,
+        before: Option<DateTime<Utc>>,
+        after: Option<DateTime<Utc>>,
+    ) -> Result<Vec<Message>, AppError> {
+        let messages = sqlx::query_as(
+            r#"
+        SELECT id, chat_id, sender_id, content, files, attachments, pinned, forwarded_from, created_at
+        FROM messages
+        WHERE chat_id = $1
+        AND id > $2
+        AND ($4::timestamptz IS NULL OR created_at < $4)
+        AND ($5::timestamptz IS NULL OR created_at > $5)
+        ORDER BY id ASC
+        LIMIT $3
+        "#,
+        )
+        .bind(chat_id as i64)
+        .bind(after_id as i64)
+        .bind(limit as i64)
+        .bind(before)
+        .bind(after)
         .fetch_all(&self.pool)
         .await?;
         Ok(messages)
     }
+
+    /// pin or unpin a message of the chat, only chat members can do it
+    pub async fn set_pinned(
+        &self,
+        chat_id: u64,
+        message_id: u64,
+        pinned: bool,
+        caller_id: u64,
+    ) -> Result<Message, AppError> {
+        let mut tx = self.pool.begin().await?;
+        // locking the chat row serializes pins of the chat, so concurrent ones can't exceed the cap
+        let is_member =
+            sqlx::query("SELECT 1 FROM chats WHERE id = $1 AND $2 = ANY(members) FOR UPDATE")
+                .bind(chat_id as i64)
+                .bind(caller_id as i64)
+                .fetch_optional(&mut *tx)
+                .await?;
+        if is_member.is_none() {
+            return Err(AppError::PermissionDeny);
+        }
+
+        if pinned {
+            let (count,): (i64,) = sqlx::query_as(
+                "SELECT count(*) FROM messages WHERE chat_id = $1 AND pinned AND id != $2",
+            )
+            .bind(chat_id as i64)
+            .bind(message_id as i64)
+            .fetch_one(&mut *tx)
+            .await?;
+            if count as usize >= self.opts.max_pins_per_chat {
+                return Err(AppError::Conflict(format!(
+                    "too many pinned messages, at most {} allowed",
+                    self.opts.max_pins_per_chat
+                )));
+            }
+        }
+
+        let message: Option<Message> = sqlx::query_as(
+            r#"
+        UPDATE messages
+        SET pinned = $3
+        WHERE chat_id = $1 AND id = $2
+        RETURNING id, chat_id, sender_id, content, files, attachments, pinned, forwarded_from, created_at
+        "#,
+        )
+        .bind(chat_id as i64)
+        .bind(message_id as i64)
+        .bind(pinned)
+        .fetch_optional(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        message.ok_or_else(|| AppError::NotFound("message id not found".to_owned()))
+    }
+
+    /// add or remove the caller's reaction to a message of the chat
+    pub async fn set_reaction(
+        &self,
+        chat_id: u64,
+        user_id: u64,
+        input: &ReactMessage,
+    ) -> Result<(), AppError> {
+        let emoji = input.emoji.trim();
+        if emoji.is_empty() || emoji.chars().count() > MAX_EMOJI_LEN {
+            return Err(AppError::InvalidInput("invalid emoji".to_string()));
+        }
+        let found = sqlx::query("SELECT 1 FROM messages WHERE chat_id = $1 AND id = $2")
+            .bind(chat_id as i64)
+            .bind(input.message_id as i64)
+            .fetch_optional(&self.pool)
+            .await?;
+        if found.is_none() {
+            return Err(AppError::NotFound("message id not found".to_owned()));
+        }
+
+        let query = if input.reacted {
+            r#"
+        INSERT INTO message_reactions (message_id, user_id, emoji)
+        VALUES ($1, $2, $3)
+        ON CONFLICT DO NOTHING
+        "#
+        } else {
+            "DELETE FROM message_reactions WHERE message_id = $1 AND user_id = $2 AND emoji = $3"
+        };
+        sqlx::query(query)
+            .bind(input.message_id as i64)
+            .bind(user_id as i64)
+            .bind(emoji)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// count of each reaction emoji per message, messages without reactions are left out
+    pub async fn reaction_summary(
+        &self,
+        message_ids: &[i64],
+    ) -> Result<HashMap<i64, BTreeMap<String, i64>>, AppError> {
+        let rows: Vec<(i64, String, i64)> = sqlx::query_as(
+            r#"
+        SELECT message_id, emoji, COUNT(*)
+        FROM message_reactions
+        WHERE message_id = ANY($1)
+        GROUP BY message_id, emoji
+        "#,
+        )
+        .bind(message_ids)
+        .fetch_all(&self.pool)
+        .await?;
+        let mut summary: HashMap<_, BTreeMap<_, _>> = HashMap::new();
+        for (message_id, emoji, count) in rows {
+            summary.entry(message_id).or_default().insert(emoji, count);
+        }
+        Ok(summary)
+    }
+
+    pub async fn list_pinned(&self, chat_id: u64) -> Result<Vec<Message>, AppError> {
+        let messages = sqlx::query_as(
+            r#"
+        SELECT id, chat_id, sender_id, content, files, attachments, pinned, forwarded_from, created_at
+        FROM messages
+        WHERE chat_id = $1 AND pinned
+        ORDER BY id DESC
+        "#,
+        )
+        .bind(chat_id as i64)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(messages)
+    }
+
+    /// delete uploaded files of the workspace which are not referenced anywhere
+    pub async fn delete_unused_files(
+        &self,
+        ws_id: u64,
+        urls: Vec<String>,
+    ) -> Result<Vec<DeleteFileResult>, AppError> {
+        let mut results = Vec::with_capacity(urls.len());
+        for url in urls {
+            let status = match ChatFile::from_str(&url) {
+                Err(_) => DeleteFileStatus::Invalid,
+                Ok(file) if file.ws_id != ws_id => DeleteFileStatus::PermissionDeny,
+                Ok(file) => {
+                    // references are stored relative, the input may be an absolute url
+                    if self.is_file_referenced(&file.url()).await? {
+                        DeleteFileStatus::Referenced
+                    } else if self.store.delete(&file).await? {
+                        DeleteFileStatus::Deleted
+                    } else {
+                        DeleteFileStatus::NotFound
+                    }
+                }
+            };
+            results.push(DeleteFileResult { url, status });
+        }
+        Ok(results)
+    }
+
+    /// delete stored files which are older than grace and not referenced anywhere,
+    /// returns the number of deleted files
+    pub async fn sweep_orphan_files(&self, grace: Duration) -> Result<usize, AppError> {
+        let before = SystemTime::now()
+            .checked_sub(grace)
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        let mut deleted = 0;
+        for file in self.store.list_before(before).await? {
+            if self.is_file_referenced(&file.url()).await? {
+                continue;
+            }
+            if self.store.delete(&file).await? {
+                deleted += 1;
+            }
+        }
+        Ok(deleted)
+    }
+
+    async fn is_file_referenced(&self, url: &str) -> Result<bool, AppError> {
+        let referenced: Option<(i32,)> = sqlx::query_as(
+            r#"
+        SELECT 1 WHERE EXISTS (SELECT 1 FROM messages WHERE $1 = ANY(files))
+        OR EXISTS (SELECT 1 FROM users WHERE avatar_url = $1)
+        "#,
+        )
+        .bind(url)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(referenced.is_some())
+    }
+
+    /// number of messages in the chat
+    pub async fn count(&self, chat_id: u64) -> Result<i64, AppError> {
+        let (count,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM messages WHERE chat_id = $1")
+            .bind(chat_id as i64)
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(count)
+    }
+
+    pub async fn fetch_sender_ids(&self, chat_id: u64) -> Result<Vec<i64>, AppError> {
+        let ids: Vec<(i64,)> = sqlx::query_as(
+            r#"
+        SELECT DISTINCT sender_id
+        FROM messages
+        WHERE chat_id = $1
+        "#,
+        )
+        .bind(chat_id as i64)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(ids.into_iter().map(|(id,)| id).collect())
+    }
+
+    /// all messages of the chat newest first, fetched a batch at a time as the stream is polled
+    pub fn stream_all(
+        &self,
+        chat_id: u64,
+    ) -> impl Stream<Item = Result<Message, AppError>> + Send + 'static {
+        let svc = self.clone();
+        stream::try_unfold(Some(i64::MAX as u64), move |last_id| {
+            let svc = svc.clone();
+            async move {
+                let Some(last_id) = last_id else {
+                    return Ok(None);
+                };
+                let input = ListMessageOption {
+                    last_id: Some(last_id),
+                    after_id: None,
+                    limit: EXPORT_BATCH_SIZE,
+                    before: None,
+                    after: None,
+                    with_sender: false,
+                    with_reactions: false,
+                };
+                let messages = svc.list(input, chat_id).await?;
+                let next = match messages.last() {
+                    Some(last) if messages.len() as u64 == EXPORT_BATCH_SIZE => {
+                        Some(last.id as u64)
+                    }
+                    _ => None,
+                };
+                let messages = stream::iter(messages.into_iter().map(Ok::<_, AppError>));
+                Ok::<_, AppError>(Some((messages, next)))
+            }
+        })
+        .try_flatten()
+    }
+
+    pub fn export(
+        &self,
+        chat_id: u64,
+        senders: HashMap<i64, String>,
+        format: ExportFormat,
+    ) -> impl Stream<Item = Result<String, AppError>> + Send + 'static {
+        let svc = self.clone();
+        let messages = stream::try_unfold(Some(0), move |after_id| {
+            let svc = svc.clone();
+            async move {
+                let Some(after_id) = after_id else {
+                    return Ok(None);
+                };
+                let messages = svc.list_after(chat_id, after_id, EXPORT_BATCH_SIZE).await?;
+                let next = match messages.last() {
+                    Some(last) if messages.len() as u64 == EXPORT_BATCH_SIZE => {
+                        Some(last.id as u64)
+                    }
+                    _ => None,
+                };
+                let messages = stream::iter(messages.into_iter().map(Ok::<_, AppError>));
+                Ok::<_, AppError>(Some((messages, next)))
+            }
+        })
+        .try_flatten();
+
+        let (head, tail) = match format {
+            ExportFormat::Json => ("[", "]"),
+            ExportFormat::Csv => ("id,sender,content,created_at\n", ""),
+        };
+        let rows = messages.enumerate().map(move |(i, msg)| {
+            let msg = msg?;
+            let sender = senders.get(&msg.sender_id).cloned().unwrap_or_default();
+            let row = match format {
+                ExportFormat::Json => {
+                    let msg = ExportMessage {
+                        id: msg.id,
+                        sender_id: msg.sender_id,
+                        sender,
+                        content: msg.content,
+                        files: msg.files,
+                        created_at: msg.created_at,
+                    };
+                    let sep = if i == 0 { "" } else { "," };
+                    let json = serde_json::to_string(&msg).map_err(anyhow::Error::from)?;
+                    format!("{sep}{json}")
+                }
+                ExportFormat::Csv => format!(
+                    "{},{},{},{}\n",
+                    msg.id,
+                    csv_escape(&sender),
+                    csv_escape(&msg.content),
+                    msg.created_at.to_rfc3339()
+                ),
+            };
+            Ok(row)
+        });
+
+        stream::once(future::ready(Ok(head.to_string())))
+            .chain(rows)
+            .chain(stream::once(future::ready(Ok(tail.to_string()))))
+    }
+}
+
+async fn insert_message<'e, E: PgExecutor<'e>>(
+    executor: E,
+    chat_id: u64,
+    user_id: u64,
+    msg: NewMessage,
+) -> Result<Message, AppError> {
+    Ok(sqlx::query_as(
+        r#"
+        INSERT INTO messages (chat_id, sender_id, content, files, attachments, forwarded_from)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        RETURNING id, chat_id, sender_id, content, files, attachments, pinned, forwarded_from, created_at
+        "#,
+    )
+    .bind(chat_id as i64)
+    .bind(user_id as i64)
+    .bind(msg.content)
+    .bind(msg.files)
+    .bind(Json(msg.attachments))
+    .bind(msg.forwarded_from)
+    .fetch_one(executor)
+    .await?)
+}
+
+/// the message found by an idempotency key, unless it was sent to another chat
+fn same_chat(message: Message, chat_id: u64) -> Result<Message, AppError> {
+    if message.chat_id != chat_id as i64 {
+        return Err(AppError::Conflict(
+            "idempotency key used in another chat".to_string(),
+        ));
+    }
+    Ok(message)
+}
+
+fn csv_escape(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
 }
 
 #[cfg(test)]
 impl CreateMessage {
     pub fn new(content: String, files: Vec<String>) -> Self {
-        Self { content, files }
+        Self {
+            content,
+            files,
+            attachments: vec![],
+        }
     }
 }
 
 #[cfg(test)]
 impl ListMessageOption {
     pub fn new(last_id: Option<u64>, limit: u64) -> Self {
-        Self { last_id, limit }
+        Self {
+            last_id,
+            after_id: None,
+            limit,
+            before: None,
+            after: None,
+            with_sender: false,
+            with_reactions: false,
+        }
+    }
+
+    pub fn with_range(mut self, after: DateTime<Utc>, before: DateTime<Utc>) -> Self {
+        self.after = Some(after);
+        self.before = Some(before);
+        self
     }
 }
 
@@ -109,7 +950,7 @@ mod tests {
     use std::path::Path;
 
     use super::*;
-    use crate::test_util::get_test_pool;
+    use crate::{store::LocalFileStore, test_util::get_test_pool};
     use anyhow::Result;
     use tempfile::tempdir;
 
@@ -117,7 +958,7 @@ mod tests {
     async fn create_message_should_work() {
         let (_tdb, pool) = get_test_pool(None).await;
         let basedir = tempdir().expect("create tempfile");
-        let svc = MsgService::new(pool, &basedir);
+        let svc = MsgService::new(pool, local_store(&basedir), MsgOptions::default());
         let url = upload_dummy_file(&basedir).expect("upload dummy file should work");
         let input = CreateMessage::new("hello world".to_string(), vec![url.to_owned()]);
         let message = svc.create(input, 1, 1).await.expect("create message fail");
@@ -125,11 +966,138 @@ mod tests {
         assert_eq!(message.files, vec![url]);
     }
 
+    #[tokio::test]
+    async fn forward_message_should_copy_it_to_target_chat() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let basedir = tempdir().expect("create tempfile");
+        let svc = MsgService::new(pool, local_store(&basedir), MsgOptions::default());
+        let url = upload_dummy_file(&basedir)?;
+        let input = CreateMessage::new("forward me".to_string(), vec![url.clone()]);
+        let source = svc.create(input, 1, 1).await?;
+
+        let message = svc.forward(1, source.id as _, 2, 2).await?;
+        assert_eq!(message.chat_id, 2);
+        assert_eq!(message.sender_id, 2);
+        assert_eq!(message.content, "forward me");
+        assert_eq!(message.files, vec![url]);
+        assert_eq!(message.forwarded_from, Some(source.id));
+        let latest = svc.list(ListMessageOption::new(None, 1), 2).await?;
+        assert_eq!(latest, vec![message]);
+
+        // user 4 is in chat 1 but not in chat 2
+        let err = svc.forward(1, source.id as _, 2, 4).await.unwrap_err();
+        assert!(matches!(err, AppError::PermissionDeny));
+        let err = svc.forward(1, i32::MAX as _, 2, 1).await.unwrap_err();
+        assert!(matches!(err, AppError::NotFound(_)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn create_message_with_only_files_should_work() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let basedir = tempdir().expect("create tempfile");
+        let svc = MsgService::new(pool, local_store(&basedir), MsgOptions::default());
+        let url = upload_dummy_file(&basedir)?;
+        let input = CreateMessage::new("".to_string(), vec![url.clone()]);
+        let message = svc.create(input, 1, 1).await?;
+        assert_eq!(message.content, "");
+        assert_eq!(message.files, vec![url]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn create_empty_message_should_fail() {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let basedir = tempdir().expect("create tempfile");
+        let svc = MsgService::new(pool, local_store(&basedir), MsgOptions::default());
+        let input = CreateMessage::new("".to_string(), vec![]);
+        let err = svc.create(input, 1, 1).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid input: content and files are both empty"
+        );
+    }
+
+    #[tokio::test]
+    async fn create_message_without_files_should_work() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let basedir = tempdir().expect("create tempfile");
+        let svc = MsgService::new(pool, local_store(&basedir), MsgOptions::default());
+        let input = CreateMessage::new("just text".to_string(), vec![]);
+        let message = svc.create(input, 1, 1).await?;
+        assert_eq!(message.content, "just text");
+        assert!(message.files.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn count_messages_should_work() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let basedir = tempdir().expect("create tempfile");
+        let svc = MsgService::new(pool, local_store(&basedir), MsgOptions::default());
+        assert_eq!(svc.count(1).await?, 10);
+        assert_eq!(svc.count(2).await?, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn create_message_with_attachments_should_work() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let basedir = tempdir().expect("create tempfile");
+        let svc = MsgService::new(pool, local_store(&basedir), MsgOptions::default());
+        let url = upload_dummy_file(&basedir)?;
+        let input = CreateMessage {
+            attachments: vec![Attachment {
+                url: url.clone(),
+                size: 5,
+                mime: "image/png".to_string(),
+                filename: "dummy.txt".to_string(),
+            }],
+            ..CreateMessage::new("with attachment".to_string(), vec![])
+        };
+        let message = svc.create(input, 1, 1).await?;
+        assert_eq!(message.files, vec![url.clone()]);
+        assert_eq!(message.attachments.len(), 1);
+        let attachment = &message.attachments[0];
+        assert_eq!(attachment.url, url);
+        assert_eq!(attachment.filename, "dummy.txt");
+        // the mime type comes from the file, not the client
+        assert_eq!(attachment.mime, "text/plain");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn create_message_should_notify() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let mut listener = sqlx::postgres::PgListener::connect_with(&pool).await?;
+        listener.listen("chat_message_created").await?;
+        let basedir = tempdir().expect("create tempfile");
+        let svc = MsgService::new(
+            pool,
+            local_store(basedir.into_path()),
+            MsgOptions::default(),
+        );
+        let input = CreateMessage::new("hello notify".to_string(), vec![]);
+        let message = svc.create(input, 4, 3).await?;
+
+        let notif =
+            tokio::time::timeout(std::time::Duration::from_secs(5), listener.recv()).await??;
+        let payload: serde_json::Value = serde_json::from_str(notif.payload())?;
+        assert_eq!(payload["message"]["id"], message.id);
+        assert_eq!(payload["message"]["content"], "hello notify");
+        assert_eq!(payload["members"], serde_json::json!([1, 3, 4]));
+        Ok(())
+    }
+
     #[tokio::test]
     async fn create_message_with_invalid_file_should_fail() {
         let (_tdb, pool) = get_test_pool(None).await;
         let basedir = tempdir().expect("create tempfile");
-        let svc = MsgService::new(pool, basedir.into_path());
+        let svc = MsgService::new(
+            pool,
+            local_store(basedir.into_path()),
+            MsgOptions::default(),
+        );
         let input = CreateMessage::new(
             "hello world".to_string(),
             vec!["invalid_file.txt".to_owned()],
@@ -138,11 +1106,352 @@ mod tests {
         assert_eq!(err.to_string(), "invalid input: file path");
     }
 
+    #[tokio::test]
+    async fn create_message_at_max_len_should_work() {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let basedir = tempdir().expect("create tempfile");
+        let opts = MsgOptions {
+            max_message_len: 5,
+            ..Default::default()
+        };
+        let svc = MsgService::new(pool, local_store(basedir.into_path()), opts);
+        let input = CreateMessage::new("你好世界!".to_string(), vec![]);
+        let message = svc.create(input, 1, 1).await.expect("create message fail");
+        assert_eq!(message.content, "你好世界!");
+    }
+
+    #[tokio::test]
+    async fn create_message_too_long_should_fail() {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let basedir = tempdir().expect("create tempfile");
+        let opts = MsgOptions {
+            max_message_len: 5,
+            ..Default::default()
+        };
+        let svc = MsgService::new(pool, local_store(basedir.into_path()), opts);
+        let input = CreateMessage::new("hello!".to_string(), vec![]);
+        let err = svc.create(input, 1, 1).await.unwrap_err();
+        assert_eq!(err.to_string(), "invalid input: message too long");
+    }
+
+    #[tokio::test]
+    async fn create_message_with_too_many_files_should_fail() {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let basedir = tempdir().expect("create tempfile");
+        let opts = MsgOptions {
+            max_files_per_message: 1,
+            ..Default::default()
+        };
+        let svc = MsgService::new(pool, local_store(&basedir), opts);
+        let url = upload_dummy_file(&basedir).expect("upload dummy file should work");
+        let input = CreateMessage::new("hello".to_string(), vec![url.clone(), url]);
+        let err = svc.create(input, 1, 1).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid input: too many files, at most 1 allowed"
+        );
+    }
+
+    #[tokio::test]
+    async fn create_message_with_other_workspace_file_should_fail() {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let basedir = tempdir().expect("create tempfile");
+        let svc = MsgService::new(pool, local_store(&basedir), MsgOptions::default());
+        let content = b"hello world";
+        let chat_file = ChatFile::new(2, "dummy.txt", content);
+        let file_path = chat_file.path(&basedir);
+        std::fs::create_dir_all(file_path.parent().unwrap()).unwrap();
+        std::fs::write(file_path, content).unwrap();
+
+        let url = chat_file.url();
+        let input = CreateMessage::new("hello".to_string(), vec![url.clone()]);
+        let err = svc.create(input, 1, 1).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("invalid input: file not in workspace: {}", url)
+        );
+    }
+
+    #[tokio::test]
+    async fn create_message_with_missing_file_should_name_it() {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let basedir = tempdir().expect("create tempfile");
+        let svc = MsgService::new(pool, local_store(&basedir), MsgOptions::default());
+        let url = ChatFile::new(1, "missing.txt", b"missing").url();
+        let input = CreateMessage::new("hello".to_string(), vec![url.clone()]);
+        let err = svc.create(input, 1, 1).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("invalid input: file not found: {}", url)
+        );
+    }
+
+    #[tokio::test]
+    async fn create_message_in_archived_chat_should_fail() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        sqlx::query("UPDATE chats SET archived_at = now() WHERE id = 1")
+            .execute(&pool)
+            .await?;
+        let basedir = tempdir().expect("create tempfile");
+        let svc = MsgService::new(
+            pool,
+            local_store(basedir.into_path()),
+            MsgOptions::default(),
+        );
+        let input = CreateMessage::new("hello".to_string(), vec![]);
+        let err = svc.create(input, 1, 1).await.unwrap_err();
+        assert_eq!(err.to_string(), "invalid input: chat is archived");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn create_message_by_non_member_should_fail() {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let basedir = tempdir().expect("create tempfile");
+        let svc = MsgService::new(pool, local_store(&basedir), MsgOptions::default());
+        // user 5 is not a member of the private channel
+        let input = CreateMessage::new("hello".to_string(), vec![]);
+        let err = svc.create(input.clone(), 2, 5).await.unwrap_err();
+        assert!(matches!(err, AppError::PermissionDeny));
+        let err = svc
+            .create_batch(vec![input.clone()], 2, 5)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AppError::PermissionDeny));
+        let err = svc.create(input, 100, 1).await.unwrap_err();
+        assert!(matches!(err, AppError::NotFound(_)));
+    }
+
+    #[tokio::test]
+    async fn pin_message_should_work() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let basedir = tempdir().expect("create tempfile");
+        let svc = MsgService::new(
+            pool,
+            local_store(basedir.into_path()),
+            MsgOptions::default(),
+        );
+
+        let message = svc.set_pinned(1, 2, true, 1).await?;
+        assert!(message.pinned);
+        svc.set_pinned(1, 5, true, 3).await?;
+
+        let pins = svc.list_pinned(1).await?;
+        let ids: Vec<_> = pins.iter().map(|m| m.id).collect();
+        assert_eq!(ids, vec![5, 2]);
+
+        svc.set_pinned(1, 2, false, 1).await?;
+        let pins = svc.list_pinned(1).await?;
+        assert_eq!(pins.len(), 1);
+
+        // user 5 is not a member of chat 2, message 1 is not in chat 2
+        assert!(matches!(
+            svc.set_pinned(2, 1, true, 5).await,
+            Err(AppError::PermissionDeny)
+        ));
+        assert!(matches!(
+            svc.set_pinned(2, 1, true, 1).await,
+            Err(AppError::NotFound(_))
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pin_message_over_cap_should_fail() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let basedir = tempdir().expect("create tempfile");
+        let opts = MsgOptions {
+            max_pins_per_chat: 2,
+            ..Default::default()
+        };
+        let svc = MsgService::new(pool, local_store(basedir.into_path()), opts);
+        svc.set_pinned(1, 1, true, 1).await?;
+        svc.set_pinned(1, 2, true, 1).await?;
+        // re-pinning an already pinned message is fine
+        svc.set_pinned(1, 2, true, 1).await?;
+        let err = svc.set_pinned(1, 3, true, 1).await.unwrap_err();
+        assert!(matches!(err, AppError::Conflict(_)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn concurrent_pins_should_not_exceed_cap() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let basedir = tempdir().expect("create tempfile");
+        let opts = MsgOptions {
+            max_pins_per_chat: 2,
+            ..Default::default()
+        };
+        let svc = MsgService::new(pool, local_store(basedir.into_path()), opts);
+        let results =
+            futures::future::join_all((1..=6).map(|id| svc.set_pinned(1, id, true, 1))).await;
+        assert_eq!(results.iter().filter(|ret| ret.is_ok()).count(), 2);
+        assert_eq!(svc.list_pinned(1).await?.len(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn delete_unused_files_should_work() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let basedir = tempdir().expect("create tempfile");
+        let svc = MsgService::new(pool, local_store(&basedir), MsgOptions::default());
+        let unused = upload_dummy_file(&basedir)?;
+        let used = {
+            let content = b"used file";
+            let file = ChatFile::new(1, "used.txt", content);
+            let path = file.path(&basedir);
+            std::fs::create_dir_all(path.parent().unwrap())?;
+            std::fs::write(path, content)?;
+            file.url()
+        };
+        let input = CreateMessage::new("with file".to_string(), vec![used.clone()]);
+        svc.create(input, 1, 1).await?;
+        let other_ws = ChatFile::new(2, "other.txt", b"other").url();
+
+        let results = svc
+            .delete_unused_files(
+                1,
+                vec![
+                    unused.clone(),
+                    used.clone(),
+                    other_ws,
+                    "/files/1/../../etc/passwd".to_string(),
+                ],
+            )
+            .await?;
+        let statuses: Vec<_> = results.iter().map(|r| r.status).collect();
+        assert_eq!(
+            statuses,
+            vec![
+                DeleteFileStatus::Deleted,
+                DeleteFileStatus::Referenced,
+                DeleteFileStatus::PermissionDeny,
+                DeleteFileStatus::Invalid,
+            ]
+        );
+        assert!(!ChatFile::from_str(&unused)?.path(&basedir).exists());
+        assert!(ChatFile::from_str(&used)?.path(&basedir).exists());
+
+        let results = svc.delete_unused_files(1, vec![unused]).await?;
+        assert_eq!(results[0].status, DeleteFileStatus::NotFound);
+
+        // absolute urls as returned by upload with a public base url
+        let absolute = ChatFile::from_str(&used)?.public_url(Some("https://chat.example.com"));
+        let results = svc.delete_unused_files(1, vec![absolute]).await?;
+        assert_eq!(results[0].status, DeleteFileStatus::Referenced);
+        assert!(ChatFile::from_str(&used)?.path(&basedir).exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sweep_orphan_files_should_work() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let basedir = tempdir().expect("create tempfile");
+        let svc = MsgService::new(pool, local_store(&basedir), MsgOptions::default());
+        let orphan = upload_dummy_file(&basedir)?;
+        let used = {
+            let content = b"used file";
+            let file = ChatFile::new(1, "used.txt", content);
+            let path = file.path(&basedir);
+            std::fs::create_dir_all(path.parent().unwrap())?;
+            std::fs::write(path, content)?;
+            file.url()
+        };
+        let input = CreateMessage::new("with file".to_string(), vec![used.clone()]);
+        svc.create(input, 1, 1).await?;
+
+        // fresh uploads are kept during the grace period
+        assert_eq!(svc.sweep_orphan_files(Duration::from_secs(3600)).await?, 0);
+        assert!(ChatFile::from_str(&orphan)?.path(&basedir).exists());
+
+        assert_eq!(svc.sweep_orphan_files(Duration::ZERO).await?, 1);
+        assert!(!ChatFile::from_str(&orphan)?.path(&basedir).exists());
+        assert!(ChatFile::from_str(&used)?.path(&basedir).exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn create_message_with_same_idempotency_key_should_dedupe() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let basedir = tempdir().expect("create tempfile");
+        let svc = MsgService::new(
+            pool.clone(),
+            local_store(basedir.into_path()),
+            MsgOptions::default(),
+        );
+        let input = CreateMessage::new("only once".to_string(), vec![]);
+        let first = svc.create_idempotent(input.clone(), 2, 1, "key-1").await?;
+        let second = svc.create_idempotent(input.clone(), 2, 1, "key-1").await?;
+        assert_eq!(first, second);
+
+        let (count,): (i64,) =
+            sqlx::query_as("SELECT count(*) FROM messages WHERE content = 'only once'")
+                .fetch_one(&pool)
+                .await?;
+        assert_eq!(count, 1);
+
+        let third = svc.create_idempotent(input.clone(), 2, 1, "key-2").await?;
+        assert_ne!(first.id, third.id);
+
+        // the key is taken by a message of chat 2
+        match svc.create_idempotent(input, 1, 1, "key-1").await {
+            Err(AppError::Conflict(_)) => {}
+            _ => panic!("reusing a key in another chat should conflict"),
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn create_message_batch_should_work() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let basedir = tempdir().expect("create tempfile");
+        let svc = MsgService::new(
+            pool,
+            local_store(basedir.into_path()),
+            MsgOptions::default(),
+        );
+        let inputs = ["one", "two", "three"]
+            .iter()
+            .map(|c| CreateMessage::new(c.to_string(), vec![]))
+            .collect();
+        let messages = svc.create_batch(inputs, 2, 1).await?;
+        let contents: Vec<_> = messages.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(contents, vec!["one", "two", "three"]);
+        assert!(messages.windows(2).all(|w| w[0].id < w[1].id));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn create_message_batch_with_invalid_file_should_rollback() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let basedir = tempdir().expect("create tempfile");
+        let svc = MsgService::new(
+            pool,
+            local_store(basedir.into_path()),
+            MsgOptions::default(),
+        );
+        let missing = ChatFile::new(1, "missing.txt", b"missing").url();
+        let inputs = vec![
+            CreateMessage::new("one".to_string(), vec![]),
+            CreateMessage::new("two".to_string(), vec![missing]),
+            CreateMessage::new("three".to_string(), vec![]),
+        ];
+        assert!(svc.create_batch(inputs, 2, 1).await.is_err());
+
+        let messages = svc.list(ListMessageOption::new(None, 10), 2).await?;
+        assert!(messages.is_empty());
+        Ok(())
+    }
+
     #[tokio::test]
     async fn list_message_should_work() {
         let (_tdb, pool) = get_test_pool(None).await;
         let basedir = tempdir().expect("create tempfile");
-        let svc = MsgService::new(pool, basedir.into_path());
+        let svc = MsgService::new(
+            pool,
+            local_store(basedir.into_path()),
+            MsgOptions::default(),
+        );
 
         let input = ListMessageOption::new(None, 6);
         let messages = svc.list(input, 1).await.expect("list fail");
@@ -155,6 +1464,132 @@ mod tests {
         assert_eq!(messages.len(), 4);
     }
 
+    #[tokio::test]
+    async fn list_message_after_should_work() {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let basedir = tempdir().expect("create tempfile");
+        let svc = MsgService::new(
+            pool,
+            local_store(basedir.into_path()),
+            MsgOptions::default(),
+        );
+
+        let messages = svc.list_after(1, 5, 3).await.expect("list after fail");
+        let ids: Vec<_> = messages.iter().map(|m| m.id).collect();
+        assert_eq!(ids, vec![6, 7, 8]);
+
+        let messages = svc.list_after(1, 8, 10).await.expect("list after fail");
+        let ids: Vec<_> = messages.iter().map(|m| m.id).collect();
+        assert_eq!(ids, vec![9, 10]);
+    }
+
+    #[tokio::test]
+    async fn list_message_in_time_range_should_work() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let basedir = tempdir().expect("create tempfile");
+        for (content, created_at) in [
+            ("jan", "2024-01-01T00:00:00Z"),
+            ("feb", "2024-02-01T00:00:00Z"),
+            ("mar", "2024-03-01T00:00:00Z"),
+        ] {
+            sqlx::query(
+                "INSERT INTO messages (chat_id, sender_id, content, created_at) VALUES (2, 1, $1, $2)",
+            )
+            .bind(content)
+            .bind(created_at.parse::<DateTime<Utc>>()?)
+            .execute(&pool)
+            .await?;
+        }
+        let svc = MsgService::new(
+            pool,
+            local_store(basedir.into_path()),
+            MsgOptions::default(),
+        );
+
+        let input = ListMessageOption::new(None, 10).with_range(
+            "2024-01-15T00:00:00Z".parse()?,
+            "2024-02-15T00:00:00Z".parse()?,
+        );
+        let messages = svc.list(input, 2).await?;
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].content, "feb");
+
+        let messages = svc
+            .list_after_in_range(
+                2,
+                0,
+                10,
+                Some("2024-02-15T00:00:00Z".parse()?),
+                Some("2024-01-15T00:00:00Z".parse()?),
+            )
+            .await?;
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].content, "feb");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stream_all_messages_should_work() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let basedir = tempdir().expect("create tempfile");
+        let svc = MsgService::new(pool, local_store(&basedir), MsgOptions::default());
+
+        let messages: Vec<Message> = svc.stream_all(1).try_collect().await?;
+        let ids: Vec<_> = messages.iter().map(|m| m.id).collect();
+        assert_eq!(ids, (1..=10).rev().collect::<Vec<_>>());
+        let messages: Vec<Message> = svc.stream_all(2).try_collect().await?;
+        assert!(messages.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn export_message_as_csv_should_work() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let basedir = tempdir().expect("create tempfile");
+        let svc = MsgService::new(
+            pool,
+            local_store(basedir.into_path()),
+            MsgOptions::default(),
+        );
+
+        let senders = HashMap::from([(1, "jack1".to_string()), (2, "jack2".to_string())]);
+        let rows: Vec<String> = svc
+            .export(1, senders, ExportFormat::Csv)
+            .try_collect()
+            .await?;
+        let csv = rows.concat();
+        let lines: Vec<_> = csv.lines().collect();
+        assert_eq!(lines.len(), 11);
+        assert_eq!(lines[0], "id,sender,content,created_at");
+        assert!(lines[1].starts_with("1,jack1,\"Hello, world!\","));
+        assert!(lines[2].starts_with("2,jack2,\"Hi, there!\","));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn export_message_as_json_should_work() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let basedir = tempdir().expect("create tempfile");
+        let svc = MsgService::new(
+            pool,
+            local_store(basedir.into_path()),
+            MsgOptions::default(),
+        );
+
+        let rows: Vec<String> = svc
+            .export(1, HashMap::new(), ExportFormat::Json)
+            .try_collect()
+            .await?;
+        let messages: Vec<ExportMessage> = serde_json::from_str(&rows.concat())?;
+        assert_eq!(messages.len(), 10);
+        assert_eq!(messages[0].id, 1);
+        Ok(())
+    }
+
+    fn local_store(base_dir: impl AsRef<Path>) -> Arc<dyn FileStore> {
+        Arc::new(LocalFileStore::new(base_dir))
+    }
+
     fn upload_dummy_file(base_dir: impl AsRef<Path>) -> Result<String> {
         let content = b"hello world";
         let chat_file = ChatFile::new(1, "dummy.txt", content);
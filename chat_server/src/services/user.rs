@@ -3,14 +3,19 @@ use std::{mem, sync::Arc};
 use crate::{error::AppError, models::ChatUser};
 use argon2::{
     password_hash::{rand_core::OsRng, PasswordHasher, SaltString},
-    Argon2, PasswordHash, PasswordVerifier,
+    Algorithm, Argon2, Params, PasswordHash, PasswordVerifier,
 };
 use chat_core::User;
 use serde::{Deserialize, Serialize};
 use sqlx::PgPool;
+use tracing::warn;
 use utoipa::ToSchema;
 
-use super::WsService;
+use super::{ChatOptions, WsService};
+
+const MAX_BATCH_USERS: usize = 100;
+pub(crate) const MAX_SEARCH_USERS: u64 = 20;
+const MAX_USERNAME_LEN: usize = 32;
 
 #[derive(Debug, Clone, ToSchema, Serialize, Deserialize, PartialEq)]
 pub struct CreateUser {
@@ -18,16 +23,44 @@ pub struct CreateUser {
     pub fullname: String,
     /// Email of the user
     pub email: String,
-    /// Workspace name - if not exists, create one
-    pub workspace: String,
+    /// Workspace name - if not exists, create one, the configured default when absent
+    #[serde(default)]
+    pub workspace: Option<String>,
     /// Password of the user
     pub password: String,
+    /// Invite code - join its workspace instead, the workspace name is ignored
+    #[serde(default)]
+    pub invite_code: Option<String>,
+    /// Username to sign in with instead of the email, without '@'
+    #[serde(default)]
+    pub username: Option<String>,
+}
+
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize, PartialEq)]
+pub struct BatchUsers {
+    /// user ids to look up
+    pub ids: Vec<i64>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct SearchUsers {
+    /// prefix of fullname or email
+    #[serde(default)]
+    pub q: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct UpdateProfile {
+    /// new full name, unchanged when absent
+    #[serde(default)]
+    pub fullname: Option<String>,
 }
 
 #[derive(Debug, Clone, ToSchema, Serialize, Deserialize, PartialEq)]
 pub struct SigninUser {
-    /// login email as username
-    pub email: String,
+    /// email or username, `email` is accepted as the field name as well
+    #[serde(alias = "email")]
+    pub identifier: String,
     /// password
     pub password: String,
 }
@@ -35,6 +68,13 @@ pub struct SigninUser {
 pub(crate) struct UserService {
     pool: PgPool,
     ws_svc: Arc<WsService>,
+    hasher: Argon2<'static>,
+    /// lowercased, anyone can sign up when empty
+    allowed_domains: Arc<Vec<String>>,
+    /// channel new users join
+    default_channel: Option<Arc<str>>,
+    /// new users don't join the default channel once it has this many members
+    max_chat_members: usize,
 }
 
 impl Clone for UserService {
@@ -42,21 +82,68 @@ impl Clone for UserService {
         Self {
             pool: self.pool.clone(),
             ws_svc: self.ws_svc.clone(),
+            hasher: self.hasher.clone(),
+            allowed_domains: self.allowed_domains.clone(),
+            default_channel: self.default_channel.clone(),
+            max_chat_members: self.max_chat_members,
         }
     }
 }
 
 impl UserService {
-    pub fn new(pool: PgPool, ws_svc: WsService) -> Self {
+    pub fn new(pool: PgPool, ws_svc: WsService, hasher: Argon2<'static>) -> Self {
         Self {
             pool,
             ws_svc: Arc::new(ws_svc),
+            hasher,
+            allowed_domains: Arc::new(vec![]),
+            default_channel: None,
+            max_chat_members: ChatOptions::default().max_chat_members,
+        }
+    }
+
+    /// only emails of these domains can sign up, anyone can when there are none
+    pub fn with_allowed_domains(mut self, domains: &[String]) -> Self {
+        let domains = domains
+            .iter()
+            .map(|v| v.trim().trim_start_matches('@').to_lowercase())
+            .filter(|v| !v.is_empty())
+            .collect();
+        self.allowed_domains = Arc::new(domains);
+        self
+    }
+
+    /// new users join the channel of this name in their workspace, if there is one
+    pub fn with_default_channel(mut self, name: Option<&str>) -> Self {
+        self.default_channel = name
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
+            .map(Into::into);
+        self
+    }
+
+    /// the member cap of chats, the default channel isn't joined when it's full
+    pub fn with_max_chat_members(mut self, max: usize) -> Self {
+        self.max_chat_members = max;
+        self
+    }
+
+    fn is_domain_allowed(&self, email: &str) -> bool {
+        if self.allowed_domains.is_empty() {
+            return true;
+        }
+        match email.rsplit_once('@') {
+            Some((_, domain)) => self
+                .allowed_domains
+                .iter()
+                .any(|v| v.eq_ignore_ascii_case(domain)),
+            None => false,
         }
     }
 
     pub async fn find_by_email(&self, email: &str) -> Result<Option<User>, AppError> {
         let user = sqlx::query_as(
-            "select id, ws_id, fullname, email, password_hash, created_at from users where email = $1",
+            "select id, ws_id, fullname, email, password_hash, token_version, created_at from users where email = $1",
         )
         .bind(email)
         .fetch_optional(&self.pool)
@@ -66,49 +153,137 @@ impl UserService {
     }
 
     pub async fn create(&self, input: &CreateUser) -> Result<User, AppError> {
+        if !self.is_domain_allowed(&input.email) {
+            return Err(AppError::InvalidInput(
+                "email domain not allowed".to_string(),
+            ));
+        }
         let user = self.find_by_email(&input.email).await?;
         if user.is_some() {
             return Err(AppError::EmailAlreadyExists(input.email.to_string()));
         }
-        let ws = match self.ws_svc.find_by_name(&input.workspace).await? {
-            Some(ws) => ws,
-            None => self.ws_svc.create(&input.workspace, 0).await?,
+        if let Some(username) = &input.username {
+            validate_username(username)?;
+            if self.username_exists(username).await? {
+                return Err(AppError::Conflict(format!(
+                    "username already exists: {}",
+                    username
+                )));
+            }
+        }
+        let ws = match &input.invite_code {
+            Some(code) => self
+                .ws_svc
+                .find_by_invite_code(code)
+                .await?
+                .ok_or_else(|| AppError::InvalidInput("invalid invite code".to_string()))?,
+            None => {
+                let name = input
+                    .workspace
+                    .as_deref()
+                    .filter(|name| !name.trim().is_empty())
+                    .ok_or_else(|| AppError::InvalidInput("workspace is required".to_string()))?;
+                match self.ws_svc.find_by_name(name).await? {
+                    Some(ws) => ws,
+                    None => self.ws_svc.create(name, 0).await?,
+                }
+            }
         };
-        let password_hash = hash_password(&input.password)?;
+        let password_hash = hash_password(&self.hasher, &input.password)?;
         let user: User = sqlx::query_as(
             r#"
-        insert into users (ws_id, email, fullname, password_hash)
-        values ($1, $2, $3, $4)
-        returning id, ws_id, fullname, email, created_at
+        insert into users (ws_id, email, fullname, password_hash, username)
+        values ($1, $2, $3, $4, $5)
+        returning id, ws_id, fullname, email, username, token_version, created_at
         "#,
         )
         .bind(ws.id)
         .bind(&input.email)
         .bind(&input.fullname)
         .bind(password_hash)
+        .bind(&input.username)
         .fetch_one(&self.pool)
         .await?;
 
         if ws.owner_id == 0 {
             ws.update_owner(user.id as _, &self.pool).await?;
         }
+        if let Some(name) = &self.default_channel {
+            self.join_default_channel(&user, name).await?;
+        }
         Ok(user)
     }
 
-    /// Verify email and password
+    /// add the user to the oldest channel of their workspace with the given name,
+    /// a full channel is skipped rather than failing the signup
+    async fn join_default_channel(&self, user: &User, name: &str) -> Result<(), AppError> {
+        sqlx::query(
+            r#"
+        UPDATE chats SET members = array_append(members, $2)
+        WHERE id = (
+            SELECT id FROM chats
+            WHERE ws_id = $1 AND name = $3
+            AND type IN ('public_channel', 'private_channel')
+            ORDER BY id
+            LIMIT 1
+        )
+        AND NOT $2 = ANY(members)
+        AND cardinality(members) < $4
+        "#,
+        )
+        .bind(user.ws_id)
+        .bind(user.id)
+        .bind(name)
+        .bind(self.max_chat_members as i32)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn rehash(&self, user_id: i64, password: &str) -> Result<(), AppError> {
+        let password_hash = hash_password(&self.hasher, password)?;
+        sqlx::query("UPDATE users SET password_hash = $1 WHERE id = $2")
+            .bind(password_hash)
+            .bind(user_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn username_exists(&self, username: &str) -> Result<bool, AppError> {
+        let found: Option<(i64,)> = sqlx::query_as("SELECT id FROM users WHERE username = $1")
+            .bind(username)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(found.is_some())
+    }
+
+    /// Verify email or username and password, an email match wins over a username match
     pub async fn verify(&self, input: &SigninUser) -> Result<Option<User>, AppError> {
         let user: Option<User> = sqlx::query_as(
-            "select id, ws_id, fullname, email, password_hash, created_at from users where email = $1",
+            r#"
+        select id, ws_id, fullname, email, username, password_hash, token_version, created_at
+        from users
+        where email = $1 or username = $1
+        order by email = $1 desc
+        limit 1
+        "#,
         )
-        .bind(&input.email)
+        .bind(&input.identifier)
         .fetch_optional(&self.pool)
         .await?;
 
         match user {
             Some(mut user) => {
                 let password_hash = mem::take(&mut user.password_hash).unwrap_or_default();
-                let is_valid = verify_password(&input.password, &password_hash)?;
+                let is_valid = verify_password(&self.hasher, &input.password, &password_hash)?;
                 if is_valid {
+                    // upgrade hashes made with outdated cost params while the password is at hand
+                    if needs_rehash(&self.hasher, &password_hash) {
+                        if let Err(e) = self.rehash(user.id, &input.password).await {
+                            warn!("rehash password of user {} failed: {}", user.id, e);
+                        }
+                    }
                     Ok(Some(user))
                 } else {
                     Ok(None)
@@ -118,14 +293,44 @@ impl UserService {
         }
     }
 
+    /// users with the given ids ordered by id
     pub async fn fetch_by_ids(&self, ids: &[i64]) -> Result<Vec<ChatUser>, AppError> {
         let users = sqlx::query_as(
             r#"
-        select id, fullname, email
+        select id, fullname, email, avatar_url
         from users
         where id = ANY($1)
+        order by id
+        "#,
+        )
+        .bind(ids)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(users)
+    }
+
+    /// fetch users by ids, only those belong to the given workspace are returned
+    pub async fn fetch_by_ids_in_ws(
+        &self,
+        ws_id: u64,
+        ids: &[i64],
+    ) -> Result<Vec<ChatUser>, AppError> {
+        if ids.len() > MAX_BATCH_USERS {
+            return Err(AppError::InvalidInput(format!(
+                "too many ids, at most {} allowed",
+                MAX_BATCH_USERS
+            )));
+        }
+        let users = sqlx::query_as(
+            r#"
+        select id, fullname, email, avatar_url
+        from users
+        where ws_id = $1 and id = ANY($2)
+        order by id
         "#,
         )
+        .bind(ws_id as i64)
         .bind(ids)
         .fetch_all(&self.pool)
         .await?;
@@ -133,13 +338,198 @@ impl UserService {
         Ok(users)
     }
 
+    /// number of the given users which belong to the workspace, takes any number of ids
+    pub async fn count_in_ws(&self, ws_id: u64, ids: &[i64]) -> Result<usize, AppError> {
+        let (count,): (i64,) =
+            sqlx::query_as("SELECT COUNT(*) FROM users WHERE ws_id = $1 AND id = ANY($2)")
+                .bind(ws_id as i64)
+                .bind(ids)
+                .fetch_one(&self.pool)
+                .await?;
+        Ok(count as usize)
+    }
+
+    /// current token version of the user, None if the user doesn't exist
+    pub async fn token_version(&self, id: u64) -> Result<Option<i32>, AppError> {
+        let version: Option<(i32,)> =
+            sqlx::query_as("select token_version from users where id = $1")
+                .bind(id as i64)
+                .fetch_optional(&self.pool)
+                .await?;
+        Ok(version.map(|(v,)| v))
+    }
+
+    /// revoke all issued tokens of the user by bumping the token version
+    pub async fn revoke_tokens(&self, id: u64) -> Result<i32, AppError> {
+        let version: Option<(i32,)> = sqlx::query_as(
+            r#"
+        update users
+        set token_version = token_version + 1
+        where id = $1
+        returning token_version
+        "#,
+        )
+        .bind(id as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+        version
+            .map(|(v,)| v)
+            .ok_or_else(|| AppError::NotFound("user id not found".to_owned()))
+    }
+
+    /// search users of the workspace whose fullname or email starts with prefix, case-insensitive
+    pub async fn search(
+        &self,
+        ws_id: u64,
+        prefix: &str,
+        limit: u64,
+    ) -> Result<Vec<ChatUser>, AppError> {
+        let prefix = prefix.trim();
+        if prefix.is_empty() {
+            return Ok(vec![]);
+        }
+        let pattern = format!(
+            "{}%",
+            prefix
+                .replace('\\', "\\\\")
+                .replace('%', "\\%")
+                .replace('_', "\\_")
+        );
+        let users = sqlx::query_as(
+            r#"
+        select id, fullname, email, avatar_url
+        from users
+        where ws_id = $1 and (fullname ILIKE $2 or email ILIKE $2)
+        order by id
+        limit $3
+        "#,
+        )
+        .bind(ws_id as i64)
+        .bind(pattern)
+        .bind(limit as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(users)
+    }
+
+    pub async fn find_chat_user_by_id(&self, id: u64) -> Result<Option<ChatUser>, AppError> {
+        let user = sqlx::query_as(
+            r#"
+        select id, fullname, email, avatar_url
+        from users
+        where id = $1
+        "#,
+        )
+        .bind(id as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(user)
+    }
+
+    pub async fn update_avatar(&self, id: u64, url: &str) -> Result<ChatUser, AppError> {
+        let user = sqlx::query_as(
+            r#"
+        update users
+        set avatar_url = $1, updated_at = now()
+        where id = $2
+        returning id, fullname, email, avatar_url
+        "#,
+        )
+        .bind(url)
+        .bind(id as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        user.ok_or_else(|| AppError::NotFound("user id not found".to_owned()))
+    }
+
+    /// update the profile fields that are given, the fullname is trimmed and can't be empty
+    pub async fn update_profile(
+        &self,
+        user_id: u64,
+        fullname: Option<String>,
+    ) -> Result<ChatUser, AppError> {
+        let Some(fullname) = fullname else {
+            return self
+                .find_chat_user_by_id(user_id)
+                .await?
+                .ok_or_else(|| AppError::NotFound("user id not found".to_owned()));
+        };
+        let fullname = fullname.trim();
+        if fullname.is_empty() {
+            return Err(AppError::InvalidInput("fullname is empty".to_string()));
+        }
+        let user = sqlx::query_as(
+            r#"
+        update users
+        set fullname = $1, updated_at = now()
+        where id = $2
+        returning id, fullname, email, avatar_url
+        "#,
+        )
+        .bind(fullname)
+        .bind(user_id as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        user.ok_or_else(|| AppError::NotFound("user id not found".to_owned()))
+    }
+
+    /// delete a user, only owners and admins of the user's workspace can do it. The user is removed
+    /// from all chats of the workspace, the workspace owner itself can't be deleted
+    pub async fn delete(&self, user_id: u64, caller_id: u64) -> Result<(), AppError> {
+        let target: Option<(i64, i64)> = sqlx::query_as(
+            r#"
+        select u.ws_id, w.owner_id
+        from users u
+        join workspaces w on w.id = u.ws_id
+        where u.id = $1
+        "#,
+        )
+        .bind(user_id as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+        let Some((ws_id, owner_id)) = target else {
+            return Err(AppError::NotFound("user id not found".to_owned()));
+        };
+        self.ws_svc.require_admin(ws_id as _, caller_id).await?;
+        if owner_id == user_id as i64 {
+            return Err(AppError::InvalidInput(
+                "workspace owner can't be deleted".to_string(),
+            ));
+        }
+
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            r#"
+        update chats
+        set members = array_remove(members, $1)
+        where ws_id = $2 and $1 = ANY(members)
+        "#,
+        )
+        .bind(user_id as i64)
+        .bind(ws_id)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query("delete from users where id = $1")
+            .bind(user_id as i64)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// users of the workspace ordered by id
     #[allow(dead_code)]
     pub async fn fetch_all(&self, ws_id: u64) -> Result<Vec<ChatUser>, AppError> {
         let users = sqlx::query_as(
             r#"
-        select id, fullname, email
+        select id, fullname, email, avatar_url
         from users
         where ws_id = $1
+        order by id
         "#,
         )
         .bind(ws_id as i64)
@@ -150,17 +540,27 @@ impl UserService {
     }
 }
 
-fn hash_password(password: &str) -> Result<String, AppError> {
+/// usernames can't contain '@', so they never look like an email
+fn validate_username(username: &str) -> Result<(), AppError> {
+    if username.is_empty() || username.chars().count() > MAX_USERNAME_LEN || username.contains('@')
+    {
+        return Err(AppError::InvalidInput(format!(
+            "username must be 1 to {} chars without '@'",
+            MAX_USERNAME_LEN
+        )));
+    }
+    Ok(())
+}
+
+fn hash_password(hasher: &Argon2, password: &str) -> Result<String, AppError> {
     let salt = SaltString::generate(&mut OsRng);
-    let hasher = Argon2::default();
     let password_hash = hasher
         .hash_password(password.as_bytes(), &salt)?
         .to_string();
     Ok(password_hash)
 }
 
-fn verify_password(password: &str, password_hash: &str) -> Result<bool, AppError> {
-    let hasher = Argon2::default();
+fn verify_password(hasher: &Argon2, password: &str, password_hash: &str) -> Result<bool, AppError> {
     let password_hash = PasswordHash::new(password_hash)?;
 
     let is_valid = hasher
@@ -169,23 +569,51 @@ fn verify_password(password: &str, password_hash: &str) -> Result<bool, AppError
     Ok(is_valid)
 }
 
+/// the hash was not made by argon2id with the hasher's cost params, e.g. before they were raised
+fn needs_rehash(hasher: &Argon2, password_hash: &str) -> bool {
+    let Ok(hash) = PasswordHash::new(password_hash) else {
+        return false;
+    };
+    if hash.algorithm != Algorithm::Argon2id.ident() {
+        return true;
+    }
+    let current = hasher.params();
+    match Params::try_from(&hash) {
+        Ok(params) => {
+            params.m_cost() != current.m_cost()
+                || params.t_cost() != current.t_cost()
+                || params.p_cost() != current.p_cost()
+        }
+        Err(_) => true,
+    }
+}
+
 #[cfg(test)]
 impl CreateUser {
     pub fn new(ws: &str, fullname: &str, email: &str, password: &str) -> Self {
         Self {
             fullname: fullname.to_string(),
-            workspace: ws.to_owned(),
+            workspace: Some(ws.to_owned()),
             email: email.to_string(),
             password: password.to_string(),
+            invite_code: None,
+            username: None,
         }
     }
 }
 
+#[cfg(test)]
+impl BatchUsers {
+    pub fn new(ids: &[i64]) -> Self {
+        Self { ids: ids.to_vec() }
+    }
+}
+
 #[cfg(test)]
 impl SigninUser {
-    pub fn new(email: &str, password: &str) -> Self {
+    pub fn new(identifier: &str, password: &str) -> Self {
         Self {
-            email: email.to_string(),
+            identifier: identifier.to_string(),
             password: password.to_string(),
         }
     }
@@ -193,24 +621,83 @@ impl SigninUser {
 
 #[cfg(test)]
 mod tests {
-    use crate::test_util::get_test_pool;
+    use crate::{config::Argon2Config, test_util::get_test_pool};
 
     use super::*;
     use anyhow::Result;
 
     #[test]
     fn hash_password_and_verify_should_work() -> Result<()> {
+        let hasher = Argon2::default();
         let password = "123456";
-        let password_hash = hash_password(password)?;
+        let password_hash = hash_password(&hasher, password)?;
         assert_eq!(password_hash.len(), 97);
-        assert!(verify_password(password, &password_hash)?);
+        assert!(verify_password(&hasher, password, &password_hash)?);
+        Ok(())
+    }
+
+    #[test]
+    fn hash_password_with_custom_cost_should_work() -> Result<()> {
+        let config = Argon2Config {
+            m_cost: 8192,
+            t_cost: 1,
+            p_cost: 1,
+        };
+        let hasher = config.hasher()?;
+        let password = "123456";
+        let password_hash = hash_password(&hasher, password)?;
+        assert!(password_hash.contains("m=8192,t=1,p=1"));
+        assert!(verify_password(&hasher, password, &password_hash)?);
+        assert!(!verify_password(&hasher, "654321", &password_hash)?);
         Ok(())
     }
+    #[tokio::test]
+    async fn verify_should_rehash_outdated_password() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let weak = Argon2Config {
+            m_cost: 8192,
+            t_cost: 1,
+            p_cost: 1,
+        };
+        let ws_svc = WsService::new(pool.clone());
+        let svc = UserService::new(pool.clone(), ws_svc.clone(), weak.hasher()?);
+        let input = CreateUser::new("none", "Tyr Chen", "tchen@acme.org", "Hunter42");
+        let user = svc.create(&input).await?;
+        let stored_hash = |id: i64| {
+            let pool = pool.clone();
+            async move {
+                let (hash,): (String,) =
+                    sqlx::query_as("SELECT password_hash FROM users WHERE id = $1")
+                        .bind(id)
+                        .fetch_one(&pool)
+                        .await?;
+                Ok::<_, anyhow::Error>(hash)
+            }
+        };
+        assert!(stored_hash(user.id).await?.contains("m=8192,t=1,p=1"));
+
+        let svc = UserService::new(pool.clone(), ws_svc, Argon2Config::default().hasher()?);
+        let signin = SigninUser::new(&input.email, &input.password);
+        assert!(svc
+            .verify(&SigninUser::new(&input.email, "wrong"))
+            .await?
+            .is_none());
+        assert!(stored_hash(user.id).await?.contains("m=8192,t=1,p=1"));
+
+        assert!(svc.verify(&signin).await?.is_some());
+        let hash = stored_hash(user.id).await?;
+        assert!(!needs_rehash(&Argon2Config::default().hasher()?, &hash));
+        assert!(hash.contains(&format!("m={}", Params::DEFAULT_M_COST)));
+        // the upgraded hash still verifies
+        assert!(svc.verify(&signin).await?.is_some());
+        Ok(())
+    }
+
     #[tokio::test]
     async fn create_duplicate_user_should_fail() -> Result<()> {
         let (_tdb, pool) = get_test_pool(None).await;
         let ws_svc = WsService::new(pool.clone());
-        let svc = UserService::new(pool, ws_svc);
+        let svc = UserService::new(pool, ws_svc, Argon2::default());
         let input = CreateUser::new("none", "jack1", "jack1@gmail.com", "123456");
         match svc.create(&input).await {
             Err(AppError::EmailAlreadyExists(email)) => {
@@ -221,11 +708,118 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn create_user_with_allowed_domain_should_work() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let ws_svc = WsService::new(pool.clone());
+        let svc = UserService::new(pool, ws_svc, Argon2::default())
+            .with_allowed_domains(&["Acme.com".to_string()]);
+        let input = CreateUser::new("none", "Tyr Chen", "tchen@acme.COM", "hunter42");
+        let user = svc.create(&input).await?;
+        assert_eq!(user.email, input.email);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn create_user_with_disallowed_domain_should_fail() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let ws_svc = WsService::new(pool.clone());
+        let svc = UserService::new(pool, ws_svc, Argon2::default())
+            .with_allowed_domains(&["acme.com".to_string()]);
+        for email in ["tchen@gmail.com", "tchen@notacme.com", "tchen"] {
+            let input = CreateUser::new("none", "Tyr Chen", email, "hunter42");
+            match svc.create(&input).await {
+                Err(AppError::InvalidInput(msg)) => assert_eq!(msg, "email domain not allowed"),
+                _ => panic!("{} should not be allowed to sign up", email),
+            }
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn create_user_should_join_default_channel() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let ws_svc = WsService::new(pool.clone());
+        let svc = UserService::new(pool.clone(), ws_svc, Argon2::default())
+            .with_default_channel(Some("general"));
+        let input = CreateUser::new("ws1", "Tyr Chen", "tchen@acme.org", "hunter42");
+        let user = svc.create(&input).await?;
+        let (members,): (Vec<i64>,) =
+            sqlx::query_as("SELECT members FROM chats WHERE ws_id = 1 AND name = 'general'")
+                .fetch_one(&pool)
+                .await?;
+        assert_eq!(members, vec![1, 2, 3, 4, 5, user.id]);
+
+        // a workspace without the channel is fine
+        let input = CreateUser::new("ws2", "Alice Chen", "alice@acme.org", "hunter42");
+        svc.create(&input).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn create_user_should_not_join_full_default_channel() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let ws_svc = WsService::new(pool.clone());
+        let svc = UserService::new(pool.clone(), ws_svc, Argon2::default())
+            .with_default_channel(Some("general"))
+            .with_max_chat_members(5);
+        let input = CreateUser::new("ws1", "Tyr Chen", "tchen@acme.org", "hunter42");
+        svc.create(&input).await?;
+        let (members,): (Vec<i64>,) =
+            sqlx::query_as("SELECT members FROM chats WHERE ws_id = 1 AND name = 'general'")
+                .fetch_one(&pool)
+                .await?;
+        assert_eq!(members, vec![1, 2, 3, 4, 5]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn signin_with_username_should_work() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let ws_svc = WsService::new(pool.clone());
+        let svc = UserService::new(pool, ws_svc, Argon2::default());
+        let input = CreateUser {
+            username: Some("tchen".to_string()),
+            ..CreateUser::new("acme", "Tyr Chen", "tchen@acme.org", "Hunter42")
+        };
+        let user = svc.create(&input).await?;
+        assert_eq!(user.username.as_deref(), Some("tchen"));
+
+        let signed_in = svc.verify(&SigninUser::new("tchen", "Hunter42")).await?;
+        assert_eq!(signed_in.map(|u| u.id), Some(user.id));
+        let signed_in = svc
+            .verify(&SigninUser::new("tchen@acme.org", "Hunter42"))
+            .await?;
+        assert_eq!(signed_in.map(|u| u.id), Some(user.id));
+        assert!(svc
+            .verify(&SigninUser::new("tchen", "wrong"))
+            .await?
+            .is_none());
+
+        let input = CreateUser {
+            username: Some("tchen".to_string()),
+            ..CreateUser::new("acme", "Other", "other@acme.org", "Hunter42")
+        };
+        assert!(matches!(
+            svc.create(&input).await,
+            Err(AppError::Conflict(_))
+        ));
+        let input = CreateUser {
+            username: Some("a@b".to_string()),
+            ..CreateUser::new("acme", "Other", "other@acme.org", "Hunter42")
+        };
+        assert!(matches!(
+            svc.create(&input).await,
+            Err(AppError::InvalidInput(_))
+        ));
+        Ok(())
+    }
+
     #[tokio::test]
     async fn create_and_verify_user_should_work() -> Result<()> {
         let (_tdb, pool) = get_test_pool(None).await;
         let ws_svc = WsService::new(pool.clone());
-        let svc = UserService::new(pool, ws_svc);
+        let svc = UserService::new(pool, ws_svc, Argon2::default());
         let input = CreateUser::new("none", "jack", "jack@admin", "123456");
         let user = svc.create(&input).await?;
         assert_eq!(user.email, input.email);
@@ -244,4 +838,140 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn fetch_by_ids_in_ws_should_exclude_other_workspace() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let ws_svc = WsService::new(pool.clone());
+        let svc = UserService::new(pool, ws_svc, Argon2::default());
+        let input = CreateUser::new("ws2", "tom", "tom@gmail.com", "Hunter42");
+        let other = svc.create(&input).await?;
+
+        let users = svc.fetch_by_ids_in_ws(1, &[1, 2, 3, other.id]).await?;
+        assert_eq!(users.len(), 3);
+        assert!(users.iter().all(|u| u.id != other.id));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fetch_by_ids_in_ws_with_too_many_ids_should_fail() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let ws_svc = WsService::new(pool.clone());
+        let svc = UserService::new(pool, ws_svc, Argon2::default());
+        let ids: Vec<i64> = (1..=101).collect();
+        match svc.fetch_by_ids_in_ws(1, &ids).await {
+            Err(AppError::InvalidInput(_)) => {}
+            _ => panic!("should return InvalidInput"),
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn search_users_should_work() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let ws_svc = WsService::new(pool.clone());
+        let svc = UserService::new(pool, ws_svc, Argon2::default());
+        let input = CreateUser::new("ws2", "jackie", "jackie@gmail.com", "Hunter42");
+        let other = svc.create(&input).await?;
+
+        let users = svc.search(1, "JACK", MAX_SEARCH_USERS).await?;
+        assert_eq!(users.len(), 5);
+        assert!(users.iter().all(|u| u.id != other.id));
+
+        let users = svc.search(1, "jack3@", MAX_SEARCH_USERS).await?;
+        assert_eq!(users.len(), 1);
+        assert_eq!(users[0].fullname, "jack3");
+
+        assert!(svc.search(1, "", MAX_SEARCH_USERS).await?.is_empty());
+        assert!(svc.search(1, "%", MAX_SEARCH_USERS).await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn update_profile_should_trim_and_reject_empty() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let ws_svc = WsService::new(pool.clone());
+        let svc = UserService::new(pool, ws_svc, Argon2::default());
+        let user = svc
+            .update_profile(1, Some("  Jack Ma ".to_string()))
+            .await?;
+        assert_eq!(user.fullname, "Jack Ma");
+        let user = svc.update_profile(1, None).await?;
+        assert_eq!(user.fullname, "Jack Ma");
+        assert!(matches!(
+            svc.update_profile(1, Some("   ".to_string())).await,
+            Err(AppError::InvalidInput(_))
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn update_avatar_should_work() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let ws_svc = WsService::new(pool.clone());
+        let svc = UserService::new(pool, ws_svc, Argon2::default());
+        let url = "/files/1/2aa/e6c/35c94fcfb415dbe95f408b9ce91ee846ed.png";
+        let user = svc.update_avatar(1, url).await?;
+        assert_eq!(user.avatar_url.as_deref(), Some(url));
+
+        let user = svc.find_chat_user_by_id(1).await?.unwrap();
+        assert_eq!(user.avatar_url.as_deref(), Some(url));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn delete_user_should_work() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        sqlx::query("update workspaces set owner_id = 1 where id = 1")
+            .execute(&pool)
+            .await?;
+        let ws_svc = WsService::new(pool.clone());
+        let svc = UserService::new(pool.clone(), ws_svc, Argon2::default());
+
+        svc.delete(2, 1).await?;
+
+        let users = svc.fetch_all(1).await?;
+        assert_eq!(users.len(), 4);
+        assert!(users.iter().all(|u| u.id != 2));
+        let (count,): (i64,) =
+            sqlx::query_as("select count(*) from chats where ws_id = 1 and 2 = ANY(members)")
+                .fetch_one(&pool)
+                .await?;
+        assert_eq!(count, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn delete_user_by_admin_should_work() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        sqlx::query("update users set role = 'admin' where id = 2")
+            .execute(&pool)
+            .await?;
+        let ws_svc = WsService::new(pool.clone());
+        let svc = UserService::new(pool, ws_svc, Argon2::default());
+
+        svc.delete(3, 2).await?;
+        assert!(svc.find_chat_user_by_id(3).await?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn delete_user_by_non_owner_or_owner_itself_should_fail() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        sqlx::query("update workspaces set owner_id = 1 where id = 1")
+            .execute(&pool)
+            .await?;
+        let ws_svc = WsService::new(pool.clone());
+        let svc = UserService::new(pool, ws_svc, Argon2::default());
+
+        assert!(matches!(
+            svc.delete(3, 2).await,
+            Err(AppError::PermissionDeny)
+        ));
+        assert!(matches!(
+            svc.delete(1, 1).await,
+            Err(AppError::InvalidInput(_))
+        ));
+        Ok(())
+    }
 }
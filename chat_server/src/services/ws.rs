@@ -1,10 +1,52 @@
-use sqlx::PgPool;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool};
+use uuid::Uuid;
 
 use crate::{
     error::AppError,
-    models::{ChatUser, Workspace},
+    models::{ChatUser, Workspace, WorkspaceRole},
 };
 
+const DEFAULT_USERS_LIMIT: u64 = 100;
+const MAX_USERS_LIMIT: u64 = 500;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ListUsersOption {
+    /// walk forward from this id (exclusive)
+    pub last_id: Option<u64>,
+    /// page size, 100 by default and at most 500
+    pub limit: Option<u64>,
+    /// only users with this role
+    pub role: Option<WorkspaceRole>,
+    /// only users created or updated after this time
+    pub since: Option<DateTime<Utc>>,
+}
+
+impl ListUsersOption {
+    pub fn limit(&self) -> u64 {
+        self.limit
+            .unwrap_or(DEFAULT_USERS_LIMIT)
+            .clamp(1, MAX_USERS_LIMIT)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct UpdateRole {
+    pub role: WorkspaceRole,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct MemberRole {
+    pub user_id: i64,
+    pub role: WorkspaceRole,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct WorkspaceInvite {
+    pub code: String,
+}
+
 pub(crate) struct WsService {
     pool: PgPool,
 }
@@ -38,12 +80,13 @@ impl WsService {
         Ok(ws)
     }
 
+    /// names are compared case-insensitively
     pub async fn find_by_name(&self, name: &str) -> Result<Option<Workspace>, AppError> {
         let ws = sqlx::query_as(
             r#"
         SELECT id, name, owner_id, created_at
         FROM workspaces
-        WHERE name = $1
+        WHERE LOWER(name) = LOWER($1)
         "#,
         )
         .bind(name)
@@ -53,7 +96,6 @@ impl WsService {
         Ok(ws)
     }
 
-    #[allow(dead_code)]
     pub async fn find_by_id(&self, id: u64) -> Result<Option<Workspace>, AppError> {
         let ws = sqlx::query_as(
             r#"
@@ -69,11 +111,198 @@ impl WsService {
         Ok(ws)
     }
 
+    /// invite code of the workspace, minted on first use
+    pub async fn invite_code(&self, ws_id: u64) -> Result<String, AppError> {
+        let (code,): (String,) = sqlx::query_as(
+            r#"
+        UPDATE workspaces
+        SET invite_code = COALESCE(invite_code, $2)
+        WHERE id = $1
+        RETURNING invite_code
+        "#,
+        )
+        .bind(ws_id as i64)
+        .bind(new_invite_code())
+        .fetch_optional(&self.pool)
+        .await?
+        .ok_or_else(|| AppError::NotFound("workspace not found".to_string()))?;
+
+        Ok(code)
+    }
+
+    /// replace the invite code, the old one stops working at once, only the owner is allowed
+    pub async fn rotate_invite(&self, ws_id: u64, caller_id: u64) -> Result<String, AppError> {
+        let ws = self
+            .find_by_id(ws_id)
+            .await?
+            .ok_or_else(|| AppError::NotFound("workspace not found".to_string()))?;
+        if ws.owner_id as u64 != caller_id {
+            return Err(AppError::PermissionDeny);
+        }
+        let (code,): (String,) = sqlx::query_as(
+            r#"
+        UPDATE workspaces
+        SET invite_code = $2
+        WHERE id = $1
+        RETURNING invite_code
+        "#,
+        )
+        .bind(ws_id as i64)
+        .bind(new_invite_code())
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(code)
+    }
+
+    /// role of the user in the workspace, none if the user isn't in it
+    ///
+    /// the workspace owner is always `Owner`, whatever is stored on the user
+    pub async fn role_of(
+        &self,
+        ws_id: u64,
+        user_id: u64,
+    ) -> Result<Option<WorkspaceRole>, AppError> {
+        let role: Option<(WorkspaceRole,)> = sqlx::query_as(
+            r#"
+        SELECT CASE WHEN w.owner_id = u.id THEN 'owner'::workspace_role ELSE u.role END
+        FROM users u
+        JOIN workspaces w ON w.id = u.ws_id
+        WHERE u.ws_id = $1 AND u.id = $2
+        "#,
+        )
+        .bind(ws_id as i64)
+        .bind(user_id as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(role.map(|(role,)| role))
+    }
+
+    /// fails with `PermissionDeny` unless the user is an owner or admin of the workspace
+    pub async fn require_admin(&self, ws_id: u64, user_id: u64) -> Result<(), AppError> {
+        match self.role_of(ws_id, user_id).await? {
+            Some(role) if role.is_admin() => Ok(()),
+            _ => Err(AppError::PermissionDeny),
+        }
+    }
+
+    /// change the role of a member, only the owner is allowed and ownership can't be handed out
+    pub async fn set_role(
+        &self,
+        ws_id: u64,
+        caller_id: u64,
+        user_id: u64,
+        role: WorkspaceRole,
+    ) -> Result<MemberRole, AppError> {
+        if self.role_of(ws_id, caller_id).await? != Some(WorkspaceRole::Owner) {
+            return Err(AppError::PermissionDeny);
+        }
+        match self.role_of(ws_id, user_id).await? {
+            None => return Err(AppError::NotFound("user id not found".to_owned())),
+            Some(WorkspaceRole::Owner) => {
+                return Err(AppError::InvalidInput(
+                    "role of the owner can't be changed".to_string(),
+                ))
+            }
+            Some(_) => {}
+        }
+        if role == WorkspaceRole::Owner {
+            return Err(AppError::InvalidInput(
+                "owner role can't be assigned".to_string(),
+            ));
+        }
+        let member = sqlx::query_as(
+            r#"
+        UPDATE users
+        SET role = $3, updated_at = now()
+        WHERE ws_id = $1 AND id = $2
+        RETURNING id AS user_id, role
+        "#,
+        )
+        .bind(ws_id as i64)
+        .bind(user_id as i64)
+        .bind(role)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(member)
+    }
+
+    pub async fn find_by_invite_code(&self, code: &str) -> Result<Option<Workspace>, AppError> {
+        let ws = sqlx::query_as(
+            r#"
+        SELECT id, name, owner_id, created_at
+        FROM workspaces
+        WHERE invite_code = $1
+        "#,
+        )
+        .bind(code)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(ws)
+    }
+
+    /// users of the workspace ordered by id, a page at a time, optionally only with `role`
+    pub async fn fetch_chat_users(
+        &self,
+        ws_id: u64,
+        last_id: Option<u64>,
+        limit: u64,
+        role: Option<WorkspaceRole>,
+    ) -> Result<Vec<ChatUser>, AppError> {
+        self.query_chat_users(ws_id, None, last_id, limit, role)
+            .await
+    }
+
+    /// like `fetch_chat_users`, but only users created or updated after `since`
+    pub async fn fetch_chat_users_since(
+        &self,
+        ws_id: u64,
+        since: DateTime<Utc>,
+        last_id: Option<u64>,
+        limit: u64,
+        role: Option<WorkspaceRole>,
+    ) -> Result<Vec<ChatUser>, AppError> {
+        self.query_chat_users(ws_id, Some(since), last_id, limit, role)
+            .await
+    }
+
+    async fn query_chat_users(
+        &self,
+        ws_id: u64,
+        since: Option<DateTime<Utc>>,
+        last_id: Option<u64>,
+        limit: u64,
+        role: Option<WorkspaceRole>,
+    ) -> Result<Vec<ChatUser>, AppError> {
+        let users = sqlx::query_as(
+            r#"
+        SELECT id, fullname, email, avatar_url
+        FROM users
+        WHERE ws_id = $1 AND id > $2 AND ($4::workspace_role IS NULL OR role = $4)
+        AND ($5::timestamptz IS NULL OR updated_at > $5)
+        ORDER BY id
+        LIMIT $3
+        "#,
+        )
+        .bind(ws_id as i64)
+        .bind(last_id.unwrap_or(0) as i64)
+        .bind(limit as i64)
+        .bind(role)
+        .bind(since)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(users)
+    }
+
     #[allow(dead_code)]
     pub async fn fetch_all_chat_users(&self, id: u64) -> Result<Vec<ChatUser>, AppError> {
         let users = sqlx::query_as(
             r#"
-        SELECT id, fullname, email
+        SELECT id, fullname, email, avatar_url
         FROM users
         WHERE ws_id = $1 order by id
         "#,
@@ -86,9 +315,14 @@ impl WsService {
     }
 }
 
+fn new_invite_code() -> String {
+    Uuid::new_v4().simple().to_string()
+}
+
 #[cfg(test)]
 mod tests {
     use anyhow::Result;
+    use argon2::Argon2;
 
     use crate::{
         services::{CreateUser, UserService},
@@ -101,7 +335,7 @@ mod tests {
     async fn workspace_should_create_and_set_owner() {
         let (_tdb, pool) = get_test_pool(None).await;
         let svc = WsService::new(pool.clone());
-        let user_svc = UserService::new(pool.clone(), svc.clone());
+        let user_svc = UserService::new(pool.clone(), svc.clone(), Argon2::default());
 
         let ws = svc.create("test", 0).await.unwrap();
 
@@ -115,6 +349,12 @@ mod tests {
         let ws = ws.update_owner(user.id as _, &pool).await.unwrap();
 
         assert_eq!(ws.owner_id, user.id);
+        let owners = svc
+            .fetch_chat_users(ws.id as _, None, 10, Some(WorkspaceRole::Owner))
+            .await
+            .unwrap();
+        assert_eq!(owners.len(), 1);
+        assert_eq!(owners[0].id, user.id);
     }
 
     #[tokio::test]
@@ -127,6 +367,23 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn workspace_name_should_be_case_insensitive() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let svc = WsService::new(pool.clone());
+        let user_svc = UserService::new(pool, svc.clone(), Argon2::default());
+        let ws = svc.create("Acme", 0).await?;
+
+        let input = CreateUser::new("acme", "tom", "tom@acme.org", "Hunter42");
+        let user = user_svc.create(&input).await?;
+        assert_eq!(user.ws_id, ws.id);
+
+        let found = svc.find_by_name("ACME").await?.unwrap();
+        assert_eq!(found.id, ws.id);
+        assert!(svc.create("aCmE", 0).await.is_err());
+        Ok(())
+    }
+
     #[tokio::test]
     async fn workspace_should_fetch_all_chat_users() -> Result<()> {
         let (_tdb, pool) = get_test_pool(None).await;
@@ -141,4 +398,199 @@ mod tests {
         assert_eq!(users[4].id, 5);
         Ok(())
     }
+
+    #[tokio::test]
+    async fn workspace_should_page_chat_users() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let svc = WsService::new(pool);
+
+        let users = svc.fetch_chat_users(1, None, 3, None).await?;
+        let ids: Vec<_> = users.iter().map(|u| u.id).collect();
+        assert_eq!(ids, vec![1, 2, 3]);
+
+        let users = svc.fetch_chat_users(1, Some(3), 3, None).await?;
+        let ids: Vec<_> = users.iter().map(|u| u.id).collect();
+        assert_eq!(ids, vec![4, 5]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn workspace_should_filter_chat_users_by_role() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        sqlx::query("UPDATE users SET role = 'admin' WHERE id IN (2, 4)")
+            .execute(&pool)
+            .await?;
+        let svc = WsService::new(pool);
+
+        let users = svc
+            .fetch_chat_users(1, None, 10, Some(WorkspaceRole::Admin))
+            .await?;
+        let ids: Vec<_> = users.iter().map(|u| u.id).collect();
+        assert_eq!(ids, vec![2, 4]);
+
+        let users = svc
+            .fetch_chat_users(1, Some(2), 10, Some(WorkspaceRole::Admin))
+            .await?;
+        let ids: Vec<_> = users.iter().map(|u| u.id).collect();
+        assert_eq!(ids, vec![4]);
+
+        let users = svc
+            .fetch_chat_users(1, None, 10, Some(WorkspaceRole::Member))
+            .await?;
+        let ids: Vec<_> = users.iter().map(|u| u.id).collect();
+        assert_eq!(ids, vec![1, 3, 5]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn workspace_should_fetch_chat_users_since() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let svc = WsService::new(pool.clone());
+        let user_svc = UserService::new(pool.clone(), svc.clone(), Argon2::default());
+        // use the db clock, the same one updated_at is set with
+        let (since,): (DateTime<Utc>,) = sqlx::query_as("SELECT now()").fetch_one(&pool).await?;
+
+        let users = svc.fetch_chat_users_since(1, since, None, 10, None).await?;
+        assert!(users.is_empty());
+
+        user_svc
+            .update_avatar(3, "/files/1/abc/def/ghi.png")
+            .await?;
+        let users = svc.fetch_chat_users_since(1, since, None, 10, None).await?;
+        let ids: Vec<_> = users.iter().map(|u| u.id).collect();
+        assert_eq!(ids, vec![3]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn workspace_role_of_should_work() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        sqlx::query("UPDATE workspaces SET owner_id = 1 WHERE id = 1")
+            .execute(&pool)
+            .await?;
+        let svc = WsService::new(pool);
+
+        assert_eq!(svc.role_of(1, 1).await?, Some(WorkspaceRole::Owner));
+        assert_eq!(svc.role_of(1, 2).await?, Some(WorkspaceRole::Member));
+        assert_eq!(svc.role_of(2, 2).await?, None);
+
+        let member = svc.set_role(1, 1, 2, WorkspaceRole::Admin).await?;
+        assert_eq!(member.role, WorkspaceRole::Admin);
+        assert_eq!(svc.role_of(1, 2).await?, Some(WorkspaceRole::Admin));
+        svc.require_admin(1, 2).await?;
+        assert!(matches!(
+            svc.require_admin(1, 3).await,
+            Err(AppError::PermissionDeny)
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn workspace_set_role_should_be_owner_only() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        sqlx::query("UPDATE workspaces SET owner_id = 1 WHERE id = 1")
+            .execute(&pool)
+            .await?;
+        let svc = WsService::new(pool);
+        svc.set_role(1, 1, 2, WorkspaceRole::Admin).await?;
+
+        // even admins can't promote
+        assert!(matches!(
+            svc.set_role(1, 2, 3, WorkspaceRole::Admin).await,
+            Err(AppError::PermissionDeny)
+        ));
+        assert!(matches!(
+            svc.set_role(1, 1, 3, WorkspaceRole::Owner).await,
+            Err(AppError::InvalidInput(_))
+        ));
+        assert!(matches!(
+            svc.set_role(1, 1, 1, WorkspaceRole::Member).await,
+            Err(AppError::InvalidInput(_))
+        ));
+        assert_eq!(svc.role_of(1, 3).await?, Some(WorkspaceRole::Member));
+        Ok(())
+    }
+
+    #[test]
+    fn list_users_option_limit_should_be_clamped() {
+        assert_eq!(ListUsersOption::default().limit(), 100);
+        let opt = ListUsersOption {
+            limit: Some(1000),
+            ..Default::default()
+        };
+        assert_eq!(opt.limit(), 500);
+    }
+
+    #[tokio::test]
+    async fn signup_with_invite_code_should_join_workspace() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let svc = WsService::new(pool.clone());
+        let code = svc.invite_code(2).await?;
+        assert_eq!(svc.invite_code(2).await?, code);
+
+        let user_svc = UserService::new(pool, svc, Argon2::default());
+        let input = CreateUser {
+            invite_code: Some(code),
+            ..CreateUser::new("ignored", "Tyr Chen", "tchen@acme.org", "Hunter42")
+        };
+        let user = user_svc.create(&input).await?;
+        assert_eq!(user.ws_id, 2);
+
+        let input = CreateUser {
+            invite_code: Some("bad-code".to_string()),
+            ..CreateUser::new("ignored", "Alice", "alice@acme.org", "Hunter42")
+        };
+        assert!(matches!(
+            user_svc.create(&input).await,
+            Err(AppError::InvalidInput(_))
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rotate_invite_should_invalidate_old_code() -> Result<()> {
+        let (_tdb, pool) = get_test_pool(None).await;
+        let svc = WsService::new(pool.clone());
+        let user_svc = UserService::new(pool, svc.clone(), Argon2::default());
+        let owner = user_svc
+            .create(&CreateUser::new(
+                "acme",
+                "Tyr Chen",
+                "tchen@acme.org",
+                "Hunter42",
+            ))
+            .await?;
+        let ws_id = owner.ws_id as u64;
+        let old = svc.invite_code(ws_id).await?;
+
+        let member = user_svc
+            .create(&CreateUser {
+                invite_code: Some(old.clone()),
+                ..CreateUser::new("ignored", "Alice", "alice@acme.org", "Hunter42")
+            })
+            .await?;
+        assert!(matches!(
+            svc.rotate_invite(ws_id, member.id as _).await,
+            Err(AppError::PermissionDeny)
+        ));
+
+        let new = svc.rotate_invite(ws_id, owner.id as _).await?;
+        assert_ne!(new, old);
+
+        let input = CreateUser {
+            invite_code: Some(old),
+            ..CreateUser::new("ignored", "Bob", "bob@acme.org", "Hunter42")
+        };
+        assert!(matches!(
+            user_svc.create(&input).await,
+            Err(AppError::InvalidInput(_))
+        ));
+        let input = CreateUser {
+            invite_code: Some(new),
+            ..input
+        };
+        let user = user_svc.create(&input).await?;
+        assert_eq!(user.ws_id, owner.ws_id);
+        Ok(())
+    }
 }
@@ -0,0 +1,181 @@
+use std::{
+    io::{self, Seek, SeekFrom, Write},
+    mem,
+    sync::{Arc, Mutex},
+};
+
+use axum::body::Bytes;
+use futures::{Stream, StreamExt};
+use tokio::sync::mpsc;
+use tracing::warn;
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipWriter};
+
+use super::FileStore;
+use crate::{error::AppError, models::ChatFile};
+
+/// lists the files that were requested but not found in the store
+pub(crate) const MANIFEST_NAME: &str = "manifest.txt";
+
+/// stream a zip archive of the files, the archive is built one file at a time so only the
+/// file being written is kept in memory. Files missing in the store are listed in the manifest
+pub(crate) fn zip_files(
+    store: Arc<dyn FileStore>,
+    files: Vec<ChatFile>,
+) -> impl Stream<Item = io::Result<Bytes>> + Send + 'static {
+    let (tx, rx) = mpsc::channel(1);
+    tokio::spawn(async move {
+        if let Err(e) = write_zip(store.as_ref(), files, &tx).await {
+            warn!("zip files failed: {}", e);
+            // the client sees a truncated body
+            let _ = tx.send(Err(e)).await;
+        }
+    });
+    futures::stream::unfold(rx, |mut rx| async move { rx.recv().await.map(|v| (v, rx)) })
+}
+
+/// the name of the file in the archive
+pub(crate) fn entry_name(file: &ChatFile) -> String {
+    match file.ext.as_str() {
+        "" => file.hash.clone(),
+        ext => format!("{}.{}", file.hash, ext),
+    }
+}
+
+async fn write_zip(
+    store: &dyn FileStore,
+    files: Vec<ChatFile>,
+    tx: &mpsc::Sender<io::Result<Bytes>>,
+) -> io::Result<()> {
+    let ready = Arc::new(Mutex::new(Vec::new()));
+    let mut zip = ZipWriter::new(SpoolWriter::new(ready.clone()));
+    // a finished file is flushed, its bytes can't change anymore
+    zip.set_flush_on_finish_file(true);
+    // uploads are mostly images and videos which are compressed already
+    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+
+    let mut missing = vec![];
+    for file in files {
+        let mut stream = match store.get(&file).await {
+            Ok(stream) => stream,
+            Err(AppError::NotFound(_)) => {
+                missing.push(file.url());
+                continue;
+            }
+            Err(e) => return Err(io::Error::other(e.to_string())),
+        };
+        // starting a file finishes the previous one
+        zip.start_file(entry_name(&file), options)?;
+        send_ready(&ready, tx).await?;
+        while let Some(chunk) = stream.next().await {
+            zip.write_all(&chunk?)?;
+        }
+    }
+    if !missing.is_empty() {
+        zip.start_file(MANIFEST_NAME, options)?;
+        for url in missing {
+            writeln!(zip, "missing: {}", url)?;
+        }
+    }
+    zip.finish()?.flush()?;
+    send_ready(&ready, tx).await
+}
+
+async fn send_ready(
+    ready: &Mutex<Vec<u8>>,
+    tx: &mpsc::Sender<io::Result<Bytes>>,
+) -> io::Result<()> {
+    let chunk = mem::take(&mut *ready.lock().expect("zip buffer lock poisoned"));
+    if !chunk.is_empty() && tx.send(Ok(Bytes::from(chunk))).await.is_err() {
+        return Err(io::Error::new(
+            io::ErrorKind::BrokenPipe,
+            "client went away",
+        ));
+    }
+    Ok(())
+}
+
+/// the zip writer needs to seek back to rewrite the header of the file it is writing, so
+/// bytes are buffered until a flush, after which they are handed over and can't be seeked to
+struct SpoolWriter {
+    /// offset of the first byte in `buf`, bytes before it are handed over
+    offset: u64,
+    pos: u64,
+    buf: Vec<u8>,
+    ready: Arc<Mutex<Vec<u8>>>,
+}
+
+impl SpoolWriter {
+    fn new(ready: Arc<Mutex<Vec<u8>>>) -> Self {
+        Self {
+            offset: 0,
+            pos: 0,
+            buf: Vec::new(),
+            ready,
+        }
+    }
+}
+
+impl Write for SpoolWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let start = (self.pos - self.offset) as usize;
+        let end = start + data.len();
+        if end > self.buf.len() {
+            self.buf.resize(end, 0);
+        }
+        self.buf[start..end].copy_from_slice(data);
+        self.pos += data.len() as u64;
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.offset += self.buf.len() as u64;
+        self.pos = self.offset;
+        self.ready
+            .lock()
+            .expect("zip buffer lock poisoned")
+            .append(&mut self.buf);
+        Ok(())
+    }
+}
+
+impl Seek for SpoolWriter {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let end = self.offset + self.buf.len() as u64;
+        let target = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::End(n) => end.checked_add_signed(n),
+            SeekFrom::Current(n) => self.pos.checked_add_signed(n),
+        };
+        match target {
+            Some(target) if target >= self.offset => {
+                self.pos = target;
+                Ok(target)
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek before the flushed bytes",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spool_writer_should_hand_over_flushed_bytes() -> io::Result<()> {
+        let ready = Arc::new(Mutex::new(Vec::new()));
+        let mut w = SpoolWriter::new(ready.clone());
+        w.write_all(b"hello world")?;
+        w.seek(SeekFrom::Start(0))?;
+        w.write_all(b"HELLO")?;
+        w.seek(SeekFrom::End(0))?;
+        w.flush()?;
+        assert_eq!(&ready.lock().unwrap()[..], b"HELLO world");
+        assert!(w.seek(SeekFrom::Start(0)).is_err());
+        w.write_all(b"!")?;
+        assert_eq!(w.stream_position()?, 12);
+        Ok(())
+    }
+}
@@ -0,0 +1,174 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::SystemTime,
+};
+
+use async_trait::async_trait;
+use axum::body::Bytes;
+use futures::StreamExt;
+use tokio_util::io::ReaderStream;
+use tracing::info;
+use uuid::Uuid;
+
+use super::{FileStore, FileStream};
+use crate::{error::AppError, models::ChatFile};
+
+/// files stored on local disk under base_dir, laid out by `ChatFile::hash_to_path`
+pub(crate) struct LocalFileStore {
+    base_dir: PathBuf,
+}
+
+impl LocalFileStore {
+    pub fn new(base_dir: impl AsRef<Path>) -> Self {
+        Self {
+            base_dir: base_dir.as_ref().to_path_buf(),
+        }
+    }
+}
+
+#[async_trait]
+impl FileStore for LocalFileStore {
+    async fn put(&self, file: &ChatFile, data: Bytes) -> Result<(), AppError> {
+        let path = file.path(&self.base_dir);
+        if tokio::fs::try_exists(&path).await? {
+            info!("File already exists: {:?}", path);
+            return Ok(());
+        }
+        tokio::fs::create_dir_all(path.parent().expect("file path parent should exists")).await?;
+        // write aside then rename, so concurrent uploads of the same content never interleave
+        // and readers never see a partial file, the rename is atomic on the same filesystem
+        let tmp = path.with_file_name(format!(".{}.tmp", Uuid::new_v4().simple()));
+        if let Err(e) = tokio::fs::write(&tmp, data).await {
+            let _ = tokio::fs::remove_file(&tmp).await;
+            return Err(e.into());
+        }
+        tokio::fs::rename(&tmp, path).await?;
+        Ok(())
+    }
+
+    async fn get(&self, file: &ChatFile) -> Result<FileStream, AppError> {
+        let file = tokio::fs::File::open(file.path(&self.base_dir))
+            .await
+            .map_err(file_error)?;
+        Ok(ReaderStream::new(file).boxed())
+    }
+
+    async fn exists(&self, file: &ChatFile) -> Result<bool, AppError> {
+        Ok(tokio::fs::try_exists(file.path(&self.base_dir)).await?)
+    }
+
+    async fn size(&self, file: &ChatFile) -> Result<u64, AppError> {
+        let meta = tokio::fs::metadata(file.path(&self.base_dir))
+            .await
+            .map_err(file_error)?;
+        Ok(meta.len())
+    }
+
+    async fn delete(&self, file: &ChatFile) -> Result<bool, AppError> {
+        match tokio::fs::remove_file(file.path(&self.base_dir)).await {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn list_before(&self, before: SystemTime) -> Result<Vec<ChatFile>, AppError> {
+        let base_dir = self.base_dir.clone();
+        let files = tokio::task::spawn_blocking(move || collect_files(&base_dir))
+            .await
+            .map_err(anyhow::Error::from)??;
+        Ok(files
+            .into_iter()
+            .filter(|(_, modified)| *modified < before)
+            .filter_map(|(path, _)| {
+                let path = path.strip_prefix(&self.base_dir).ok()?.to_str()?;
+                // only files laid out by ChatFile are managed here
+                ChatFile::from_str(&format!("/files/{}", path)).ok()
+            })
+            .collect())
+    }
+}
+
+/// a missing file is a 404 rather than an io error
+fn file_error(e: io::Error) -> AppError {
+    match e.kind() {
+        io::ErrorKind::NotFound => AppError::NotFound("file doesn't exist".to_string()),
+        _ => AppError::IoError(e),
+    }
+}
+
+/// all regular files under dir with their modified time
+fn collect_files(dir: &Path) -> io::Result<Vec<(PathBuf, SystemTime)>> {
+    let mut files = vec![];
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(files),
+        Err(e) => return Err(e),
+    };
+    for entry in entries {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            files.extend(collect_files(&entry.path())?);
+        } else if file_type.is_file() {
+            files.push((entry.path(), entry.metadata()?.modified()?));
+        }
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use futures::TryStreamExt;
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn local_file_store_should_work() -> Result<()> {
+        let dir = tempdir()?;
+        let store = LocalFileStore::new(dir.path());
+        let file = ChatFile::new(1, "hello.txt", b"hello world");
+        assert!(!store.exists(&file).await?);
+
+        store.put(&file, Bytes::from_static(b"hello world")).await?;
+        assert!(store.exists(&file).await?);
+        let data: Vec<Bytes> = store.get(&file).await?.try_collect().await?;
+        assert_eq!(data.concat(), b"hello world");
+
+        let files = store.list_before(SystemTime::now()).await?;
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].url(), file.url());
+
+        assert!(store.delete(&file).await?);
+        assert!(!store.delete(&file).await?);
+        assert!(matches!(store.get(&file).await, Err(AppError::NotFound(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn concurrent_puts_of_same_content_should_not_corrupt() -> Result<()> {
+        let dir = tempdir()?;
+        let store = std::sync::Arc::new(LocalFileStore::new(dir.path()));
+        let content = Bytes::from((0..4 * 1024 * 1024).map(|i| i as u8).collect::<Vec<_>>());
+        let file = ChatFile::new(1, "big.bin", &content);
+
+        let tasks: Vec<_> = (0..2)
+            .map(|_| {
+                let (store, file, content) = (store.clone(), file.clone(), content.clone());
+                tokio::spawn(async move { store.put(&file, content).await })
+            })
+            .collect();
+        for task in tasks {
+            task.await??;
+        }
+
+        let data = tokio::fs::read(file.path(dir.path())).await?;
+        assert_eq!(data, content);
+        // no temp file is left behind
+        assert_eq!(collect_files(dir.path())?.len(), 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,36 @@
+mod archive;
+mod local;
+
+use std::{io, time::SystemTime};
+
+use async_trait::async_trait;
+use axum::body::Bytes;
+use futures::stream::BoxStream;
+
+use crate::{error::AppError, models::ChatFile};
+
+pub(crate) use archive::{entry_name, zip_files, MANIFEST_NAME};
+pub(crate) use local::LocalFileStore;
+
+pub(crate) type FileStream = BoxStream<'static, io::Result<Bytes>>;
+
+/// where uploaded files live, files are content addressed by `ChatFile`
+#[async_trait]
+pub(crate) trait FileStore: Send + Sync + 'static {
+    /// store the data, an existing file with the same content is kept as is
+    async fn put(&self, file: &ChatFile, data: Bytes) -> Result<(), AppError>;
+
+    /// stream the file content, `AppError::NotFound` if it doesn't exist
+    async fn get(&self, file: &ChatFile) -> Result<FileStream, AppError>;
+
+    async fn exists(&self, file: &ChatFile) -> Result<bool, AppError>;
+
+    /// size of the file in bytes, `AppError::NotFound` if it doesn't exist
+    async fn size(&self, file: &ChatFile) -> Result<u64, AppError>;
+
+    /// delete the file, false if it doesn't exist
+    async fn delete(&self, file: &ChatFile) -> Result<bool, AppError>;
+
+    /// files last modified before the given time, used to sweep orphaned uploads
+    async fn list_before(&self, before: SystemTime) -> Result<Vec<ChatFile>, AppError>;
+}
@@ -1,7 +1,7 @@
 use std::{io::Cursor, net::SocketAddr, time::Duration};
 
 use anyhow::Result;
-use chat_core::{Chat, ChatType, Message};
+use chat_core::{Attachment, Chat, ChatType, Message};
 use chat_server::test_util;
 use futures::StreamExt;
 use reqwest::{
@@ -11,7 +11,7 @@ use reqwest::{
 use reqwest_eventsource::{Event, EventSource};
 use serde::Deserialize;
 use serde_json::json;
-use tokio::{net::TcpListener, time::sleep};
+use tokio::{net::TcpListener, sync::mpsc, time::sleep};
 
 struct ChatServer {
     addr: SocketAddr,
@@ -24,6 +24,26 @@ struct AuthToken {
     token: String,
 }
 
+#[derive(Debug, Deserialize)]
+struct UploadOutput {
+    files: Vec<Attachment>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatUser {
+    id: i64,
+    avatar_url: Option<String>,
+}
+
+// 1x1 transparent png
+const PNG_BYTES: &[u8] = &[
+    0x89, 0x50, 0x4e, 0x47, 0x0d, 0x0a, 0x1a, 0x0a, 0x00, 0x00, 0x00, 0x0d, 0x49, 0x48, 0x44, 0x52,
+    0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x08, 0x06, 0x00, 0x00, 0x00, 0x1f, 0x15, 0xc4,
+    0x89, 0x00, 0x00, 0x00, 0x0d, 0x49, 0x44, 0x41, 0x54, 0x78, 0x9c, 0x63, 0x00, 0x01, 0x00, 0x00,
+    0x05, 0x00, 0x01, 0x0d, 0x0a, 0x2d, 0xb4, 0x00, 0x00, 0x00, 0x00, 0x49, 0x45, 0x4e, 0x44, 0xae,
+    0x42, 0x60, 0x82,
+];
+
 impl ChatServer {
     async fn try_new(state: chat_server::AppState) -> Result<Self> {
         let app = chat_server::get_router(state.clone()).await?;
@@ -44,16 +64,16 @@ impl ChatServer {
             token: "".to_string(),
         };
 
-        ret.token = ret.signin().await?;
+        ret.token = ret.signin("jack1@gmail.com").await?;
 
         Ok(ret)
     }
 
-    async fn signin(&self) -> Result<String> {
+    async fn signin(&self, email: &str) -> Result<String> {
         let resp = self
             .client
             .post(format!("http://{}/api/signin", self.addr))
-            .json(&json!({"email": "jack1@gmail.com", "password": "Hunter48"}))
+            .json(&json!({"email": email, "password": "Hunter48"}))
             .send()
             .await?;
         assert_eq!(resp.status(), StatusCode::OK);
@@ -89,32 +109,102 @@ impl ChatServer {
             .send()
             .await?;
         assert_eq!(resp.status(), StatusCode::OK);
-        let urls: Vec<String> = resp.json().await?;
+        let attachments = resp.json::<UploadOutput>().await?.files;
+        assert_eq!(attachments[0].size, data.len() as u64);
+        assert_eq!(attachments[0].filename, "Cargo.toml");
         let resp = self
             .client
             .post(format!("http://{}/api/chats/{}", self.addr, chat_id))
             .header("Authorization", format!("Bearer {}", self.token))
-            .json(&json!({"content": "hello", "files": urls}))
+            .json(&json!({"content": "hello", "files": [], "attachments": attachments}))
             .send()
             .await?;
         assert_eq!(resp.status(), StatusCode::CREATED);
         let message: Message = resp.json().await?;
         assert_eq!(message.content, "hello");
-        assert_eq!(message.files, urls);
+        assert_eq!(message.files, vec![attachments[0].url.clone()]);
+        assert_eq!(*message.attachments, attachments);
         assert_eq!(message.sender_id, 1);
         assert_eq!(message.chat_id, chat_id as i64);
         Ok(message)
     }
+
+    async fn send_text(&self, chat_id: u64, content: &str) -> Result<Message> {
+        let resp = self
+            .client
+            .post(format!("http://{}/api/chats/{}", self.addr, chat_id))
+            .header("Authorization", format!("Bearer {}", self.token))
+            .json(&json!({"content": content, "files": []}))
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        Ok(resp.json().await?)
+    }
+
+    async fn mute_chat(&self, token: &str, chat_id: u64) -> Result<()> {
+        let resp = self
+            .client
+            .put(format!("http://{}/api/chats/{}/mute", self.addr, chat_id))
+            .header("Authorization", format!("Bearer {}", token))
+            .json(&json!({"muted": true}))
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+        Ok(())
+    }
+
+    async fn upload_avatar(&self) -> Result<ChatUser> {
+        let avatar = Part::bytes(PNG_BYTES)
+            .file_name("avatar.png")
+            .mime_str("image/png")?;
+        let form = Form::new().part("avatar", avatar);
+        let resp = self
+            .client
+            .post(format!("http://{}/api/users/avatar", self.addr))
+            .header("Authorization", format!("Bearer {}", self.token))
+            .multipart(form)
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let user: ChatUser = resp.json().await?;
+        assert!(user.avatar_url.is_some());
+
+        let resp = self
+            .client
+            .get(format!("http://{}/api/users/me", self.addr))
+            .header("Authorization", format!("Bearer {}", self.token))
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let me: ChatUser = resp.json().await?;
+        assert_eq!(me.id, user.id);
+        assert_eq!(me.avatar_url, user.avatar_url);
+
+        let resp = self
+            .client
+            .get(format!(
+                "http://{}/api{}",
+                self.addr,
+                user.avatar_url.as_ref().unwrap()
+            ))
+            .header("Authorization", format!("Bearer {}", self.token))
+            .send()
+            .await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.bytes().await?.as_ref(), PNG_BYTES);
+        Ok(user)
+    }
 }
 
 struct NotifyServer;
 
 impl NotifyServer {
-    async fn new<R: std::io::Read>(reader: R, db_url: &str, token: &str) -> Result<Self> {
+    async fn start<R: std::io::Read>(reader: R, db_url: &str) -> Result<SocketAddr> {
         let mut config = notify_server::config::AppConfig::load_from_reader(reader)?;
         let listener = TcpListener::bind(format!("0.0.0.0:{}", config.server.port)).await?;
         config.server.db_url = db_url.to_string();
-        let app = notify_server::get_router(config).await?;
+        let state = notify_server::AppState::new(config);
+        let app = notify_server::get_router(state).await?;
         let addr = listener.local_addr()?;
 
         tokio::spawn(async move {
@@ -122,6 +212,32 @@ impl NotifyServer {
                 .await
                 .unwrap();
         });
+        Ok(addr)
+    }
+
+    /// forward NewMessage events received by the user to the returned channel
+    fn subscribe_messages(addr: SocketAddr, token: &str) -> mpsc::UnboundedReceiver<Message> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mut es = EventSource::get(format!("http://{}/events?token={}", addr, token));
+        tokio::spawn(async move {
+            while let Some(event) = es.next().await {
+                match event {
+                    Ok(Event::Message(message)) if message.event == "NewMessage" => {
+                        let msg: Message = serde_json::from_str(&message.data).unwrap();
+                        if tx.send(msg).is_err() {
+                            es.close();
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(_) => es.close(),
+                }
+            }
+        });
+        rx
+    }
+
+    async fn new<R: std::io::Read>(reader: R, db_url: &str, token: &str) -> Result<Self> {
+        let addr = Self::start(reader, db_url).await?;
 
         let mut es = EventSource::get(format!("http://{}/events?token={}", addr, token));
         tokio::spawn(async move {
@@ -201,3 +317,73 @@ async fn chat_server_should_work() -> Result<()> {
     sleep(Duration::from_secs(1)).await;
     Ok(())
 }
+
+#[tokio::test]
+async fn avatar_upload_should_work() -> Result<()> {
+    let chat_server_config_reader = std::io::BufReader::new(Cursor::new(TEST_APP_YAML.as_bytes()));
+    let (state, _tdb) =
+        test_util::get_test_state_and_pg_from_config_reader(chat_server_config_reader).await?;
+    let chat_server = ChatServer::try_new(state).await?;
+    chat_server.upload_avatar().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn muted_chat_should_not_notify() -> Result<()> {
+    let chat_server_config_reader = std::io::BufReader::new(Cursor::new(TEST_APP_YAML.as_bytes()));
+    let (state, tdb) =
+        test_util::get_test_state_and_pg_from_config_reader(chat_server_config_reader).await?;
+    let chat_server = ChatServer::try_new(state).await?;
+    let notify_yaml = TEST_NOTIFY_YAML.replace("port: 6687", "port: 0");
+    let notify_server_config_reader = std::io::BufReader::new(Cursor::new(notify_yaml.as_bytes()));
+    let addr = NotifyServer::start(notify_server_config_reader, &tdb.url()).await?;
+
+    // jack2 mutes the general chat, jack1 keeps it
+    let token2 = chat_server.signin("jack2@gmail.com").await?;
+    chat_server.mute_chat(&token2, 1).await?;
+    let mut rx1 = NotifyServer::subscribe_messages(addr, &chat_server.token);
+    let mut rx2 = NotifyServer::subscribe_messages(addr, &token2);
+    sleep(Duration::from_millis(500)).await;
+
+    let message = chat_server.send_text(1, "muted").await?;
+    let msg = tokio::time::timeout(Duration::from_secs(3), rx1.recv())
+        .await?
+        .expect("unmuted member should be notified");
+    assert_eq!(msg.id, message.id);
+    let ret = tokio::time::timeout(Duration::from_secs(1), rx2.recv()).await;
+    assert!(ret.is_err(), "muted member should not be notified");
+    Ok(())
+}
+
+#[tokio::test]
+async fn revoked_token_should_be_rejected_by_notify() -> Result<()> {
+    let chat_server_config_reader = std::io::BufReader::new(Cursor::new(TEST_APP_YAML.as_bytes()));
+    let (state, tdb) =
+        test_util::get_test_state_and_pg_from_config_reader(chat_server_config_reader).await?;
+    let chat_server = ChatServer::try_new(state).await?;
+    let notify_yaml = TEST_NOTIFY_YAML.replace("port: 6687", "port: 0");
+    let notify_server_config_reader = std::io::BufReader::new(Cursor::new(notify_yaml.as_bytes()));
+    let addr = NotifyServer::start(notify_server_config_reader, &tdb.url()).await?;
+
+    let events = format!("http://{}/events?token={}", addr, chat_server.token);
+    let resp = chat_server.client.get(&events).send().await?;
+    assert_eq!(resp.status(), StatusCode::OK);
+    drop(resp);
+
+    let resp = chat_server
+        .client
+        .post(format!(
+            "http://{}/api/users/revoke-tokens",
+            chat_server.addr
+        ))
+        .header("Authorization", format!("Bearer {}", chat_server.token))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+    let resp = chat_server.client.get(events).send().await?;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    let body: serde_json::Value = resp.json().await?;
+    assert_eq!(body["error"], "token revoked");
+    Ok(())
+}
@@ -0,0 +1,4 @@
+-- Add migration script here
+-- avatar url of user, it's a chat file url
+ALTER TABLE users
+    ADD COLUMN avatar_url varchar(256);
@@ -0,0 +1,4 @@
+-- Add migration script here
+-- archived chats are kept but hidden from the default chat list and can't receive messages
+ALTER TABLE chats
+    ADD COLUMN archived_at timestamptz;
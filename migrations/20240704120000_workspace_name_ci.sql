@@ -0,0 +1,3 @@
+-- Add migration script here
+-- workspace names are unique regardless of case
+CREATE UNIQUE INDEX IF NOT EXISTS workspaces_lower_name_index ON workspaces(LOWER(name));
@@ -0,0 +1,6 @@
+-- Add migration script here
+-- pinned messages of a chat
+ALTER TABLE messages
+    ADD COLUMN pinned boolean NOT NULL DEFAULT FALSE;
+
+CREATE INDEX IF NOT EXISTS chat_id_pinned_index ON messages(chat_id) WHERE pinned;
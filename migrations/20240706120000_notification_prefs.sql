@@ -0,0 +1,8 @@
+-- Add migration script here
+-- per user notification preferences of a chat
+CREATE TABLE IF NOT EXISTS notification_prefs(
+  user_id bigint NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  chat_id bigint NOT NULL REFERENCES chats(id) ON DELETE CASCADE,
+  muted boolean NOT NULL DEFAULT FALSE,
+  PRIMARY KEY (user_id, chat_id)
+);
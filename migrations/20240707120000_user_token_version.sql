@@ -0,0 +1,4 @@
+-- Add migration script here
+-- tokens signed with a version lower than this are revoked
+ALTER TABLE users
+    ADD COLUMN token_version integer NOT NULL DEFAULT 0;
@@ -0,0 +1,8 @@
+-- Add migration script here
+-- client supplied key to dedupe retried message sends
+ALTER TABLE messages
+    ADD COLUMN idempotency_key varchar(64);
+
+CREATE UNIQUE INDEX IF NOT EXISTS sender_id_idempotency_key_index ON messages(sender_id, idempotency_key)
+WHERE
+    idempotency_key IS NOT NULL;
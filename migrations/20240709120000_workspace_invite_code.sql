@@ -0,0 +1,4 @@
+-- Add migration script here
+-- code to join a workspace on signup regardless of its name
+ALTER TABLE workspaces
+    ADD COLUMN invite_code varchar(32) UNIQUE;
@@ -0,0 +1,4 @@
+-- Add migration script here
+-- metadata of attached files, files keeps the plain urls for older clients
+ALTER TABLE messages
+    ADD COLUMN attachments jsonb NOT NULL DEFAULT '[]';
@@ -0,0 +1,4 @@
+-- Add migration script here
+-- optional username to sign in with instead of the email
+ALTER TABLE users
+    ADD COLUMN username varchar(32) UNIQUE;
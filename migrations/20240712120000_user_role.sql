@@ -0,0 +1,15 @@
+-- Add migration script here
+-- role of a user in its workspace
+CREATE TYPE workspace_role AS ENUM(
+  'owner',
+  'admin',
+  'member'
+);
+
+ALTER TABLE users
+    ADD COLUMN role workspace_role NOT NULL DEFAULT 'member';
+
+UPDATE users
+SET role = 'owner'
+FROM workspaces
+WHERE workspaces.owner_id = users.id AND workspaces.id = users.ws_id;
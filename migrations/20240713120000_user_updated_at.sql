@@ -0,0 +1,7 @@
+-- Add migration script here
+-- last time the profile of a user changed, used for incremental sync of the member directory
+ALTER TABLE users
+    ADD COLUMN updated_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP;
+
+UPDATE users
+SET updated_at = created_at;
@@ -0,0 +1,4 @@
+-- Add migration script here
+-- the message a forwarded message was copied from, kept when the original is deleted
+ALTER TABLE messages
+    ADD COLUMN forwarded_from bigint REFERENCES messages(id) ON DELETE SET NULL;
@@ -0,0 +1,9 @@
+-- Add migration script here
+-- the latest message of a chat each user has read
+CREATE TABLE IF NOT EXISTS chat_reads(
+  user_id bigint NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  chat_id bigint NOT NULL REFERENCES chats(id) ON DELETE CASCADE,
+  last_read_id bigint NOT NULL,
+  updated_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (user_id, chat_id)
+);
@@ -0,0 +1,28 @@
+-- Add migration script here
+-- if a user read further in a chat, notify the chat members
+CREATE OR REPLACE FUNCTION chat_read()
+    RETURNS TRIGGER
+    AS $$
+DECLARE
+    USERS bigint[];
+BEGIN
+    IF TG_OP = 'INSERT' OR NEW.last_read_id > OLD.last_read_id THEN
+        SELECT
+            members INTO USERS
+        FROM
+            chats
+        WHERE
+            id = NEW.chat_id;
+        PERFORM
+            pg_notify('chat_read', json_build_object('chat_id', NEW.chat_id, 'user_id', NEW.user_id,
+                'up_to_message_id', NEW.last_read_id, 'members', USERS)::text);
+    END IF;
+    RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE TRIGGER chat_read_trigger
+    AFTER INSERT OR UPDATE ON chat_reads
+    FOR EACH ROW
+        EXECUTE FUNCTION chat_read();
@@ -0,0 +1,86 @@
+use std::sync::Arc;
+
+use axum::{extract::State, http::StatusCode, response::IntoResponse, Extension, Json};
+use chat_core::User;
+use serde::{Deserialize, Serialize};
+
+use crate::{error::AppError, notif::AppEvent, AppState};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Announce {
+    pub text: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct AnnounceOutput {
+    /// number of connected users the announcement was sent to
+    pub delivered: usize,
+}
+
+/// push an announcement to every connected user of the caller's workspace, owner only
+pub(crate) async fn announce_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+    Json(input): Json<Announce>,
+) -> Result<impl IntoResponse, AppError> {
+    let text = input.text.trim();
+    if text.is_empty() {
+        return Err(AppError::InvalidInput("text is empty".to_string()));
+    }
+    if !state.is_ws_owner(&user).await? {
+        return Err(AppError::PermissionDeny);
+    }
+
+    let user_ids = state.ws_user_ids(user.ws_id).await?;
+    let event = Arc::new(AppEvent::Announcement {
+        text: text.to_string(),
+    });
+    let delivered = state.broadcast(user_ids, event);
+
+    Ok((StatusCode::ACCEPTED, Json(AnnounceOutput { delivered })))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::broadcast;
+
+    use super::*;
+    use crate::config::AppConfig;
+
+    #[tokio::test]
+    async fn broadcast_announcement_should_reach_all_given_users() -> anyhow::Result<()> {
+        let state = AppState::new(AppConfig::load()?);
+        let mut receivers = Vec::new();
+        for user_id in [1, 2, 3] {
+            let (tx, rx) = broadcast::channel(16);
+            state.users.insert(user_id, tx);
+            receivers.push(rx);
+        }
+
+        let event = Arc::new(AppEvent::Announcement {
+            text: "maintenance at 10pm".to_string(),
+        });
+        // user 4 is not connected, user 3 is not a recipient
+        let delivered = state.broadcast([1, 2, 4], event);
+        assert_eq!(delivered, 2);
+
+        for rx in &mut receivers[..2] {
+            let event = rx.try_recv()?;
+            assert!(
+                matches!(event.as_ref(), AppEvent::Announcement { text } if text == "maintenance at 10pm")
+            );
+        }
+        assert!(receivers[2].try_recv().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn announcement_should_serialize_with_event_tag() {
+        let event = AppEvent::Announcement {
+            text: "hello".to_string(),
+        };
+        let v = serde_json::to_value(&event).unwrap();
+        assert_eq!(v["event"], "Announcement");
+        assert_eq!(v["text"], "hello");
+    }
+}
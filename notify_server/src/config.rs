@@ -1,6 +1,7 @@
 use std::{env, fs::File};
 
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
+use chat_core::utils::{parse_log_level, DecodingKey, LogFormat, PublicKeyConfig, TlsConfig};
 use serde::{Deserialize, Serialize};
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -12,12 +13,55 @@ pub struct AppConfig {
 #[derive(Deserialize, Serialize, Debug)]
 pub struct AuthConfig {
     pub pk: String,
+    /// key id of `pk`, tokens carrying a `kid` header are checked against the key with that id
+    #[serde(default)]
+    pub kid: Option<String>,
+    /// extra public keys still accepted when verifying, used to rotate keys
+    #[serde(default)]
+    pub pks: Vec<PublicKeyConfig>,
+    /// `iss` claim tokens must carry, must match the chat server
+    #[serde(default = "default_jwt_issuer")]
+    pub issuer: String,
+    /// `aud` claim tokens must carry, must match the chat server
+    #[serde(default = "default_jwt_audience")]
+    pub audience: String,
+    /// seconds of clock skew tolerated when checking token expiry
+    #[serde(default = "default_jwt_leeway_secs")]
+    pub leeway_secs: u64,
 }
 
 #[derive(Deserialize, Serialize, Debug)]
 pub struct ServerConfig {
     pub port: u16,
     pub db_url: String,
+    /// serve over https when present
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+    /// `text` or `json`
+    #[serde(default)]
+    pub log_format: LogFormat,
+    /// e.g. `debug`, overridden by the `RUST_LOG` env, INFO when absent
+    #[serde(default)]
+    pub log_level: Option<String>,
+    /// events buffered per user before slow connections start lagging
+    #[serde(default = "default_channel_capacity")]
+    pub channel_capacity: usize,
+}
+
+fn default_jwt_issuer() -> String {
+    "chat_server".to_string()
+}
+
+fn default_jwt_audience() -> String {
+    "chat_web".to_string()
+}
+
+fn default_jwt_leeway_secs() -> u64 {
+    60
+}
+
+fn default_channel_capacity() -> usize {
+    256
 }
 
 impl AppConfig {
@@ -33,7 +77,51 @@ impl AppConfig {
             (_, _, Ok(path)) => serde_yaml::from_reader(File::open(path)?),
             _ => bail!("no config file found"),
         };
-        Ok(ret?)
+        let mut config: Self = ret?;
+        config.apply_env_overrides(|key| env::var(key).ok())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// check the values make sense, the error lists every problem found
+    pub fn validate(&self) -> Result<()> {
+        let mut problems = vec![];
+        if self.server.port == 0 {
+            problems.push("server.port must not be 0".to_string());
+        }
+        if self.server.db_url.trim().is_empty() {
+            problems.push("server.db_url is empty".to_string());
+        }
+        if let Some(Err(e)) = self.server.log_level.as_deref().map(parse_log_level) {
+            problems.push(format!("server.log_level: {}", e));
+        }
+        if self.server.channel_capacity == 0 {
+            problems.push("server.channel_capacity must not be 0".to_string());
+        }
+        if let Err(e) = DecodingKey::load(&self.auth.pk) {
+            problems.push(format!("auth.pk is not a valid public key: {}", e));
+        }
+        for (i, pk) in self.auth.pks.iter().enumerate() {
+            if let Err(e) = DecodingKey::load(&pk.pk) {
+                problems.push(format!("auth.pks[{}] is not a valid public key: {}", i, e));
+            }
+        }
+
+        if !problems.is_empty() {
+            bail!("invalid config:\n  - {}", problems.join("\n  - "));
+        }
+        Ok(())
+    }
+
+    /// override config fields by env, e.g. NOTIFY__SERVER__PORT=6687. `lookup` reads a var
+    fn apply_env_overrides(&mut self, lookup: impl Fn(&str) -> Option<String>) -> Result<()> {
+        if let Some(port) = lookup("NOTIFY__SERVER__PORT") {
+            self.server.port = port.parse().context("invalid NOTIFY__SERVER__PORT")?;
+        }
+        if let Some(db_url) = lookup("NOTIFY__SERVER__DB_URL") {
+            self.server.db_url = db_url;
+        }
+        Ok(())
     }
 
     pub fn load_from_reader<R: std::io::Read>(reader: R) -> Result<Self> {
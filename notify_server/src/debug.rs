@@ -0,0 +1,70 @@
+use axum::{extract::State, response::IntoResponse, Extension, Json};
+use chat_core::User;
+use serde::{Deserialize, Serialize};
+
+use crate::{error::AppError, AppState};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Subscription {
+    pub user_id: u64,
+    /// open sse connections of the user, 0 means the sender leaked
+    pub receiver_count: usize,
+}
+
+/// users of the caller's workspace that have a channel, owner only
+pub(crate) async fn subscriptions_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+) -> Result<impl IntoResponse, AppError> {
+    if !state.is_ws_owner(&user).await? {
+        return Err(AppError::PermissionDeny);
+    }
+    let user_ids = state.ws_user_ids(user.ws_id).await?;
+    Ok(Json(subscriptions(&state, user_ids)))
+}
+
+/// channels of the given users ordered by user id, users without a channel are skipped
+fn subscriptions(state: &AppState, user_ids: impl IntoIterator<Item = u64>) -> Vec<Subscription> {
+    let mut subs: Vec<_> = user_ids
+        .into_iter()
+        .filter_map(|user_id| {
+            state.users.get(&user_id).map(|tx| Subscription {
+                user_id,
+                receiver_count: tx.receiver_count(),
+            })
+        })
+        .collect();
+    subs.sort_by_key(|v| v.user_id);
+    subs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::AppConfig, sse::subscribe};
+
+    #[tokio::test]
+    async fn subscriptions_should_list_connected_users() -> anyhow::Result<()> {
+        let state = AppState::new(AppConfig::load()?);
+        let _rx1 = subscribe(&state, 1);
+        let _rx2 = subscribe(&state, 1);
+        let rx3 = subscribe(&state, 3);
+        drop(rx3);
+
+        let subs = subscriptions(&state, [3, 2, 1]);
+        assert_eq!(
+            subs,
+            vec![
+                Subscription {
+                    user_id: 1,
+                    receiver_count: 2,
+                },
+                Subscription {
+                    user_id: 3,
+                    receiver_count: 0,
+                },
+            ]
+        );
+        Ok(())
+    }
+}
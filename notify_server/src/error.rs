@@ -1,6 +1,7 @@
 use axum::http::StatusCode;
 use axum::response::Json;
 use axum::response::{IntoResponse, Response};
+use chat_core::utils::TokenError;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
@@ -16,6 +17,21 @@ pub enum AppError {
 
     #[error("jwt error: {0}")]
     JwtError(#[from] jwt_simple::Error),
+
+    #[error("invalid token: {0}")]
+    TokenError(#[from] TokenError),
+
+    #[error("token revoked")]
+    TokenRevoked,
+
+    #[error("sql error: {0}")]
+    SqlxError(#[from] sqlx::Error),
+
+    #[error("invalid input: {0}")]
+    InvalidInput(String),
+
+    #[error("permission denied")]
+    PermissionDeny,
 }
 
 impl ErrorOutput {
@@ -30,7 +46,10 @@ impl IntoResponse for AppError {
     fn into_response(self) -> Response<axum::body::Body> {
         let status = match &self {
             Self::JwtError(_) => StatusCode::FORBIDDEN,
-            Self::IoError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::TokenError(_) | Self::TokenRevoked => StatusCode::UNAUTHORIZED,
+            Self::InvalidInput(_) => StatusCode::BAD_REQUEST,
+            Self::PermissionDeny => StatusCode::FORBIDDEN,
+            Self::IoError(_) | Self::SqlxError(_) => StatusCode::INTERNAL_SERVER_ERROR,
         };
 
         (status, Json(ErrorOutput::new(self.to_string()))).into_response()
@@ -1,9 +1,10 @@
 use std::{ops::Deref, sync::Arc};
 
+use announce::announce_handler;
 use axum::{
     middleware::from_fn_with_state,
     response::{Html, IntoResponse},
-    routing::get,
+    routing::{get, post},
     Router,
 };
 use chat_core::{
@@ -13,15 +14,23 @@ use chat_core::{
 };
 use config::AppConfig;
 use dashmap::DashMap;
+use debug::subscriptions_handler;
 use error::AppError;
 use notif::AppEvent;
+use sqlx::PgPool;
 use sse::sse_handler;
+use token_version::verify_token_version;
+mod announce;
 pub mod config;
+mod debug;
 mod error;
 mod notif;
 mod sse;
+mod token_version;
 pub use notif::setup_pg_listener;
 use tokio::sync::broadcast;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
 
 pub type UserMap = Arc<DashMap<u64, broadcast::Sender<Arc<AppEvent>>>>;
 
@@ -35,6 +44,8 @@ pub struct AppStateInner {
     pub(crate) config: AppConfig,
     users: UserMap,
     dk: DecodingKey,
+    pool: PgPool,
+    shutdown: CancellationToken,
 }
 
 impl Deref for AppState {
@@ -47,9 +58,91 @@ impl Deref for AppState {
 
 impl AppState {
     pub fn new(config: AppConfig) -> Self {
-        let dk = DecodingKey::load(&config.auth.pk).expect("Failed to load public key");
+        let mut dk = DecodingKey::load(&config.auth.pk)
+            .expect("Failed to load public key")
+            .with_scope(&config.auth.issuer, &config.auth.audience)
+            .with_leeway(config.auth.leeway_secs);
+        if let Some(kid) = &config.auth.kid {
+            dk = dk.with_key_id(kid);
+        }
+        for pk in &config.auth.pks {
+            dk = match &pk.kid {
+                Some(kid) => dk.add_key_with_id(&pk.pk, kid),
+                None => dk.add_key(&pk.pk),
+            }
+            .expect("Failed to load public key");
+        }
         let users = Arc::new(DashMap::new());
-        Self(Arc::new(AppStateInner { config, dk, users }))
+        // connections are opened on first use, handlers only need it for a few lookups
+        let pool = PgPool::connect_lazy(&config.server.db_url).expect("Invalid db_url");
+        Self(Arc::new(AppStateInner {
+            config,
+            dk,
+            users,
+            pool,
+            shutdown: CancellationToken::new(),
+        }))
+    }
+
+    /// end all open sse streams, used when the server is shutting down
+    pub fn shutdown(&self) {
+        self.shutdown.cancel();
+    }
+
+    /// the workspace's owner_id always counts as owner, same as the chat server
+    pub(crate) async fn is_ws_owner(&self, user: &User) -> Result<bool, AppError> {
+        let found: Option<(i32,)> = sqlx::query_as(
+            r#"
+        SELECT 1
+        FROM users u
+        JOIN workspaces w ON w.id = u.ws_id
+        WHERE u.id = $1 AND u.ws_id = $2 AND (w.owner_id = u.id OR u.role = 'owner')
+        "#,
+        )
+        .bind(user.id)
+        .bind(user.ws_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(found.is_some())
+    }
+
+    /// current token version of the user, None if the user doesn't exist
+    pub(crate) async fn token_version(&self, user_id: i64) -> Result<Option<i32>, AppError> {
+        let version: Option<(i32,)> =
+            sqlx::query_as("SELECT token_version FROM users WHERE id = $1")
+                .bind(user_id)
+                .fetch_optional(&self.pool)
+                .await?;
+        Ok(version.map(|(v,)| v))
+    }
+
+    /// ids of all users in the workspace
+    pub(crate) async fn ws_user_ids(&self, ws_id: i64) -> Result<Vec<u64>, AppError> {
+        let ids: Vec<(i64,)> = sqlx::query_as("SELECT id FROM users WHERE ws_id = $1")
+            .bind(ws_id)
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(ids.into_iter().map(|(id,)| id as u64).collect())
+    }
+
+    /// send the event to the given users that are connected, returns how many got it
+    pub(crate) fn broadcast(
+        &self,
+        user_ids: impl IntoIterator<Item = u64>,
+        event: Arc<AppEvent>,
+    ) -> usize {
+        let mut sent = 0;
+        for user_id in user_ids {
+            if let Some(tx) = self.users.get(&user_id) {
+                info!("Sending notification to user {}", user_id);
+                match tx.send(event.clone()) {
+                    Ok(_) => sent += 1,
+                    Err(e) => warn!("Failed to send notification to user {}: {}", user_id, e),
+                }
+            }
+        }
+        sent
     }
 }
 
@@ -60,11 +153,13 @@ impl TokenVerify for AppState {
     }
 }
 
-pub async fn get_router(config: AppConfig) -> anyhow::Result<Router> {
-    let state = AppState::new(config);
+pub async fn get_router(state: AppState) -> anyhow::Result<Router> {
     setup_pg_listener(state.clone()).await?;
     Ok(Router::new()
         .route("/events", get(sse_handler))
+        .route("/announce", post(announce_handler))
+        .route("/debug/subscriptions", get(subscriptions_handler))
+        .layer(from_fn_with_state(state.clone(), verify_token_version))
         .layer(from_fn_with_state(
             state.clone(),
             verify_token_v2::<AppState>,
@@ -1,20 +1,57 @@
+use std::time::Duration;
+
 use anyhow::Result;
-use notify_server::{config::AppConfig, get_router};
-use tokio::net::TcpListener;
-use tracing::{info, level_filters::LevelFilter};
-use tracing_subscriber::{fmt::Layer, layer::SubscriberExt, util::SubscriberInitExt, Layer as _};
+use axum_server::Handle;
+use chat_core::utils::{
+    graceful_shutdown, invalid_env_log_level, log_layer, log_level, shutdown_signal,
+};
+use notify_server::{config::AppConfig, get_router, AppState};
+use tracing::{info, warn};
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, Layer as _};
+
+const SHUTDOWN_DRAIN: Duration = Duration::from_secs(10);
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let layer = Layer::new().with_filter(LevelFilter::INFO);
+    let config = AppConfig::load().expect("Failed to load config");
+    let layer = log_layer(config.server.log_format, std::io::stdout)
+        .with_filter(log_level(config.server.log_level.as_deref()));
     tracing_subscriber::registry().with(layer).init();
+    if let Some(level) = invalid_env_log_level() {
+        warn!("Ignore invalid RUST_LOG: {:?}", level);
+    }
 
-    let addr = "0.0.0.0:6687";
-    let config = AppConfig::load().expect("Failed to load config");
-    let app = get_router(config).await?;
-    let listener = TcpListener::bind(&addr).await?;
-    info!("Listening on: {}", addr);
+    let addr = format!("0.0.0.0:{}", config.server.port);
+    let tls = config.server.tls.clone();
+    let state = AppState::new(config);
+    let app = get_router(state.clone()).await?;
 
-    axum::serve(listener, app).await?;
+    let handle = Handle::new();
+    tokio::spawn(graceful_shutdown(
+        handle.clone(),
+        async move {
+            shutdown_signal().await;
+            // end all sse streams so that their connections can be drained
+            state.shutdown();
+        },
+        SHUTDOWN_DRAIN,
+    ));
+    match tls {
+        Some(tls) => {
+            let tls = tls.load().await?;
+            info!("Listening on: https://{}", addr);
+            axum_server::bind_rustls(addr.parse()?, tls)
+                .handle(handle)
+                .serve(app.into_make_service())
+                .await?;
+        }
+        None => {
+            info!("Listening on: {}", addr);
+            axum_server::bind(addr.parse()?)
+                .handle(handle)
+                .serve(app.into_make_service())
+                .await?;
+        }
+    }
     Ok(())
 }
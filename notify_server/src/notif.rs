@@ -3,7 +3,7 @@ use std::{collections::HashSet, sync::Arc};
 use chat_core::{Chat, Message};
 use futures::StreamExt;
 use serde::{Deserialize, Serialize};
-use sqlx::postgres::PgListener;
+use sqlx::{postgres::PgListener, PgPool};
 use tracing::{info, warn};
 
 use crate::AppState;
@@ -15,6 +15,45 @@ pub enum AppEvent {
     AddToChat(Chat),
     RemoveFromChat(Chat),
     NewMessage(Message),
+    /// notice a workspace owner sends to everyone in the workspace
+    Announcement {
+        text: String,
+    },
+    /// a member read the chat up to the message
+    Seen {
+        chat_id: u64,
+        user_id: u64,
+        up_to_message_id: u64,
+    },
+    /// the connection fell behind and `missed` events were dropped, clients should resync
+    Lagged {
+        missed: u64,
+    },
+}
+
+impl AppEvent {
+    /// event names clients can filter on, `Lagged` is always delivered
+    pub const NAMES: [&'static str; 6] = [
+        "NewChat",
+        "AddToChat",
+        "RemoveFromChat",
+        "NewMessage",
+        "Announcement",
+        "Seen",
+    ];
+
+    /// the sse event name
+    pub fn name(&self) -> &'static str {
+        match self {
+            AppEvent::NewChat(_) => "NewChat",
+            AppEvent::AddToChat(_) => "AddToChat",
+            AppEvent::RemoveFromChat(_) => "RemoveFromChat",
+            AppEvent::NewMessage(_) => "NewMessage",
+            AppEvent::Announcement { .. } => "Announcement",
+            AppEvent::Seen { .. } => "Seen",
+            AppEvent::Lagged { .. } => "Lagged",
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -24,33 +63,58 @@ struct Notification {
     event: Arc<AppEvent>,
 }
 
-// pg_notify('chat_updated', json_build_object('op', TG_OP, 'old', OLD, 'new', NEW)::text);
+// Trigger output contract, see migrations/*_triggers.sql
+//
+// channel `chat_updated`:
+//   pg_notify('chat_updated', json_build_object('op', TG_OP, 'old', OLD, 'new', NEW)::text);
+//   `new` is required for INSERT/UPDATE, `old` is required for UPDATE/DELETE
+// channel `chat_message_created`:
+//   pg_notify('chat_message_created', json_build_object('message', NEW, 'members', USERS)::text);
+// channel `chat_read`:
+//   pg_notify('chat_read', json_build_object('chat_id', NEW.chat_id, 'user_id', NEW.user_id,
+//       'up_to_message_id', NEW.last_read_id, 'members', USERS)::text);
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+enum ChatOp {
+    Insert,
+    Update,
+    Delete,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct ChatUpdated {
-    op: String,
+    op: ChatOp,
     old: Option<Chat>,
     new: Option<Chat>,
 }
 
-// pg_notify('chat_message_created', row_to_json(NEW)::text);
 #[derive(Debug, Serialize, Deserialize)]
 struct ChatMessageCreated {
     message: Message,
     members: Vec<i64>,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+struct ChatRead {
+    chat_id: u64,
+    user_id: u64,
+    up_to_message_id: u64,
+    members: Vec<i64>,
+}
+
 impl Notification {
+    /// parse the payload of a notification, malformed payloads are errors rather than panics
     fn load(rtype: &str, payload: &str) -> anyhow::Result<Self> {
         match rtype {
             "chat_updated" => {
                 let payload: ChatUpdated = serde_json::from_str(payload)?;
                 let user_ids =
                     get_affected_chat_user_ids(payload.old.as_ref(), payload.new.as_ref());
-                let event = match payload.op.as_str() {
-                    "INSERT" => AppEvent::NewChat(payload.new.expect("new should exist")),
-                    "UPDATE" => AppEvent::AddToChat(payload.new.expect("new should exist")),
-                    "DELETE" => AppEvent::RemoveFromChat(payload.old.expect("old should exist")),
-                    _ => return Err(anyhow::anyhow!("Invalid operation")),
+                let event = match (payload.op, payload.old, payload.new) {
+                    (ChatOp::Insert, _, Some(new)) => AppEvent::NewChat(new),
+                    (ChatOp::Update, Some(_), Some(new)) => AppEvent::AddToChat(new),
+                    (ChatOp::Delete, Some(old), _) => AppEvent::RemoveFromChat(old),
+                    (op, _, _) => anyhow::bail!("missing chat data for {:?}", op),
                 };
                 Ok(Self {
                     user_ids,
@@ -65,6 +129,24 @@ impl Notification {
                     event: Arc::new(AppEvent::NewMessage(payload.message)),
                 })
             }
+            "chat_read" => {
+                let payload: ChatRead = serde_json::from_str(payload)?;
+                // the reader knows what they read
+                let user_ids = payload
+                    .members
+                    .iter()
+                    .map(|v| *v as u64)
+                    .filter(|v| *v != payload.user_id)
+                    .collect();
+                Ok(Self {
+                    user_ids,
+                    event: Arc::new(AppEvent::Seen {
+                        chat_id: payload.chat_id,
+                        user_id: payload.user_id,
+                        up_to_message_id: payload.up_to_message_id,
+                    }),
+                })
+            }
             _ => Err(anyhow::anyhow!("Invalid notification type")),
         }
     }
@@ -88,29 +170,101 @@ fn get_affected_chat_user_ids(old: Option<&Chat>, new: Option<&Chat>) -> HashSet
     }
 }
 
+/// users who muted the chat, they don't get new messages of it
+async fn muted_user_ids(pool: &PgPool, chat_id: i64) -> anyhow::Result<HashSet<u64>> {
+    let ids: Vec<(i64,)> =
+        sqlx::query_as("SELECT user_id FROM notification_prefs WHERE chat_id = $1 AND muted")
+            .bind(chat_id)
+            .fetch_all(pool)
+            .await?;
+    Ok(ids.into_iter().map(|(id,)| id as u64).collect())
+}
+
 pub async fn setup_pg_listener(state: AppState) -> anyhow::Result<()> {
-    let mut listener = PgListener::connect(&state.config.server.db_url).await?;
+    let pool = PgPool::connect(&state.config.server.db_url).await?;
+    let mut listener = PgListener::connect_with(&pool).await?;
     listener.listen("chat_updated").await?;
     listener.listen("chat_message_created").await?;
+    listener.listen("chat_read").await?;
 
     let mut stream = listener.into_stream();
 
     tokio::spawn(async move {
         while let Some(Ok(notif)) = stream.next().await {
-            println!("Received notification: {:?}", notif);
-            let notification = Notification::load(notif.channel(), notif.payload())?;
-            let users = &state.users;
-            for user_id in notification.user_ids {
-                if let Some(tx) = users.get(&user_id) {
-                    info!("Sending notification to user {}", user_id);
-                    if let Err(e) = tx.send(notification.event.clone()) {
-                        warn!("Failed to send notification to user {}: {}", user_id, e);
-                    }
+            info!("Received notification: {:?}", notif);
+            let mut notification = match Notification::load(notif.channel(), notif.payload()) {
+                Ok(notification) => notification,
+                Err(e) => {
+                    warn!("Skip malformed notification on {}: {}", notif.channel(), e);
+                    continue;
+                }
+            };
+            if let AppEvent::NewMessage(message) = notification.event.as_ref() {
+                match muted_user_ids(&pool, message.chat_id).await {
+                    Ok(muted) => notification.user_ids.retain(|id| !muted.contains(id)),
+                    Err(e) => warn!("Failed to load muted users: {}", e),
                 }
             }
+            state.broadcast(notification.user_ids, notification.event);
         }
         Ok::<_, anyhow::Error>(())
     });
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load_message_created_should_work() {
+        let payload = r#"{"message":{"id":1,"chat_id":1,"sender_id":1,"content":"hello","files":[],"created_at":"2024-07-01T00:00:00+00:00"},"members":[1,2]}"#;
+        let notification = Notification::load("chat_message_created", payload).unwrap();
+        assert_eq!(notification.user_ids, HashSet::from([1, 2]));
+        assert!(matches!(*notification.event, AppEvent::NewMessage(_)));
+    }
+
+    #[test]
+    fn load_chat_updated_should_work() {
+        let payload = r#"{"op":"INSERT","old":null,"new":{"id":1,"ws_id":1,"name":null,"type":"single","members":[1,2],"created_at":"2024-07-01T00:00:00+00:00"}}"#;
+        let notification = Notification::load("chat_updated", payload).unwrap();
+        assert_eq!(notification.user_ids, HashSet::from([1, 2]));
+        assert!(matches!(*notification.event, AppEvent::NewChat(_)));
+    }
+
+    #[tokio::test]
+    async fn chat_read_should_deliver_seen_to_other_members() -> anyhow::Result<()> {
+        let state = AppState::new(crate::config::AppConfig::load()?);
+        let mut rx_a = crate::sse::subscribe(&state, 1);
+        let mut rx_b = crate::sse::subscribe(&state, 2);
+
+        // user 1 read chat 3 up to message 10
+        let payload = r#"{"chat_id":3,"user_id":1,"up_to_message_id":10,"members":[1,2]}"#;
+        let notification = Notification::load("chat_read", payload)?;
+        assert_eq!(notification.user_ids, HashSet::from([2]));
+        state.broadcast(notification.user_ids, notification.event);
+
+        let event = rx_b.try_recv()?;
+        assert!(matches!(
+            event.as_ref(),
+            AppEvent::Seen {
+                chat_id: 3,
+                user_id: 1,
+                up_to_message_id: 10,
+            }
+        ));
+        assert_eq!(event.name(), "Seen");
+        assert!(rx_a.try_recv().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn load_malformed_payload_should_fail() {
+        assert!(Notification::load("chat_message_created", "not json").is_err());
+        assert!(Notification::load("chat_message_created", r#"{"members":[1]}"#).is_err());
+        assert!(Notification::load("chat_updated", r#"{"op":"TRUNCATE"}"#).is_err());
+        // INSERT without new must not panic
+        assert!(Notification::load("chat_updated", r#"{"op":"INSERT","old":null}"#).is_err());
+    }
+}
@@ -1,39 +1,45 @@
-use std::{convert::Infallible, time::Duration};
+use std::{collections::HashSet, convert::Infallible, sync::Arc, time::Duration};
 
-use axum::{extract::State, response::Sse, Extension};
+use axum::{
+    extract::{Query, State},
+    response::Sse,
+    Extension,
+};
 use chat_core::User;
 use futures::Stream;
+use serde::Deserialize;
 use tokio::sync::broadcast;
-use tokio_stream::{wrappers::BroadcastStream, StreamExt};
-use tracing::info;
+use tokio_stream::{
+    wrappers::{errors::BroadcastStreamRecvError, BroadcastStream},
+    StreamExt,
+};
+use tracing::{info, warn};
 
 use crate::{notif::AppEvent, AppState};
 
-const CHANNEL_CAPACITY: usize = 256;
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct SseParams {
+    /// comma separated event names, e.g. `NewMessage,NewChat`, all events when absent
+    #[serde(default)]
+    pub events: Option<String>,
+}
 
 pub(crate) async fn sse_handler(
     State(state): State<AppState>,
     Extension(user): Extension<User>,
+    Query(params): Query<SseParams>,
 ) -> Sse<impl Stream<Item = Result<axum::response::sse::Event, Infallible>>> {
     let user_id = user.id as u64;
-    let rx = match state.users.get(&user_id) {
-        Some(tx) => tx.subscribe(),
-        None => {
-            let (tx, rx) = broadcast::channel(CHANNEL_CAPACITY);
-            state.users.insert(user_id, tx);
-            rx
-        }
-    };
+    let rx = subscribe(&state, user_id);
 
     info!("User {} subscribed", user_id);
 
-    let stream = BroadcastStream::new(rx).filter_map(|v| v.ok()).map(|v| {
-        let name = match v.as_ref() {
-            AppEvent::NewChat(_) => "NewChat",
-            AppEvent::AddToChat(_) => "AddToChat",
-            AppEvent::RemoveFromChat(_) => "RemoveFromChat",
-            AppEvent::NewMessage(_) => "NewMessage",
-        };
+    let filter = params.events.as_deref().map(parse_event_filter);
+    let stream = filter_events(rx, filter);
+    // stop the stream when the server is shutting down
+    let stream = futures::StreamExt::take_until(stream, state.shutdown.clone().cancelled_owned());
+    let stream = stream.map(|v| {
+        let name = v.name();
         let v = serde_json::to_string(&v).expect("Failed to serialize event");
         // sse event name
         Ok(axum::response::sse::Event::default().data(v).event(name))
@@ -45,3 +51,142 @@ pub(crate) async fn sse_handler(
             .text("keep-alive-text"),
     )
 }
+
+/// receiver on the user's channel, the channel is created on first subscribe
+pub(crate) fn subscribe(state: &AppState, user_id: u64) -> broadcast::Receiver<Arc<AppEvent>> {
+    match state.users.get(&user_id) {
+        Some(tx) => tx.subscribe(),
+        None => {
+            // a zero capacity channel panics
+            let capacity = state.config.server.channel_capacity.max(1);
+            let (tx, rx) = broadcast::channel(capacity);
+            state.users.insert(user_id, tx);
+            rx
+        }
+    }
+}
+
+/// known event names in `events`, unknown ones are ignored
+fn parse_event_filter(events: &str) -> HashSet<&'static str> {
+    let mut filter = HashSet::new();
+    for name in events.split(',').map(str::trim).filter(|v| !v.is_empty()) {
+        match AppEvent::NAMES.iter().find(|v| **v == name) {
+            Some(name) => {
+                filter.insert(*name);
+            }
+            None => warn!("Ignore unknown event name: {}", name),
+        }
+    }
+    filter
+}
+
+/// events of the receiver whose name is in the filter, all of them when there is no filter.
+/// a slow receiver gets a `Lagged` event in place of the events it missed
+fn filter_events(
+    rx: broadcast::Receiver<Arc<AppEvent>>,
+    filter: Option<HashSet<&'static str>>,
+) -> impl Stream<Item = Arc<AppEvent>> {
+    BroadcastStream::new(rx)
+        .map(|v| match v {
+            Ok(event) => event,
+            Err(BroadcastStreamRecvError::Lagged(missed)) => {
+                warn!("Receiver lagged, {} events missed", missed);
+                Arc::new(AppEvent::Lagged { missed })
+            }
+        })
+        .filter(move |v| match (v.as_ref(), &filter) {
+            (AppEvent::Lagged { .. }, _) | (_, None) => true,
+            (v, Some(filter)) => filter.contains(v.name()),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use chat_core::{Chat, Message};
+    use tokio::sync::broadcast::error::TryRecvError;
+
+    use super::*;
+    use crate::config::AppConfig;
+
+    fn new_chat() -> Arc<AppEvent> {
+        let chat: Chat = serde_json::from_str(
+            r#"{"id":1,"ws_id":1,"name":null,"type":"single","members":[1,2],"created_at":"2024-07-01T00:00:00+00:00"}"#,
+        )
+        .unwrap();
+        Arc::new(AppEvent::NewChat(chat))
+    }
+
+    fn new_message() -> Arc<AppEvent> {
+        let message: Message = serde_json::from_str(
+            r#"{"id":1,"chat_id":1,"sender_id":1,"content":"hello","files":[],"created_at":"2024-07-01T00:00:00+00:00"}"#,
+        )
+        .unwrap();
+        Arc::new(AppEvent::NewMessage(message))
+    }
+
+    #[tokio::test]
+    async fn subscribe_should_use_configured_capacity() -> anyhow::Result<()> {
+        let mut config = AppConfig::load()?;
+        config.server.channel_capacity = 4;
+        let state = AppState::new(config);
+        let mut rx = subscribe(&state, 1);
+        let tx = state.users.get(&1).unwrap().clone();
+        for _ in 0..5 {
+            tx.send(new_chat())?;
+        }
+        // one event more than the capacity, the oldest one is dropped
+        assert_eq!(rx.try_recv().unwrap_err(), TryRecvError::Lagged(1));
+        assert_eq!(rx.len(), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn parse_event_filter_should_ignore_unknown_names() {
+        let filter = parse_event_filter("NewMessage, NewChat,Nope,");
+        assert_eq!(filter, HashSet::from(["NewMessage", "NewChat"]));
+    }
+
+    #[tokio::test]
+    async fn filter_events_should_only_deliver_subscribed_events() {
+        let (tx, rx) = broadcast::channel(16);
+        let stream = filter_events(rx, Some(parse_event_filter("NewChat")));
+        tx.send(new_message()).unwrap();
+        tx.send(new_chat()).unwrap();
+        drop(tx);
+
+        let events: Vec<_> = stream.collect().await;
+        assert_eq!(events.len(), 1);
+        assert!(matches!(events[0].as_ref(), AppEvent::NewChat(_)));
+    }
+
+    #[tokio::test]
+    async fn filter_events_without_filter_should_deliver_all() {
+        let (tx, rx) = broadcast::channel(16);
+        let stream = filter_events(rx, None);
+        tx.send(new_message()).unwrap();
+        tx.send(new_chat()).unwrap();
+        drop(tx);
+
+        let events: Vec<_> = stream.collect().await;
+        assert_eq!(events.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn slow_receiver_should_get_lagged_event() {
+        let (tx, rx) = broadcast::channel(2);
+        let stream = filter_events(rx, Some(parse_event_filter("NewChat")));
+        // overflow the channel before the receiver reads anything
+        for _ in 0..5 {
+            tx.send(new_chat()).unwrap();
+        }
+        drop(tx);
+
+        let events: Vec<_> = stream.collect().await;
+        assert_eq!(events.len(), 3);
+        assert!(matches!(events[0].as_ref(), AppEvent::Lagged { missed: 3 }));
+        assert!(matches!(events[1].as_ref(), AppEvent::NewChat(_)));
+        let v = serde_json::to_value(events[0].as_ref()).unwrap();
+        assert_eq!(v["event"], "Lagged");
+        assert_eq!(v["missed"], 3);
+    }
+}
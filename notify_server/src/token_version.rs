@@ -0,0 +1,23 @@
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Extension,
+};
+use chat_core::User;
+
+use crate::{error::AppError, AppState};
+
+/// reject tokens revoked on the chat server, must run after token verification
+pub(crate) async fn verify_token_version(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+    req: Request,
+    next: Next,
+) -> Response {
+    match state.token_version(user.id).await {
+        Err(e) => e.into_response(),
+        Ok(Some(version)) if user.token_version >= version => next.run(req).await,
+        Ok(_) => AppError::TokenRevoked.into_response(),
+    }
+}